@@ -1,25 +1,73 @@
-use color_eyre::Result;
-use std::path::PathBuf;
+use adapt_cache::fs::FsAdaptCache;
+use color_eyre::{eyre::eyre, Result};
+use fn0::{CodeKind, CodeManifest, DeploymentMap, Fn0, Fn0Config};
+use http_body_util::BodyExt;
+use hyper::{server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::net::TcpListener;
+
+const DEFAULT_PORT: u16 = 3000;
+/// The compiled component in `./dist`, which is also its code id.
+const CODE_ID: &str = "component.cwasm";
+const CACHE_BYTES: usize = 512 * 1024 * 1024;
 
 pub async fn execute(port: Option<u16>) -> Result<()> {
     println!("Starting local fn0 server...\n");
 
-    let wasm_file = PathBuf::from("./dist/component.wasm");
+    let dist = PathBuf::from("./dist");
+    let wasm_file = dist.join("component.wasm");
 
     crate::commands::build::execute().await?;
 
-    let config = fn0::Config {
-        port,
-        wasm_path: Some(wasm_file),
-        otlp_endpoint: None,
-    };
+    let wasm = std::fs::read(&wasm_file)
+        .map_err(|e| eyre!("Failed to read {}: {}", wasm_file.display(), e))?;
+    let compiled = fn0::compile(&wasm).map_err(|e| eyre!("Failed to compile: {:?}", e))?;
+    std::fs::write(dist.join(CODE_ID), compiled)?;
+
+    let mut deployment_map = DeploymentMap::new();
+    deployment_map.register_code_manifest(CodeManifest {
+        kind: CodeKind::Wasm,
+        code_id: CODE_ID.to_string(),
+        code_version: 0,
+        limits: Default::default(),
+        config: Default::default(),
+    });
+    let fn0 = Fn0::new(
+        FsAdaptCache::new(dist.clone(), CACHE_BYTES),
+        FsAdaptCache::new(dist, CACHE_BYTES),
+        deployment_map,
+        // Traps and their wasm backtrace show up in the error responses.
+        Fn0Config {
+            debug_errors: true,
+            ..Default::default()
+        },
+    );
 
     println!("\nServer starting...\n");
 
-    fn0::run(config, |port| {
-        println!("Server on http://localhost:{}", port);
-    })
-    .await?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port.unwrap_or(DEFAULT_PORT)));
+    let listener = TcpListener::bind(addr).await?;
+    println!("Server on http://localhost:{}", addr.port());
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let fn0 = fn0.clone();
 
-    Ok(())
+        tokio::spawn(async move {
+            let service = service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
+                let fn0 = fn0.clone();
+                async move {
+                    let request = request.map(|body| body.map_err(|e| e.into()).boxed_unsync());
+                    fn0.run(CODE_ID, request).await
+                }
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(socket), service)
+                .await
+            {
+                eprintln!("Failed to serve connection: {}", err);
+            }
+        });
+    }
 }
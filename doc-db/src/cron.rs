@@ -0,0 +1,91 @@
+//! Cron runs that hq made and hosts haven't finished yet.
+//!
+//! A run and the cursor of its schedule are written together, so after a restart hq neither
+//! makes a run again nor loses one. A run is deleted once a host ran it or hq gave up.
+
+use libsql::Row;
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CronRun {
+    pub run_id: u64,
+    pub code_id: u64,
+    /// `CronSchedule::name`.
+    pub schedule: String,
+    /// Unix epoch milliseconds of the fire time.
+    pub scheduled_at: u64,
+}
+
+impl DocDb {
+    /// `scheduled_at` of the last run made for the schedule.
+    pub async fn get_cron_cursor(&self, code_id: u64, schedule: &str) -> Result<Option<u64>> {
+        let conn = self.db.connect()?;
+        let mut rows = conn
+            .query(
+                "SELECT value FROM docs WHERE pk = 'cron-cursors' AND sk = ?",
+                libsql::params![cursor_sk(code_id, schedule)],
+            )
+            .await?;
+        match rows.next().await? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn put_cron_run(&self, run: &CronRun) -> Result<()> {
+        let conn = self.db.connect()?;
+        let tx = conn.transaction().await?;
+        tx.execute(
+            "REPLACE INTO docs (pk, sk, value) VALUES ('cron-runs', ?, ?)",
+            libsql::params![run.run_id, serde_json::to_string(run).unwrap()],
+        )
+        .await?;
+        tx.execute(
+            "REPLACE INTO docs (pk, sk, value) VALUES ('cron-cursors', ?, ?)",
+            libsql::params![cursor_sk(run.code_id, &run.schedule), run.scheduled_at],
+        )
+        .await?;
+        tx.commit().await
+    }
+
+    pub async fn delete_cron_run(&self, run_id: u64) -> Result<()> {
+        let conn = self.db.connect()?;
+        conn.execute(
+            "DELETE FROM docs WHERE pk = 'cron-runs' AND sk = ?",
+            libsql::params![run_id],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn all_cron_runs(&self) -> Result<Vec<CronRun>> {
+        let mut runs = vec![];
+
+        let conn = self.db.connect()?;
+        let mut rows = conn
+            .query(
+                "SELECT value FROM docs WHERE pk = 'cron-runs' ORDER BY sk ASC",
+                libsql::params!(),
+            )
+            .await?;
+
+        while let Some(row) = rows.next().await? {
+            runs.push(row.into());
+        }
+
+        Ok(runs)
+    }
+}
+
+fn cursor_sk(code_id: u64, schedule: &str) -> Vec<u8> {
+    format!("{code_id}/{schedule}").into_bytes()
+}
+
+impl From<Row> for CronRun {
+    fn from(row: Row) -> Self {
+        let json: String = row.get(0).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+}
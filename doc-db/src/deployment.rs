@@ -1,12 +1,23 @@
 use bytes::Buf;
 use libsql::Row;
+use serde::{Deserialize, Serialize};
 
 use super::*;
 
-#[derive(Clone, Copy)]
+/// Stored as `code_id` and `code_version` in u64 LE, then `schedules` in JSON if any.
+#[derive(Clone)]
 pub struct Deployment {
     pub code_id: u64,
     pub code_version: u64,
+    pub schedules: Vec<CronSchedule>,
+}
+
+/// Run the code on `cron`, in UTC, with seconds. hq pushes each run to a host.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CronSchedule {
+    /// Given to the code with each run, unique in the code.
+    pub name: String,
+    pub cron: String,
 }
 
 impl DocDb {
@@ -49,13 +60,17 @@ impl DocDb {
 
 impl From<Row> for Deployment {
     fn from(row: Row) -> Self {
-        let bytes: [u8; 16] = row.get(0).unwrap();
-        assert_eq!(bytes.len(), 16);
+        let bytes: Vec<u8> = row.get(0).unwrap();
+        assert!(bytes.len() >= 16);
 
         let mut cursor = bytes.as_slice();
         Deployment {
             code_id: cursor.get_u64_le(),
             code_version: cursor.get_u64_le(),
+            schedules: match cursor.is_empty() {
+                true => vec![],
+                false => serde_json::from_slice(cursor).unwrap(),
+            },
         }
     }
 }
@@ -0,0 +1,122 @@
+//! Key-value items in `docs`. `pk` is the namespace given by the caller and `sk` is the key.
+
+use super::*;
+
+impl DocDb {
+    pub async fn kv_get(&self, pk: &[u8], key: &str) -> Result<Option<Vec<u8>>> {
+        let conn = self.db.connect()?;
+        let mut rows = conn
+            .query(
+                "SELECT value FROM docs WHERE pk = ? AND sk = ?",
+                libsql::params![pk, key.as_bytes()],
+            )
+            .await?;
+        match rows.next().await? {
+            Some(row) => Ok(Some(row.get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn kv_set(&self, pk: &[u8], key: &str, value: &[u8]) -> Result<()> {
+        let conn = self.db.connect()?;
+        conn.execute(
+            "REPLACE INTO docs (pk, sk, value) VALUES (?, ?, ?)",
+            libsql::params![pk, key.as_bytes(), value],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn kv_set_many(&self, pk: &[u8], key_values: &[(String, Vec<u8>)]) -> Result<()> {
+        let conn = self.db.connect()?;
+        let tx = conn.transaction().await?;
+        for (key, value) in key_values {
+            tx.execute(
+                "REPLACE INTO docs (pk, sk, value) VALUES (?, ?, ?)",
+                libsql::params![pk, key.as_bytes(), value.as_slice()],
+            )
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn kv_delete(&self, pk: &[u8], key: &str) -> Result<()> {
+        let conn = self.db.connect()?;
+        conn.execute(
+            "DELETE FROM docs WHERE pk = ? AND sk = ?",
+            libsql::params![pk, key.as_bytes()],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn kv_delete_many(&self, pk: &[u8], keys: &[String]) -> Result<()> {
+        let conn = self.db.connect()?;
+        let tx = conn.transaction().await?;
+        for key in keys {
+            tx.execute(
+                "DELETE FROM docs WHERE pk = ? AND sk = ?",
+                libsql::params![pk, key.as_bytes()],
+            )
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Keys in byte order, from the `offset`-th one.
+    pub async fn kv_list_keys(&self, pk: &[u8], offset: u64, limit: u64) -> Result<Vec<String>> {
+        let mut keys = vec![];
+
+        let conn = self.db.connect()?;
+        let mut rows = conn
+            .query(
+                "SELECT sk FROM docs WHERE pk = ? ORDER BY sk ASC LIMIT ? OFFSET ?",
+                libsql::params![pk, limit, offset],
+            )
+            .await?;
+
+        while let Some(row) = rows.next().await? {
+            let key: Vec<u8> = row.get(0)?;
+            keys.push(String::from_utf8_lossy(&key).into_owned());
+        }
+
+        Ok(keys)
+    }
+
+    /// Values are decimal strings, and a missing key counts from 0.
+    pub async fn kv_increment(&self, pk: &[u8], key: &str, delta: u64) -> Result<u64> {
+        let conn = self.db.connect()?;
+        // Takes the write lock before reading, so racing increments don't lose each other's.
+        let tx = conn
+            .transaction_with_behavior(libsql::TransactionBehavior::Immediate)
+            .await?;
+        let mut rows = tx
+            .query(
+                "SELECT value FROM docs WHERE pk = ? AND sk = ?",
+                libsql::params![pk, key.as_bytes()],
+            )
+            .await?;
+        let current = match rows.next().await? {
+            Some(row) => {
+                let value: Vec<u8> = row.get(0)?;
+                std::str::from_utf8(&value)
+                    .ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or_else(|| libsql::Error::Misuse("value is not a u64".to_string()))?
+            }
+            None => 0,
+        };
+        drop(rows);
+
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| libsql::Error::Misuse("u64 overflow".to_string()))?;
+        tx.execute(
+            "REPLACE INTO docs (pk, sk, value) VALUES (?, ?, ?)",
+            libsql::params![pk, key.as_bytes(), value.to_string().into_bytes()],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+}
@@ -1,9 +1,17 @@
+mod cron;
 mod deployment;
+mod keyvalue;
+mod queue;
 mod scale_config;
+mod secret;
 
+pub use cron::CronRun;
 pub use deployment::*;
+pub use libsql::Error;
 use libsql::{Builder, Database, Result};
+pub use queue::QueueItem;
 pub use scale_config::*;
+pub use secret::SecretKey;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -16,4 +24,21 @@ impl DocDb {
         let db = Builder::new_remote(url, token).build().await?;
         Ok(Self { db: Arc::new(db) })
     }
+
+    /// A database file for development. The `docs` table is created if missing.
+    pub async fn new_local(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let db = Builder::new_local(path).build().await?;
+        db.connect()?
+            .execute(
+                "CREATE TABLE IF NOT EXISTS docs (
+  pk BLOB NOT NULL,
+  sk BLOB NOT NULL,
+  value BLOB NOT NULL,
+  PRIMARY KEY (pk, sk)
+) WITHOUT ROWID",
+                libsql::params!(),
+            )
+            .await?;
+        Ok(Self { db: Arc::new(db) })
+    }
 }
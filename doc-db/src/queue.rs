@@ -0,0 +1,141 @@
+//! Queue messages in `docs`, under `pk = "queue/<name>"`.
+//!
+//! `sk` is the time the message gets visible, then its id, both u64 BE, so the visible ones come
+//! first. `value` is the number of deliveries as u32 LE, then the body.
+//! A delivery moves the message to a new `sk`, which is also its receipt.
+
+use bytes::Buf;
+
+use super::*;
+
+pub struct QueueItem {
+    pub id: u64,
+    pub body: Vec<u8>,
+    /// Deliveries so far, including this one.
+    pub deliveries: u32,
+    pub receipt: u128,
+}
+
+impl DocDb {
+    pub async fn queue_send(&self, queue: &str, body: &[u8], visible_at_ms: u64) -> Result<()> {
+        let conn = self.db.connect()?;
+        conn.execute(
+            "INSERT INTO docs (pk, sk, value) VALUES (?, ?, ?)",
+            libsql::params![
+                queue_pk(queue),
+                sk(visible_at_ms, rand_id()).to_vec(),
+                value(0, body)
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Delivers up to `max` messages visible at `now_ms`, hiding them until `visible_at_ms`.
+    pub async fn queue_receive(
+        &self,
+        queue: &str,
+        now_ms: u64,
+        max: usize,
+        visible_at_ms: u64,
+    ) -> Result<Vec<QueueItem>> {
+        let pk = queue_pk(queue);
+        let conn = self.db.connect()?;
+        // Takes the write lock before reading, so racing consumers don't take the same messages.
+        let tx = conn
+            .transaction_with_behavior(libsql::TransactionBehavior::Immediate)
+            .await?;
+
+        let mut rows = tx
+            .query(
+                "SELECT sk, value FROM docs WHERE pk = ? AND sk < ? ORDER BY sk ASC LIMIT ?",
+                libsql::params![pk.as_slice(), sk(now_ms + 1, 0).to_vec(), max as u64],
+            )
+            .await?;
+        let mut found = vec![];
+        while let Some(row) = rows.next().await? {
+            let sk: Vec<u8> = row.get(0)?;
+            let value: Vec<u8> = row.get(1)?;
+            found.push((sk, value));
+        }
+        drop(rows);
+
+        let mut items = Vec::with_capacity(found.len());
+        for (old_sk, old_value) in found {
+            let id = (&old_sk[8..]).get_u64();
+            let mut cursor = old_value.as_slice();
+            let deliveries = cursor.get_u32_le() + 1;
+            let new_sk = sk(visible_at_ms, id);
+            let updated = tx
+                .execute(
+                    "UPDATE docs SET sk = ?, value = ? WHERE pk = ? AND sk = ?",
+                    libsql::params![
+                        new_sk.to_vec(),
+                        value(deliveries, cursor),
+                        pk.as_slice(),
+                        old_sk
+                    ],
+                )
+                .await?;
+            // Taken or deleted since the select, so the receipt wouldn't exist.
+            if updated == 0 {
+                continue;
+            }
+            items.push(QueueItem {
+                id,
+                body: cursor.to_vec(),
+                deliveries,
+                receipt: u128::from_be_bytes(new_sk),
+            });
+        }
+
+        tx.commit().await?;
+        Ok(items)
+    }
+
+    /// Nothing happens if the message was delivered again since `receipt`.
+    pub async fn queue_delete(&self, queue: &str, receipt: u128) -> Result<()> {
+        let conn = self.db.connect()?;
+        conn.execute(
+            "DELETE FROM docs WHERE pk = ? AND sk = ?",
+            libsql::params![queue_pk(queue), receipt.to_be_bytes().to_vec()],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Makes the message visible at `visible_at_ms` instead.
+    /// Nothing happens if the message was delivered again since `receipt`.
+    pub async fn queue_retry(&self, queue: &str, receipt: u128, visible_at_ms: u64) -> Result<()> {
+        let id = receipt as u64;
+        let conn = self.db.connect()?;
+        conn.execute(
+            "UPDATE docs SET sk = ? WHERE pk = ? AND sk = ?",
+            libsql::params![
+                sk(visible_at_ms, id).to_vec(),
+                queue_pk(queue),
+                receipt.to_be_bytes().to_vec()
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+fn queue_pk(queue: &str) -> Vec<u8> {
+    format!("queue/{queue}").into_bytes()
+}
+
+fn sk(visible_at_ms: u64, id: u64) -> [u8; 16] {
+    ((visible_at_ms as u128) << 64 | id as u128).to_be_bytes()
+}
+
+fn value(deliveries: u32, body: &[u8]) -> Vec<u8> {
+    let mut value = deliveries.to_le_bytes().to_vec();
+    value.extend_from_slice(body);
+    value
+}
+
+fn rand_id() -> u64 {
+    rand::random()
+}
@@ -0,0 +1,110 @@
+//! Secrets of codes, encrypted with AES-256-GCM at rest.
+//!
+//! `value` is the 12-byte nonce followed by the ciphertext. `pk` and `sk` are the associated data,
+//! so a value copied to another code or name doesn't decrypt.
+
+use super::*;
+use aes_gcm::{
+    Aes256Gcm, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng, Payload},
+};
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct SecretKey {
+    cipher: Aes256Gcm,
+}
+
+impl SecretKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        }
+    }
+}
+
+impl DocDb {
+    pub async fn set_secret(
+        &self,
+        key: &SecretKey,
+        code_id: &str,
+        name: &str,
+        secret: &str,
+    ) -> Result<()> {
+        let pk = secret_pk(code_id);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret.as_bytes(),
+                    aad: &aad(&pk, name),
+                },
+            )
+            .map_err(|_| libsql::Error::Misuse("failed to encrypt the secret".to_string()))?;
+
+        let mut value = nonce.to_vec();
+        value.extend_from_slice(&ciphertext);
+
+        let conn = self.db.connect()?;
+        conn.execute(
+            "REPLACE INTO docs (pk, sk, value) VALUES (?, ?, ?)",
+            libsql::params![pk, name.as_bytes(), value],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_secret(
+        &self,
+        key: &SecretKey,
+        code_id: &str,
+        name: &str,
+    ) -> Result<Option<String>> {
+        let pk = secret_pk(code_id);
+        let conn = self.db.connect()?;
+        let mut rows = conn
+            .query(
+                "SELECT value FROM docs WHERE pk = ? AND sk = ?",
+                libsql::params![pk.as_slice(), name.as_bytes()],
+            )
+            .await?;
+        let Some(row) = rows.next().await? else {
+            return Ok(None);
+        };
+        let value: Vec<u8> = row.get(0)?;
+
+        // The error doesn't carry anything of the value.
+        let undecryptable = || libsql::Error::Misuse(format!("secret {name} can't be decrypted"));
+        if value.len() < NONCE_LEN {
+            return Err(undecryptable());
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        let secret = key
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad(&pk, name),
+                },
+            )
+            .map_err(|_| undecryptable())?;
+        String::from_utf8(secret)
+            .map(Some)
+            .map_err(|_| undecryptable())
+    }
+}
+
+fn secret_pk(code_id: &str) -> Vec<u8> {
+    format!("secrets/{code_id}").into_bytes()
+}
+
+fn aad(pk: &[u8], name: &str) -> Vec<u8> {
+    let mut aad = pk.to_vec();
+    aad.push(0);
+    aad.extend_from_slice(name.as_bytes());
+    aad
+}
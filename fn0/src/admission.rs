@@ -0,0 +1,289 @@
+//! Admission control of wasm instances and JS isolates.
+//!
+//! A job needs a permit of the host and one of its code before it gets an instance or an isolate.
+//! Jobs without permits wait in a short queue, and get a 503 once it's full or they waited too long,
+//! instead of failing in the pooling allocator.
+
+use crate::{CodeKind, Response, execute::response, telemetry};
+use bytes::Bytes;
+use hyper::{StatusCode, header::RETRY_AFTER};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdmissionLimits {
+    /// Wasm jobs running at once on the host. `None` is what the pooling allocator can hold.
+    pub max_instances: Option<usize>,
+    /// JS jobs running at once on the host. `None` is as many isolates as `max_memory_bytes`
+    /// per isolate fits in the host memory, like the pooling allocator.
+    pub max_isolates: Option<usize>,
+    /// Jobs waiting for a permit, of all codes.
+    pub max_queue_len: usize,
+    pub max_queue_wait: Duration,
+    /// Sent as `Retry-After` with the 503.
+    pub retry_after: Duration,
+}
+
+impl Default for AdmissionLimits {
+    fn default() -> Self {
+        Self {
+            max_instances: None,
+            max_isolates: None,
+            max_queue_len: 256,
+            max_queue_wait: Duration::from_millis(500),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A snapshot of `Admission`, shown by the health route.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct AdmissionStats {
+    pub max_instances: usize,
+    pub available_instances: usize,
+    pub max_isolates: usize,
+    pub available_isolates: usize,
+    /// Jobs waiting for a permit. Any means the host is overloaded.
+    pub queue_len: usize,
+}
+
+pub(crate) struct Admission {
+    instances: Arc<Semaphore>,
+    max_instances: usize,
+    isolates: Arc<Semaphore>,
+    max_isolates: usize,
+    codes: Mutex<Codes>,
+    queue_len: AtomicUsize,
+    limits: AdmissionLimits,
+}
+
+/// Per-code permits with the concurrency they were made for.
+struct Codes {
+    semaphores: HashMap<String, (usize, Arc<Semaphore>)>,
+    /// Idle codes are evicted once this many are kept, so sweeping is amortized over inserts.
+    sweep_at: usize,
+}
+
+const MIN_SWEEP_AT: usize = 64;
+
+/// Held while the job's instance or isolate is busy.
+pub(crate) struct AdmissionPermit {
+    _host: OwnedSemaphorePermit,
+    _code: OwnedSemaphorePermit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    QueueFull,
+    QueueTimeout,
+}
+
+impl Rejection {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::QueueFull => "queue_full",
+            Self::QueueTimeout => "queue_timeout",
+        }
+    }
+}
+
+impl Admission {
+    /// `pool_instances` is what the pooling allocator can hold, and also sizes the isolates.
+    pub(crate) fn new(limits: AdmissionLimits, pool_instances: usize) -> Self {
+        let max_instances = limits.max_instances.unwrap_or(pool_instances);
+        let max_isolates = limits.max_isolates.unwrap_or(pool_instances);
+        Self {
+            instances: Arc::new(Semaphore::new(max_instances)),
+            max_instances,
+            isolates: Arc::new(Semaphore::new(max_isolates)),
+            max_isolates,
+            codes: Mutex::new(Codes {
+                semaphores: Default::default(),
+                sweep_at: MIN_SWEEP_AT,
+            }),
+            queue_len: AtomicUsize::new(0),
+            limits,
+        }
+    }
+
+    pub(crate) async fn admit(
+        &self,
+        kind: CodeKind,
+        code_id: &str,
+        concurrency: usize,
+    ) -> Result<AdmissionPermit, Rejection> {
+        let host = match kind {
+            CodeKind::Wasm => &self.instances,
+            CodeKind::Js => &self.isolates,
+        };
+        let code = self.code_semaphore(code_id, concurrency);
+        if let (Ok(host), Ok(code)) = (
+            host.clone().try_acquire_owned(),
+            code.clone().try_acquire_owned(),
+        ) {
+            return Ok(AdmissionPermit {
+                _host: host,
+                _code: code,
+            });
+        }
+
+        let queue_len = self.queue_len.fetch_add(1, Ordering::Relaxed);
+        if queue_len >= self.limits.max_queue_len {
+            self.queue_len.fetch_sub(1, Ordering::Relaxed);
+            return Err(Rejection::QueueFull);
+        }
+        telemetry::admission_queue_len(1);
+
+        // The code permit comes first so a busy code doesn't hold host permits while it waits.
+        let result = tokio::time::timeout(self.limits.max_queue_wait, async {
+            let code = code.acquire_owned().await.unwrap();
+            let host = host.clone().acquire_owned().await.unwrap();
+            AdmissionPermit {
+                _host: host,
+                _code: code,
+            }
+        })
+        .await;
+
+        self.queue_len.fetch_sub(1, Ordering::Relaxed);
+        telemetry::admission_queue_len(-1);
+        result.map_err(|_elapsed| Rejection::QueueTimeout)
+    }
+
+    fn code_semaphore(&self, code_id: &str, concurrency: usize) -> Arc<Semaphore> {
+        let mut codes = self.codes.lock().unwrap();
+        if let Some((limit, semaphore)) = codes.semaphores.get(code_id)
+            && *limit == concurrency
+        {
+            return semaphore.clone();
+        }
+        // Jobs holding permits of the old semaphore aren't counted in the new one.
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        codes
+            .semaphores
+            .insert(code_id.to_string(), (concurrency, semaphore.clone()));
+        if codes.semaphores.len() >= codes.sweep_at {
+            codes.evict_idle();
+        }
+        semaphore
+    }
+
+    pub(crate) fn stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            max_instances: self.max_instances,
+            available_instances: self.instances.available_permits(),
+            max_isolates: self.max_isolates,
+            available_isolates: self.isolates.available_permits(),
+            queue_len: self.queue_len.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn rejected_response(&self) -> Response {
+        let mut response = response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Bytes::from("Service Unavailable: all instances are busy"),
+        );
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, self.limits.retry_after.as_secs().max(1).into());
+        response
+    }
+}
+
+impl Codes {
+    /// Drops the semaphores nobody holds a permit of or waits on.
+    fn evict_idle(&mut self) {
+        self.semaphores
+            .retain(|_, (_, semaphore)| Arc::strong_count(semaphore) > 1);
+        self.sweep_at = (self.semaphores.len() * 2).max(MIN_SWEEP_AT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admit() {
+        let admission = Admission::new(
+            AdmissionLimits {
+                max_instances: Some(2),
+                max_isolates: Some(1),
+                max_queue_len: 1,
+                max_queue_wait: Duration::from_millis(10),
+                ..Default::default()
+            },
+            0,
+        );
+
+        let a = admission.admit(CodeKind::Wasm, "a", 1).await.unwrap();
+        assert_eq!(
+            admission.stats(),
+            AdmissionStats {
+                max_instances: 2,
+                available_instances: 1,
+                max_isolates: 1,
+                available_isolates: 1,
+                queue_len: 0,
+            }
+        );
+        assert_eq!(
+            admission.admit(CodeKind::Wasm, "a", 1).await.err(),
+            Some(Rejection::QueueTimeout)
+        );
+        // One waiter fills the queue, so the next is rejected without waiting.
+        let (waiting, full) = tokio::join!(
+            admission.admit(CodeKind::Wasm, "a", 1),
+            admission.admit(CodeKind::Wasm, "a", 1),
+        );
+        assert_eq!(waiting.err(), Some(Rejection::QueueTimeout));
+        assert_eq!(full.err(), Some(Rejection::QueueFull));
+        assert_eq!(admission.stats().queue_len, 0);
+        let _b = admission.admit(CodeKind::Wasm, "b", 1).await.unwrap();
+        assert_eq!(
+            admission.admit(CodeKind::Wasm, "c", 1).await.err(),
+            Some(Rejection::QueueTimeout)
+        );
+
+        // JS has its own host permits, but shares the code permits.
+        let _js = admission.admit(CodeKind::Js, "c", 1).await.unwrap();
+        assert_eq!(
+            admission.admit(CodeKind::Js, "d", 1).await.err(),
+            Some(Rejection::QueueTimeout)
+        );
+        assert_eq!(
+            admission.admit(CodeKind::Js, "a", 1).await.err(),
+            Some(Rejection::QueueTimeout)
+        );
+
+        drop(a);
+        let _a = admission.admit(CodeKind::Wasm, "a", 1).await.unwrap();
+
+        let response = admission.rejected_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+
+    #[tokio::test]
+    async fn test_evicts_idle_codes() {
+        let admission = Admission::new(Default::default(), 2);
+        let busy = admission.admit(CodeKind::Wasm, "busy", 1).await.unwrap();
+        for index in 0..MIN_SWEEP_AT * 4 {
+            let code_id = index.to_string();
+            drop(admission.admit(CodeKind::Wasm, &code_id, 1).await.unwrap());
+        }
+
+        let codes = admission.codes.lock().unwrap();
+        assert!(codes.semaphores.len() <= MIN_SWEEP_AT);
+        assert!(codes.semaphores.contains_key("busy"));
+        drop(busy);
+    }
+}
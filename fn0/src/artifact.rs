@@ -0,0 +1,134 @@
+//! Header that `compile` puts in front of the precompiled wasm.
+//!
+//! Artifacts made before the header existed start with the ELF magic and are read as they are.
+//! A baseline artifact also carries the source wasm, right after the header with its length
+//! as u64 LE, so the executor can build the optimized tier from it later.
+
+const MAGIC: &[u8; 4] = b"fn0\0";
+const HEADER_LEN: usize = 8;
+const SOURCE_LEN_LEN: usize = 8;
+
+const FLAG_PRE_INITIALIZED: u8 = 1;
+const FLAG_BASELINE: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ArtifactHeader {
+    /// Initialization already ran under Wizer and its memory is in the data segments.
+    pub pre_initialized: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Artifact<'a> {
+    pub header: ArtifactHeader,
+    pub precompiled: &'a [u8],
+    /// Set for a Winch build, which is loaded with the baseline engine.
+    pub source: Option<&'a [u8]>,
+}
+
+pub(crate) fn encode(
+    header: ArtifactHeader,
+    precompiled: Vec<u8>,
+    source: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut flags = 0;
+    if header.pre_initialized {
+        flags |= FLAG_PRE_INITIALIZED;
+    }
+    if source.is_some() {
+        flags |= FLAG_BASELINE;
+    }
+    let source_bytes = source.map_or(0, |source| SOURCE_LEN_LEN + source.len());
+    let mut bytes = Vec::with_capacity(HEADER_LEN + source_bytes + precompiled.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&[flags, 0, 0, 0]);
+    if let Some(source) = source {
+        bytes.extend_from_slice(&(source.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(source);
+    }
+    bytes.extend_from_slice(&precompiled);
+    bytes
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> anyhow::Result<Artifact<'_>> {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Ok(Artifact {
+            header: ArtifactHeader::default(),
+            precompiled: bytes,
+            source: None,
+        });
+    }
+    let flags = bytes[MAGIC.len()];
+    let header = ArtifactHeader {
+        pre_initialized: flags & FLAG_PRE_INITIALIZED != 0,
+    };
+    let rest = &bytes[HEADER_LEN..];
+    if flags & FLAG_BASELINE == 0 {
+        return Ok(Artifact {
+            header,
+            precompiled: rest,
+            source: None,
+        });
+    }
+
+    let (source_len, rest) = rest
+        .split_first_chunk::<SOURCE_LEN_LEN>()
+        .ok_or_else(|| anyhow::anyhow!("artifact truncated in source length"))?;
+    let source_len = usize::try_from(u64::from_le_bytes(*source_len))?;
+    if rest.len() < source_len {
+        anyhow::bail!("artifact truncated in source");
+    }
+    let (source, precompiled) = rest.split_at(source_len);
+    Ok(Artifact {
+        header,
+        precompiled,
+        source: Some(source),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let header = ArtifactHeader {
+            pre_initialized: true,
+        };
+        let bytes = encode(header, b"\x7fELF...".to_vec(), None);
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            Artifact {
+                header,
+                precompiled: b"\x7fELF...",
+                source: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_round_trip_with_source() {
+        let header = ArtifactHeader::default();
+        let bytes = encode(header, b"\x7fELF...".to_vec(), Some(b"\0asm..."));
+        assert_eq!(
+            decode(&bytes).unwrap(),
+            Artifact {
+                header,
+                precompiled: b"\x7fELF...",
+                source: Some(b"\0asm..."),
+            }
+        );
+        assert!(decode(&bytes[..HEADER_LEN + SOURCE_LEN_LEN + 2]).is_err());
+    }
+
+    #[test]
+    fn test_without_header() {
+        assert_eq!(
+            decode(b"\x7fELF...").unwrap(),
+            Artifact {
+                header: ArtifactHeader::default(),
+                precompiled: b"\x7fELF...",
+                source: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! CGI-style execution of WASI preview1 core modules.
+//!
+//! The request body is stdin and the request line and headers are CGI meta-variables in env.
+//! The module writes CGI response headers, a blank line and the body to stdout.
+
+use crate::{
+    Body, CodeLimits, Response,
+    admission::AdmissionPermit,
+    execute::{Call, response},
+    fn0_error::ErrorKind,
+    guest_log::{GuestLog, GuestLogStream},
+    memory_usage::MemoryUsage,
+    telemetry,
+    tiering::Hotness,
+    trace_context,
+};
+use anyhow::anyhow;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::{
+    StatusCode,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderName, HeaderValue, LOCATION},
+};
+use measure_cpu_time::{Clock, TimeTracker, measure_cpu_time};
+use std::sync::{Arc, atomic::Ordering};
+use wasmtime::{Engine, InstancePre, Linker, ResourceLimiter, Store};
+use wasmtime_wasi::{
+    I32Exit, WasiCtx,
+    p1::{self, WasiP1Ctx},
+    p2::pipe::{MemoryInputPipe, MemoryOutputPipe},
+};
+
+/// stdout is buffered until the module exits, so it's bounded apart from linear memory.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024 * 1024;
+
+pub struct CgiState<C: Clock> {
+    wasi: WasiP1Ctx,
+    time_tracker: TimeTracker<C>,
+    code_id: String,
+    is_timeout: bool,
+    limits: CodeLimits,
+    memory: MemoryUsage,
+    _permit: AdmissionPermit,
+}
+
+impl<C: Clock> ResourceLimiter for CgiState<C> {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(self.memory.memory_growing(current, desired))
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(self.memory.table_growing(current, desired))
+    }
+}
+
+pub(crate) fn linker<C: Clock>(engine: &Engine) -> Linker<CgiState<C>> {
+    let mut linker = Linker::new(engine);
+    p1::add_to_linker_async(&mut linker, |state: &mut CgiState<C>| &mut state.wasi).unwrap();
+    linker
+}
+
+pub(crate) async fn handle_request<C>(
+    pre: InstancePre<CgiState<C>>,
+    call: Call,
+    clock: C,
+    hotness: Arc<Hotness>,
+) -> Response
+where
+    C: Clock,
+{
+    let Call {
+        req,
+        code_id,
+        limits,
+        env,
+        invocation,
+        permit,
+        errors,
+    } = call;
+    let started_at = tokio::time::Instant::now();
+    let (parts, body) = req.into_parts();
+    // Meta-variables come last, so they win over code entries of the same name.
+    let mut env_vars = match env.expose_as_env {
+        true => env.entries.clone(),
+        false => vec![],
+    };
+    env_vars.extend(meta_variables(&parts));
+    let stdin = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(_) => {
+            return response(
+                StatusCode::BAD_REQUEST,
+                Bytes::from("Bad Request: failed to read the request body"),
+            );
+        }
+    };
+
+    let stdout = MemoryOutputPipe::new(MAX_OUTPUT_BYTES);
+    let guest_log = GuestLog::new(&code_id, invocation.request_id().clone(), limits.log_bytes);
+    let time_tracker = TimeTracker::new(clock);
+    let memory = MemoryUsage::new(
+        code_id.clone(),
+        invocation.span().clone(),
+        limits.memory_bytes,
+    );
+    let is_memory_denied = memory.is_denied();
+
+    let mut store = Store::new(
+        pre.module().engine(),
+        CgiState {
+            wasi: WasiCtx::builder()
+                .stdin(MemoryInputPipe::new(stdin))
+                .stdout(stdout.clone())
+                .stderr(guest_log.output(GuestLogStream::Stderr))
+                .envs(&env_vars)
+                .args(&[&code_id])
+                .build_p1(),
+            time_tracker: time_tracker.clone(),
+            code_id: code_id.clone(),
+            is_timeout: false,
+            limits,
+            memory,
+            _permit: permit,
+        },
+    );
+    store.limiter(|state| state);
+    store.epoch_deadline_trap();
+    store.set_epoch_deadline(1);
+    store.epoch_deadline_callback(|mut context| {
+        let state = context.data_mut();
+        let cpu_time = state.time_tracker.duration();
+        if cpu_time > state.limits.cpu_time {
+            telemetry::cpu_timeout(&state.code_id, cpu_time);
+            state.is_timeout = true;
+            return Ok(wasmtime::UpdateDeadline::Interrupt);
+        }
+        Ok(wasmtime::UpdateDeadline::Yield(1))
+    });
+
+    // A core module has no separate initialization, so `_start` is covered by `cpu_time` as a whole.
+    let result = tokio::time::timeout_at(
+        started_at + limits.duration,
+        measure_cpu_time(time_tracker.clone(), async {
+            let instance = pre.instantiate_async(&mut store).await?;
+            let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+            start.call_async(&mut store, ()).await
+        }),
+    )
+    .await;
+    telemetry::cpu_time(&code_id, time_tracker.duration());
+    trace_context::record_cpu_time(invocation.span(), time_tracker.duration());
+    hotness.record_cpu_time(time_tracker.duration());
+
+    let result = match result {
+        Ok(result) => result,
+        Err(_elapsed) => {
+            telemetry::duration_timeout(&code_id, started_at.elapsed());
+            return errors.response(ErrorKind::DurationTimeout, None);
+        }
+    };
+    if let Err(error) = result {
+        match error.downcast_ref::<I32Exit>() {
+            Some(I32Exit(0)) => {}
+            Some(I32Exit(code)) => {
+                telemetry::cgi_exit_code(&code_id, *code);
+                let detail = format!("exit code {code}");
+                return errors.response(ErrorKind::ExitCode, Some(&detail));
+            }
+            None => {
+                let detail = format!("{error:?}");
+                if store.data().is_timeout {
+                    return errors.response(ErrorKind::CpuTimeout, Some(&detail));
+                }
+                if is_memory_denied.load(Ordering::Relaxed) {
+                    return errors.response(ErrorKind::MemoryLimitExceeded, Some(&detail));
+                }
+                telemetry::trapped(&code_id, &detail);
+                return errors.response(ErrorKind::Trapped, Some(&detail));
+            }
+        }
+    }
+
+    match parse_output(stdout.contents()) {
+        Ok(response) => response,
+        Err(error) => {
+            let detail = error.to_string();
+            telemetry::cgi_invalid_output(&code_id, &detail);
+            errors.response(ErrorKind::InvalidOutput, Some(&detail))
+        }
+    }
+}
+
+/// RFC 3875 meta-variables. Other headers become `HTTP_*`.
+fn meta_variables(parts: &hyper::http::request::Parts) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        (
+            "SERVER_PROTOCOL".to_string(),
+            format!("{:?}", parts.version),
+        ),
+        ("REQUEST_METHOD".to_string(), parts.method.to_string()),
+        ("PATH_INFO".to_string(), parts.uri.path().to_string()),
+        (
+            "QUERY_STRING".to_string(),
+            parts.uri.query().unwrap_or_default().to_string(),
+        ),
+    ];
+    for (name, value) in &parts.headers {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        let key = match *name {
+            CONTENT_LENGTH => "CONTENT_LENGTH".to_string(),
+            CONTENT_TYPE => "CONTENT_TYPE".to_string(),
+            _ => format!("HTTP_{}", name.as_str().to_uppercase().replace('-', "_")),
+        };
+        env.push((key, value.to_string()));
+    }
+    env
+}
+
+fn parse_output(output: Bytes) -> anyhow::Result<Response> {
+    let (header_end, body_start) =
+        find_header_end(&output).ok_or_else(|| anyhow!("no blank line after the headers"))?;
+    let head = std::str::from_utf8(&output[..header_end])?;
+
+    let mut builder = hyper::Response::builder();
+    let mut status = None;
+    let mut has_location = false;
+    for line in head.lines().filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid header line"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next().unwrap_or_default();
+            status = Some(StatusCode::from_bytes(code.as_bytes())?);
+            continue;
+        }
+        let name = HeaderName::from_bytes(name.trim().as_bytes())?;
+        has_location |= name == LOCATION;
+        builder = builder.header(name, HeaderValue::from_str(value)?);
+    }
+    let status = status.unwrap_or(if has_location {
+        StatusCode::FOUND
+    } else {
+        StatusCode::OK
+    });
+
+    let body =
+        http_body_util::Full::new(output.slice(body_start..)).map_err(|never| match never {});
+    Ok(builder.status(status).body(Body::new(body))?)
+}
+
+fn find_header_end(output: &[u8]) -> Option<(usize, usize)> {
+    let lf = output.windows(2).position(|window| window == b"\n\n");
+    let crlf = output.windows(4).position(|window| window == b"\r\n\r\n");
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) if crlf < lf => Some((crlf, crlf + 4)),
+        (Some(lf), _) => Some((lf, lf + 2)),
+        (None, Some(crlf)) => Some((crlf, crlf + 4)),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let response = parse_output(Bytes::from(
+            "Status: 404 Not Found\r\nX-A: b\r\n\r\nmissing",
+        ))
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["x-a"], "b");
+        let body = futures::executor::block_on(response.into_body().collect())
+            .unwrap()
+            .to_bytes();
+        assert_eq!(body, "missing");
+
+        let response = parse_output(Bytes::from("Location: /next\n\n")).unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+
+        assert!(parse_output(Bytes::from("hello")).is_err());
+    }
+}
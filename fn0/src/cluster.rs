@@ -0,0 +1,627 @@
+//! Routing of requests over the hosts of a cluster, see "Internal Implementation" in the README.
+//!
+//! Hosts form a memberlist cluster. They gossip the codes they have warm with `warm_up_map`,
+//! and their load as the node meta. A request goes to the less loaded of two random hosts with
+//! its code warm, then to the other one if rejected, and otherwise runs here with a cold start.
+//! Forwarded requests carry `x-fn0-forwarded` and run where they land, or get a 503 there.
+//! The header is only trusted from members, and requests with large or streaming bodies aren't
+//! forwarded, as they would be buffered.
+//!
+//! Codes are gossiped by their numeric id. Requests of other codes always run here.
+
+use crate::{
+    HttpLimits, Request, Response,
+    gossip::{self, Memberlist},
+    telemetry,
+    warm_up_map::{CodeId, NodeId, NodePresence, WarmUpMapNode},
+};
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    HeaderMap, StatusCode,
+    body::Body as _,
+    header::{CONNECTION, CONTENT_LENGTH, HeaderValue, TRANSFER_ENCODING},
+    http::request::Parts,
+};
+use memberlist::{
+    delegate::{EventDelegate, NodeDelegate},
+    proto::{Meta, NodeState},
+};
+use rand::seq::IndexedRandom;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const FORWARDED_HEADER: &str = "x-fn0-forwarded";
+
+/// Of the connection to the peer, not of the response, see RFC 9110 section 7.6.1.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Gossips a warm_up_map message goes in from each host, like memberlist's own broadcasts.
+const RETRANSMITS: usize = 4;
+
+pub struct ClusterConfig {
+    pub node_id: u128,
+    /// memberlist listens on it with TCP and UDP. Every host uses the same port.
+    pub gossip_addr: SocketAddrV4,
+    /// Port of the HTTP server of every host, which calls `Cluster::route`.
+    pub http_port: u16,
+    /// Hosts to join the cluster through. None for the first host.
+    pub seeds: Vec<Ipv4Addr>,
+    /// A code is advertised as cold once it hasn't run here for this long.
+    pub warm_ttl: Duration,
+    /// How often the load and the codes that went cold are advertised.
+    pub advertise_interval: Duration,
+    /// Past it, a host counts as rejecting the request.
+    pub forward_connect_timeout: Duration,
+    /// Requests with larger bodies, or without a known length, run here.
+    /// Defaults to `HttpLimits::max_request_body_bytes`.
+    pub max_forward_body_bytes: usize,
+}
+
+impl ClusterConfig {
+    pub fn new(node_id: u128, gossip_addr: SocketAddrV4, http_port: u16) -> Self {
+        Self {
+            node_id,
+            gossip_addr,
+            http_port,
+            seeds: Vec::new(),
+            warm_ttl: Duration::from_secs(5 * 60),
+            advertise_interval: Duration::from_secs(1),
+            forward_connect_timeout: Duration::from_millis(500),
+            max_forward_body_bytes: HttpLimits::default().max_request_body_bytes,
+        }
+    }
+}
+
+pub struct Cluster {
+    memberlist: Memberlist<ClusterDelegate>,
+    delegate: ClusterDelegate,
+    node_id: NodeId,
+    addr: Ipv4Addr,
+    http_port: u16,
+    warm_ttl: Duration,
+    max_forward_body_bytes: usize,
+    /// Codes that ran here, with when they last did.
+    warm: Mutex<HashMap<CodeId, Instant>>,
+    client: reqwest::Client,
+}
+
+impl Cluster {
+    /// Starts memberlist and joins through `config.seeds`. `load` is advertised to other hosts,
+    /// like `Fn0::instances`.
+    pub async fn join(
+        config: ClusterConfig,
+        load: impl Fn() -> u32 + Send + Sync + 'static,
+    ) -> Result<Arc<Self>> {
+        let delegate = ClusterDelegate {
+            warm_up_map: Default::default(),
+            load: Arc::new(load),
+            advertised_load: Default::default(),
+            retransmits: Default::default(),
+        };
+        let memberlist =
+            gossip::start(config.node_id, config.gossip_addr, delegate.clone()).await?;
+        gossip::join(&memberlist, config.gossip_addr, &config.seeds).await;
+
+        let client = reqwest::Client::builder()
+            .connect_timeout(config.forward_connect_timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let cluster = Arc::new(Self {
+            memberlist,
+            delegate,
+            node_id: config.node_id,
+            addr: *config.gossip_addr.ip(),
+            http_port: config.http_port,
+            warm_ttl: config.warm_ttl,
+            max_forward_body_bytes: config.max_forward_body_bytes,
+            warm: Default::default(),
+            client,
+        });
+        tokio::spawn(advertise(
+            Arc::downgrade(&cluster),
+            config.advertise_interval,
+        ));
+        Ok(cluster)
+    }
+
+    /// Runs `request` on a host with `code_id` warm, or with `run_local` here.
+    /// `remote_ip` is the peer of the connection, which tells forwarded requests from clients.
+    /// Errors of `run_local` are returned as they are, and a 503 it returns is a rejection.
+    pub async fn route<F, Fut>(
+        &self,
+        code_id: &str,
+        mut request: Request,
+        remote_ip: IpAddr,
+        run_local: F,
+    ) -> Result<Response>
+    where
+        F: FnOnce(Request) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let numeric_code_id = code_id.parse::<CodeId>().ok();
+        if request.headers().contains_key(FORWARDED_HEADER) && self.is_member(remote_ip).await {
+            let response = run_local(request).await?;
+            self.ran_locally(numeric_code_id, response.status()).await;
+            return Ok(response);
+        }
+        // A client can't make a host skip routing.
+        request.headers_mut().remove(FORWARDED_HEADER);
+
+        let body_len = request.body().size_hint().exact();
+        let hosts = match numeric_code_id {
+            Some(code_id)
+                if body_len.is_some_and(|len| len <= self.max_forward_body_bytes as u64) =>
+            {
+                self.pick_hosts(code_id).await
+            }
+            _ => Vec::new(),
+        };
+        let request = if hosts.is_empty() {
+            request
+        } else {
+            let (parts, body) = request.into_parts();
+            let body = body.collect().await?.to_bytes();
+            for addr in hosts {
+                match self.forward(addr, &parts, body.clone()).await {
+                    Ok(response) if response.status() != StatusCode::SERVICE_UNAVAILABLE => {
+                        telemetry::cluster_forward(code_id, "ok");
+                        return Ok(response);
+                    }
+                    Ok(_) => telemetry::cluster_forward(code_id, "rejected"),
+                    Err(_) => telemetry::cluster_forward(code_id, "unreachable"),
+                }
+            }
+            let body = Full::new(body).map_err(|never| match never {});
+            Request::from_parts(parts, body.boxed_unsync())
+        };
+
+        let response = run_local(request).await?;
+        self.ran_locally(numeric_code_id, response.status()).await;
+        Ok(response)
+    }
+
+    /// Leaves the cluster, so other hosts stop forwarding here.
+    pub async fn leave(&self, timeout: Duration) -> Result<()> {
+        self.memberlist
+            .leave(timeout)
+            .await
+            .map_err(|err| anyhow!("Failed to leave: {err}"))?;
+        self.memberlist
+            .shutdown()
+            .await
+            .map_err(|err| anyhow!("Failed to shutdown memberlist: {err}"))?;
+        Ok(())
+    }
+
+    pub(crate) async fn is_member(&self, ip: IpAddr) -> bool {
+        ip == self.addr
+            || self
+                .memberlist
+                .online_members()
+                .await
+                .iter()
+                .any(|node| node.address().ip() == ip)
+    }
+
+    /// Up to two hosts with `code_id` warm, less loaded first.
+    /// Those after this host are dropped, as it runs the request itself then.
+    async fn pick_hosts(&self, code_id: CodeId) -> Vec<Ipv4Addr> {
+        let mut loads = self
+            .memberlist
+            .online_members()
+            .await
+            .iter()
+            .filter_map(|node| {
+                let SocketAddr::V4(addr) = node.address() else {
+                    return None;
+                };
+                let load = u32::from_be_bytes(node.meta().as_bytes().try_into().ok()?);
+                Some((*addr.ip(), load))
+            })
+            .collect::<HashMap<_, _>>();
+        loads.insert(self.addr, (self.delegate.load)());
+
+        let candidates = self
+            .delegate
+            .warm_up_map
+            .warm_nodes(code_id)
+            .await
+            .into_iter()
+            .filter_map(|node| Some((node.addr, *loads.get(&node.addr)?)))
+            .collect::<Vec<_>>();
+        let mut picked = candidates
+            .choose_multiple(&mut rand::rng(), 2)
+            .copied()
+            .collect::<Vec<_>>();
+        picked.sort_by_key(|(_, load)| *load);
+        picked
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .take_while(|addr| *addr != self.addr)
+            .collect()
+    }
+
+    async fn forward(&self, addr: Ipv4Addr, parts: &Parts, body: Bytes) -> Result<Response> {
+        let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+        let mut headers = parts.headers.clone();
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(FORWARDED_HEADER, HeaderValue::from_static("1"));
+        let response = self
+            .client
+            .request(
+                parts.method.clone(),
+                format!("http://{addr}:{}{path}", self.http_port),
+            )
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+        let mut response = hyper::Response::from(response)
+            .map(|body| body.map_err(anyhow::Error::from).boxed_unsync());
+        remove_hop_by_hop_headers(response.headers_mut());
+        Ok(response)
+    }
+
+    /// A 503 is a rejection, so the code didn't run.
+    async fn ran_locally(&self, code_id: Option<CodeId>, status: StatusCode) {
+        if let Some(code_id) = code_id
+            && status != StatusCode::SERVICE_UNAVAILABLE
+        {
+            self.mark_warm(code_id).await;
+        }
+    }
+
+    async fn mark_warm(&self, code_id: CodeId) {
+        let is_new = self
+            .warm
+            .lock()
+            .unwrap()
+            .insert(code_id, Instant::now())
+            .is_none();
+        if is_new {
+            let presence = self.presence(true);
+            self.delegate
+                .warm_up_map
+                .set_local_presence(code_id, presence)
+                .await;
+        }
+    }
+
+    async fn advertise_cold(&self) {
+        let mut cold = Vec::new();
+        self.warm.lock().unwrap().retain(|code_id, ran_at| {
+            let is_warm = ran_at.elapsed() < self.warm_ttl;
+            if !is_warm {
+                cold.push(*code_id);
+            }
+            is_warm
+        });
+        for code_id in cold {
+            let presence = self.presence(false);
+            self.delegate
+                .warm_up_map
+                .set_local_presence(code_id, presence)
+                .await;
+        }
+    }
+
+    fn presence(&self, is_alive: bool) -> NodePresence {
+        NodePresence {
+            id: self.node_id,
+            addr: self.addr,
+            updated_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            is_alive,
+        }
+    }
+}
+
+/// Also the headers `connection` names.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let named = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    for name in HOP_BY_HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(named.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+async fn advertise(cluster: Weak<Cluster>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let Some(cluster) = cluster.upgrade() else {
+            return;
+        };
+        cluster.advertise_cold().await;
+        let delegate = &cluster.delegate;
+        if (delegate.load)() != delegate.advertised_load.load(Ordering::Relaxed)
+            && let Err(err) = cluster.memberlist.update_node(interval.period()).await
+        {
+            telemetry::cluster_error("update_node", &err.to_string());
+        }
+    }
+}
+
+/// Feeds `WarmUpMapNode`, which knows hosts by their IPv4 address,
+/// and puts the load of this host in its meta.
+#[derive(Clone)]
+struct ClusterDelegate {
+    warm_up_map: Arc<WarmUpMapNode>,
+    load: Arc<dyn Fn() -> u32 + Send + Sync>,
+    /// As of the last `node_meta`.
+    advertised_load: Arc<AtomicU32>,
+    /// Messages of `warm_up_map` with the gossips left to send them in. It hands each out once,
+    /// but memberlist asks for broadcasts once per gossip target.
+    retransmits: Arc<Mutex<VecDeque<(Bytes, usize)>>>,
+}
+
+fn ipv4_node(node: &NodeState<NodeId, SocketAddr>) -> Option<Arc<NodeState<NodeId, Ipv4Addr>>> {
+    let SocketAddr::V4(addr) = node.address() else {
+        return None;
+    };
+    Some(Arc::new(
+        NodeState::new(*node.id(), *addr.ip(), node.state())
+            .with_meta(node.meta().clone())
+            .with_protocol_version(node.protocol_version())
+            .with_delegate_version(node.delegate_version()),
+    ))
+}
+
+impl EventDelegate for ClusterDelegate {
+    type Id = NodeId;
+    type Address = SocketAddr;
+
+    async fn notify_join(&self, node: Arc<NodeState<Self::Id, Self::Address>>) {
+        if let Some(node) = ipv4_node(&node) {
+            self.warm_up_map.notify_join(node).await;
+        }
+    }
+
+    async fn notify_update(&self, _node: Arc<NodeState<Self::Id, Self::Address>>) {}
+
+    async fn notify_leave(&self, node: Arc<NodeState<Self::Id, Self::Address>>) {
+        if let Some(node) = ipv4_node(&node) {
+            self.warm_up_map.notify_leave(node).await;
+        }
+    }
+}
+
+impl NodeDelegate for ClusterDelegate {
+    async fn node_meta(&self, _limit: usize) -> Meta {
+        let load = (self.load)();
+        self.advertised_load.store(load, Ordering::Relaxed);
+        Meta::try_from(load.to_be_bytes().as_slice()).unwrap()
+    }
+
+    async fn notify_message(&self, msg: Cow<'_, [u8]>) {
+        self.warm_up_map.notify_message(msg).await;
+    }
+
+    async fn broadcast_messages<F>(
+        &self,
+        limit: usize,
+        encoded_len: F,
+    ) -> impl Iterator<Item = Bytes> + Send
+    where
+        F: Fn(Bytes) -> (usize, Bytes) + Send + Sync + 'static,
+    {
+        let encoded_len = Arc::new(encoded_len);
+        let new_messages = self
+            .warm_up_map
+            .broadcast_messages(limit, {
+                let encoded_len = encoded_len.clone();
+                move |bytes| encoded_len(bytes)
+            })
+            .await;
+        let mut retransmits = self.retransmits.lock().unwrap();
+        retransmits.extend(new_messages.map(|bytes| (bytes, RETRANSMITS)));
+
+        let mut total_size = 0;
+        let mut messages = Vec::new();
+        for (bytes, left) in retransmits.iter_mut() {
+            let (len, bytes) = encoded_len(bytes.clone());
+            if total_size + len > limit {
+                break;
+            }
+            total_size += len;
+            messages.push(bytes);
+            *left -= 1;
+        }
+        retransmits.retain(|(_, left)| *left > 0);
+        messages.into_iter()
+    }
+
+    async fn local_state(&self, join: bool) -> Bytes {
+        self.warm_up_map.local_state(join).await
+    }
+
+    async fn merge_remote_state(&self, buf: &[u8], join: bool) {
+        self.warm_up_map.merge_remote_state(buf, join).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        execute::response,
+        test_util::{eventually, free_port},
+    };
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use std::sync::atomic::AtomicBool;
+    use tokio::net::TcpListener;
+
+    struct TestHost {
+        cluster: Arc<Cluster>,
+        load: Arc<AtomicU32>,
+        rejects: Arc<AtomicBool>,
+    }
+
+    async fn start_host(index: u8, gossip_port: u16, http_port: u16) -> TestHost {
+        let ip = Ipv4Addr::new(127, 0, 0, index);
+        let mut config =
+            ClusterConfig::new(index as u128, SocketAddrV4::new(ip, gossip_port), http_port);
+        if index != 1 {
+            config.seeds = vec![Ipv4Addr::new(127, 0, 0, 1)];
+        }
+        config.advertise_interval = Duration::from_millis(50);
+        let load = Arc::new(AtomicU32::new(0));
+        let cluster = Cluster::join(config, {
+            let load = load.clone();
+            move || load.load(Ordering::Relaxed)
+        })
+        .await
+        .unwrap();
+        let rejects = Arc::new(AtomicBool::new(false));
+        let listener = TcpListener::bind((ip, http_port)).await.unwrap();
+        tokio::spawn(serve(listener, cluster.clone(), index, rejects.clone()));
+        TestHost {
+            cluster,
+            load,
+            rejects,
+        }
+    }
+
+    /// Runs every request here as `node-{index}`, or rejects it with a 503.
+    async fn serve(
+        listener: TcpListener,
+        cluster: Arc<Cluster>,
+        index: u8,
+        rejects: Arc<AtomicBool>,
+    ) {
+        loop {
+            let (stream, remote) = listener.accept().await.unwrap();
+            let cluster = cluster.clone();
+            let rejects = rejects.clone();
+            let service = service_fn(move |request: hyper::Request<Incoming>| {
+                let cluster = cluster.clone();
+                let rejects = rejects.clone();
+                async move {
+                    let request =
+                        request.map(|body| body.map_err(anyhow::Error::from).boxed_unsync());
+                    let code_id = request.uri().path().trim_start_matches('/').to_string();
+                    cluster
+                        .route(&code_id, request, remote.ip(), |_request| async move {
+                            let status = if rejects.load(Ordering::Relaxed) {
+                                StatusCode::SERVICE_UNAVAILABLE
+                            } else {
+                                StatusCode::OK
+                            };
+                            Ok(response(status, Bytes::from(format!("node-{index}"))))
+                        })
+                        .await
+                }
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    }
+
+    async fn get(index: u8, http_port: u16, code_id: &str) -> String {
+        reqwest::get(format!("http://127.0.0.{index}:{http_port}/{code_id}"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_remove_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("connection", "keep-alive, x-hop"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("x-hop", "1"),
+            ("content-type", "text/plain"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        remove_hop_by_hop_headers(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["content-type"], "text/plain");
+    }
+
+    #[tokio::test]
+    async fn test_route_over_loopback_cluster() {
+        let gossip_port = free_port();
+        let http_port = free_port();
+        let host1 = start_host(1, gossip_port, http_port).await;
+        let host2 = start_host(2, gossip_port, http_port).await;
+        let host3 = start_host(3, gossip_port, http_port).await;
+        for host in [&host1, &host2, &host3] {
+            eventually(|| async { host.cluster.memberlist.num_online_members().await == 3 }).await;
+        }
+
+        // No host has it warm, so it cold starts where it lands.
+        assert_eq!(get(2, http_port, "7").await, "node-2");
+        eventually(|| async { host1.cluster.pick_hosts(7).await == [Ipv4Addr::new(127, 0, 0, 2)] })
+            .await;
+        assert_eq!(get(1, http_port, "7").await, "node-2");
+
+        // The less loaded host rejects, so the other one runs it.
+        host3.cluster.mark_warm(7).await;
+        host2.load.store(5, Ordering::Relaxed);
+        host3.rejects.store(true, Ordering::Relaxed);
+        eventually(|| async {
+            host1.cluster.pick_hosts(7).await
+                == [Ipv4Addr::new(127, 0, 0, 3), Ipv4Addr::new(127, 0, 0, 2)]
+        })
+        .await;
+        assert_eq!(get(1, http_port, "7").await, "node-2");
+
+        // Both reject, so it cold starts here.
+        host2.rejects.store(true, Ordering::Relaxed);
+        assert_eq!(get(1, http_port, "7").await, "node-1");
+        assert!(host1.cluster.warm.lock().unwrap().contains_key(&7));
+
+        // Codes without a numeric id aren't forwarded.
+        host3.rejects.store(false, Ordering::Relaxed);
+        assert_eq!(get(3, http_port, "not-numeric").await, "node-3");
+
+        // Only members can forward requests.
+        assert!(
+            host1
+                .cluster
+                .is_member(Ipv4Addr::new(127, 0, 0, 3).into())
+                .await
+        );
+        assert!(
+            !host1
+                .cluster
+                .is_member(Ipv4Addr::new(127, 0, 0, 9).into())
+                .await
+        );
+    }
+}
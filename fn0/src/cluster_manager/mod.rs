@@ -0,0 +1,329 @@
+//! Tiered control of the cluster, see `tiered-control.md`.
+//!
+//! Nodes sort the members of the cluster by rank: the newest generation first, then the oldest
+//! node. Rank 0 is the master, which owns the writes of the routing table, the next `√N` nodes
+//! are replicas that cache it, and the rest are workers. A worker registers the codes it warmed
+//! up with the master, which sends them to the replicas in batches, and queries its replica.
+//! Codes that didn't run for `warm_ttl` are unregistered the same way.
+//!
+//! When the master is gone, rank 1 takes over with its cached table. When a node of a newer
+//! generation joins, like during a deployment, it takes over too, and the former master hands
+//! the table over to it.
+//!
+//! Unlike `warm_up_map`, which gossips every update to every node, gossip only carries
+//! liveness. The routing table goes over memberlist's TCP user messages.
+
+mod state;
+
+use crate::{
+    gossip::{self, Memberlist},
+    telemetry,
+};
+use anyhow::{Result, anyhow};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use memberlist::{
+    delegate::{EventDelegate, NodeDelegate},
+    proto::{Meta, NodeState},
+};
+pub use state::Role;
+use state::*;
+use std::{
+    borrow::Cow,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot};
+
+pub struct ClusterManagerConfig {
+    pub node_id: u128,
+    /// memberlist listens on it with TCP and UDP. Every node uses the same port.
+    pub gossip_addr: SocketAddrV4,
+    /// Nodes to join the cluster through. None for the first node.
+    pub seeds: Vec<Ipv4Addr>,
+    /// Of the deployment. Nodes of a newer one take over as master and replicas.
+    pub generation: u64,
+    /// Unix millis. The oldest node of the newest generation is master.
+    pub started_at: u64,
+    /// How long the master batches registrations before sending them to replicas.
+    pub batch_interval: Duration,
+    /// Past it, a query is answered with `None`, and the node runs the request itself.
+    pub query_timeout: Duration,
+    /// How long workers cache the answers of replicas.
+    pub query_cache_ttl: Duration,
+    /// How long a code stays registered after it last ran here.
+    pub warm_ttl: Duration,
+}
+
+impl ClusterManagerConfig {
+    pub fn new(node_id: u128, gossip_addr: SocketAddrV4) -> Self {
+        Self {
+            node_id,
+            gossip_addr,
+            seeds: Vec::new(),
+            generation: 0,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+            batch_interval: Duration::from_millis(100),
+            query_timeout: Duration::from_millis(200),
+            query_cache_ttl: Duration::from_secs(1),
+            warm_ttl: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Handle of the actor that owns the `ClusterState`.
+pub struct ClusterManager {
+    tx: mpsc::Sender<ClusterMsg>,
+    memberlist: Memberlist<ManagerDelegate>,
+    query_timeout: Duration,
+}
+
+impl ClusterManager {
+    pub async fn start(config: ClusterManagerConfig) -> Result<Self> {
+        let me = Member {
+            id: config.node_id,
+            addr: config.gossip_addr.into(),
+            generation: config.generation,
+            started_at: config.started_at,
+        };
+        let (tx, rx) = mpsc::channel(1024);
+        let delegate = ManagerDelegate {
+            tx: tx.clone(),
+            meta: encode_meta(&me),
+        };
+        let memberlist = gossip::start(config.node_id, config.gossip_addr, delegate).await?;
+
+        let state = ClusterState::new(me, config.query_cache_ttl, config.warm_ttl);
+        tokio::spawn(cluster_manager_loop(
+            rx,
+            state,
+            memberlist.clone(),
+            config.batch_interval,
+        ));
+
+        gossip::join(&memberlist, config.gossip_addr, &config.seeds).await;
+
+        Ok(Self {
+            tx,
+            memberlist,
+            query_timeout: config.query_timeout,
+        })
+    }
+
+    pub async fn role(&self) -> Result<Role> {
+        let (resp, rx) = oneshot::channel();
+        self.send(ClusterMsg::Role(resp)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Tells the master this node has `code_id` warm. Called on every run, as it's unregistered
+    /// `warm_ttl` after the last one.
+    pub async fn register_function(&self, code_id: &str) -> Result<()> {
+        self.send(ClusterMsg::RegisterFunction {
+            code_id: code_id.to_string(),
+        })
+        .await
+    }
+
+    /// Nodes with `code_id` warm, or `None` if there are none or the replica didn't answer.
+    pub async fn query_function(&self, code_id: &str) -> Option<Vec<Ipv4Addr>> {
+        let (resp, rx) = oneshot::channel();
+        let msg = ClusterMsg::QueryFunction {
+            code_id: code_id.to_string(),
+            resp,
+        };
+        self.send(msg).await.ok()?;
+        tokio::time::timeout(self.query_timeout, rx)
+            .await
+            .ok()?
+            .ok()?
+    }
+
+    /// Leaves the cluster, so other nodes take over the role of this one.
+    pub async fn leave(&self, timeout: Duration) -> Result<()> {
+        self.memberlist
+            .leave(timeout)
+            .await
+            .map_err(|err| anyhow!("Failed to leave: {err}"))?;
+        self.memberlist
+            .shutdown()
+            .await
+            .map_err(|err| anyhow!("Failed to shutdown memberlist: {err}"))?;
+        self.send(ClusterMsg::Stop).await
+    }
+
+    async fn send(&self, msg: ClusterMsg) -> Result<()> {
+        self.tx
+            .send(msg)
+            .await
+            .map_err(|_| anyhow!("cluster manager is stopped"))
+    }
+}
+
+/// Owns the state, so nothing else locks it. Sends go to their own tasks.
+async fn cluster_manager_loop(
+    mut rx: mpsc::Receiver<ClusterMsg>,
+    mut state: ClusterState,
+    memberlist: Memberlist<ManagerDelegate>,
+    batch_interval: Duration,
+) {
+    let mut flush = tokio::time::interval(batch_interval);
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            _ = flush.tick() => Some(ClusterMsg::Flush),
+        };
+        let Some(msg) = msg else {
+            return;
+        };
+        if matches!(msg, ClusterMsg::Stop) {
+            return;
+        }
+        for (to, control) in state.handle(msg) {
+            let memberlist = memberlist.clone();
+            tokio::spawn(async move {
+                let msg = Bytes::from(sonic_rs::to_vec(&control).unwrap());
+                if let Err(err) = memberlist.send_reliable(&to, msg).await {
+                    telemetry::cluster_error("send", &err.to_string());
+                }
+            });
+        }
+    }
+}
+
+/// Member info of a node is its meta: generation and started_at.
+fn encode_meta(member: &Member) -> Meta {
+    let mut bytes = BytesMut::with_capacity(16);
+    bytes.put_u64(member.generation);
+    bytes.put_u64(member.started_at);
+    Meta::try_from(bytes.freeze()).unwrap()
+}
+
+fn decode_member(node: &NodeState<NodeId, SocketAddr>) -> Option<Member> {
+    let mut meta = node.meta().as_bytes();
+    if meta.len() != 16 {
+        return None;
+    }
+    Some(Member {
+        id: *node.id(),
+        addr: *node.address(),
+        generation: meta.get_u64(),
+        started_at: meta.get_u64(),
+    })
+}
+
+/// Turns gossip events and control messages into `ClusterMsg`s.
+#[derive(Clone)]
+struct ManagerDelegate {
+    tx: mpsc::Sender<ClusterMsg>,
+    meta: Meta,
+}
+
+impl EventDelegate for ManagerDelegate {
+    type Id = NodeId;
+    type Address = SocketAddr;
+
+    async fn notify_join(&self, node: Arc<NodeState<Self::Id, Self::Address>>) {
+        if let Some(member) = decode_member(&node) {
+            let _ = self.tx.send(ClusterMsg::Heartbeat(member)).await;
+        }
+    }
+
+    async fn notify_update(&self, node: Arc<NodeState<Self::Id, Self::Address>>) {
+        self.notify_join(node).await;
+    }
+
+    async fn notify_leave(&self, node: Arc<NodeState<Self::Id, Self::Address>>) {
+        let _ = self.tx.send(ClusterMsg::Leave(*node.id())).await;
+    }
+}
+
+impl NodeDelegate for ManagerDelegate {
+    async fn node_meta(&self, _limit: usize) -> Meta {
+        self.meta.clone()
+    }
+
+    async fn notify_message(&self, msg: Cow<'_, [u8]>) {
+        match sonic_rs::from_slice::<Control>(&msg) {
+            Ok(control) => {
+                let _ = self.tx.send(ClusterMsg::Control(control)).await;
+            }
+            Err(err) => telemetry::cluster_error("decode", &err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{eventually, free_port};
+
+    async fn start_node(index: u8, port: u16, generation: u64, seed: u8) -> ClusterManager {
+        let ip = Ipv4Addr::new(127, 0, 0, index);
+        let mut config = ClusterManagerConfig::new(index as u128, SocketAddrV4::new(ip, port));
+        config.seeds = vec![Ipv4Addr::new(127, 0, 0, seed)];
+        config.generation = generation;
+        config.started_at = index as u64;
+        config.batch_interval = Duration::from_millis(20);
+        config.query_cache_ttl = Duration::ZERO;
+        ClusterManager::start(config).await.unwrap()
+    }
+
+    async fn roles(nodes: &[&ClusterManager]) -> Vec<Role> {
+        let mut roles = Vec::new();
+        for node in nodes {
+            roles.push(node.role().await.unwrap());
+        }
+        roles
+    }
+
+    fn ips(indexes: &[u8]) -> Option<Vec<Ipv4Addr>> {
+        Some(
+            indexes
+                .iter()
+                .map(|index| Ipv4Addr::new(127, 0, 0, *index))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tiered_cluster_over_loopback() {
+        use Role::*;
+
+        let port = free_port();
+        let node1 = start_node(1, port, 1, 1).await;
+        let node2 = start_node(2, port, 1, 1).await;
+        let node3 = start_node(3, port, 1, 1).await;
+        let node4 = start_node(4, port, 1, 1).await;
+        let nodes = [&node1, &node2, &node3, &node4];
+        eventually(|| async { roles(&nodes).await == [Master, Replica, Replica, Worker] }).await;
+
+        // Registered with the master, and queried from a replica.
+        node4.register_function("a").await.unwrap();
+        node3.register_function("a").await.unwrap();
+        eventually(|| async { node4.query_function("a").await == ips(&[3, 4]) }).await;
+        assert_eq!(node4.query_function("b").await, None);
+
+        // Rank 1 takes over with its cached table.
+        node1.leave(Duration::from_secs(1)).await.unwrap();
+        let nodes = [&node2, &node3, &node4];
+        eventually(|| async { roles(&nodes).await == [Master, Replica, Worker] }).await;
+        assert_eq!(node4.query_function("a").await, ips(&[3, 4]));
+        node4.register_function("b").await.unwrap();
+        eventually(|| async { node4.query_function("b").await == ips(&[4]) }).await;
+
+        // A node of a new deployment takes over, and gets the table handed over.
+        let node5 = start_node(5, port, 2, 2).await;
+        let nodes = [&node2, &node3, &node4, &node5];
+        eventually(|| async { roles(&nodes).await == [Replica, Replica, Worker, Master] }).await;
+        eventually(|| async {
+            node5.query_function("a").await == ips(&[3, 4])
+                && node5.query_function("b").await == ips(&[4])
+        })
+        .await;
+        eventually(|| async { node4.query_function("b").await == ips(&[4]) }).await;
+    }
+}
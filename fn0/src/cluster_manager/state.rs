@@ -0,0 +1,632 @@
+//! `ClusterState` and the messages of the `ClusterManager` actor.
+//!
+//! Handling a message only changes the state, and returns the control messages to send,
+//! so the actor loop never waits on the network.
+
+use crate::telemetry;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+pub(crate) type NodeId = u128;
+pub(crate) type RoutingTable = HashMap<String, Vec<Ipv4Addr>>;
+type QueryResp = oneshot::Sender<Option<Vec<Ipv4Addr>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Owns the writes of the routing table, and sends them to replicas.
+    Master,
+    /// Caches the routing table, and answers queries of workers.
+    Replica,
+    Worker,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Master => "master",
+            Self::Replica => "replica",
+            Self::Worker => "worker",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Member {
+    pub(crate) id: NodeId,
+    pub(crate) addr: SocketAddr,
+    pub(crate) generation: u64,
+    pub(crate) started_at: u64,
+}
+
+impl Member {
+    pub(crate) fn ip(&self) -> Option<Ipv4Addr> {
+        match self.addr {
+            SocketAddr::V4(addr) => Some(*addr.ip()),
+            SocketAddr::V6(_) => None,
+        }
+    }
+
+    /// The newest generation comes first, then the oldest node.
+    fn rank_key(&self) -> (std::cmp::Reverse<u64>, u64, NodeId) {
+        (std::cmp::Reverse(self.generation), self.started_at, self.id)
+    }
+}
+
+pub(crate) enum ClusterMsg {
+    /// A node joined or is alive, from gossip.
+    Heartbeat(Member),
+    /// A node left or died, from gossip.
+    Leave(NodeId),
+    /// This node ran `code_id`.
+    RegisterFunction {
+        code_id: String,
+    },
+    QueryFunction {
+        code_id: String,
+        resp: QueryResp,
+    },
+    /// From another node.
+    Control(Control),
+    Role(oneshot::Sender<Role>),
+    /// Unregisters the codes that got cold here, and sends the registrations the master
+    /// batched to replicas.
+    Flush,
+    Stop,
+}
+
+/// Messages between nodes, sent over memberlist's TCP user messages.
+/// Gossip only carries liveness and the `Member` of each node.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Control {
+    /// To the master. A former master forwards it to the current one.
+    Register {
+        code_id: String,
+        addr: Ipv4Addr,
+    },
+    /// To the master, when `code_id` got cold on the node. Forwarded like `Register`.
+    Unregister {
+        code_id: String,
+        addr: Ipv4Addr,
+    },
+    /// From the master to replicas, the registrations and unregistrations since the last batch.
+    Updates {
+        table: RoutingTable,
+        removed: RoutingTable,
+    },
+    /// From the master to a node that just became a replica.
+    Snapshot {
+        table: RoutingTable,
+    },
+    Query {
+        query_id: u64,
+        code_id: String,
+        reply_to: SocketAddr,
+    },
+    QueryReply {
+        query_id: u64,
+        addrs: Option<Vec<Ipv4Addr>>,
+    },
+    /// From a master to the node that took over, like a node of a new deployment.
+    Handover {
+        from: SocketAddr,
+        table: RoutingTable,
+    },
+    HandoverAck,
+}
+
+/// `k ≈ √n` replicas, but never all nodes.
+pub(crate) fn replica_count(nodes: usize) -> usize {
+    ((nodes as f64).sqrt().floor() as usize).min(nodes.saturating_sub(1))
+}
+
+pub(crate) struct ClusterState {
+    me: Member,
+    /// Sorted by rank. Rank 0 is master, ranks 1 to `replica_count` are replicas.
+    topology: Vec<Member>,
+    role: Role,
+    master: NodeId,
+    routing_table: RoutingTable,
+    /// Master only. Registrations not sent to replicas yet.
+    pending: RoutingTable,
+    /// Master only. Unregistrations not sent to replicas yet.
+    pending_removals: RoutingTable,
+    /// Master only. Replicas that got a snapshot of the routing table.
+    synced_replicas: HashSet<NodeId>,
+    /// Registered by this node with when they last ran, and registered again with every new
+    /// master. Unregistered past `warm_ttl`.
+    local_codes: HashMap<String, Instant>,
+    warm_ttl: Duration,
+    /// Worker only. Answers of replicas.
+    cache: HashMap<String, (Instant, Option<Vec<Ipv4Addr>>)>,
+    cache_ttl: Duration,
+    queries: HashMap<u64, (String, QueryResp)>,
+    next_query_id: u64,
+}
+
+impl ClusterState {
+    pub(crate) fn new(me: Member, cache_ttl: Duration, warm_ttl: Duration) -> Self {
+        Self {
+            topology: vec![me.clone()],
+            role: Role::Master,
+            master: me.id,
+            me,
+            routing_table: Default::default(),
+            pending: Default::default(),
+            pending_removals: Default::default(),
+            synced_replicas: Default::default(),
+            local_codes: Default::default(),
+            warm_ttl,
+            cache: Default::default(),
+            cache_ttl,
+            queries: Default::default(),
+            next_query_id: 0,
+        }
+    }
+
+    pub(crate) fn handle(&mut self, msg: ClusterMsg) -> Vec<(SocketAddr, Control)> {
+        let mut out = Vec::new();
+        match msg {
+            ClusterMsg::Heartbeat(member) => {
+                if self.topology.contains(&member) {
+                    return out;
+                }
+                // Like a former run of this node, which the others still gossip for a while.
+                if member.id != self.me.id && member.addr == self.me.addr {
+                    telemetry::cluster_error("heartbeat", "another node has this address");
+                    return out;
+                }
+                // A node restarted on the same address comes back with a new id.
+                self.topology
+                    .retain(|node| node.id != member.id && node.addr != member.addr);
+                self.topology.push(member);
+                self.topology.sort_by_key(Member::rank_key);
+                self.on_topology_change(&mut out);
+            }
+            ClusterMsg::Leave(id) => {
+                if id == self.me.id {
+                    return out;
+                }
+                let Some(index) = self.topology.iter().position(|node| node.id == id) else {
+                    return out;
+                };
+                let node = self.topology.remove(index);
+                if let Some(ip) = node.ip() {
+                    for table in [
+                        &mut self.routing_table,
+                        &mut self.pending,
+                        &mut self.pending_removals,
+                    ] {
+                        remove_addr(table, ip);
+                    }
+                    self.cache.clear();
+                }
+                self.on_topology_change(&mut out);
+            }
+            ClusterMsg::RegisterFunction { code_id } => {
+                if let Some(addr) = self.me.ip()
+                    && self
+                        .local_codes
+                        .insert(code_id.clone(), Instant::now())
+                        .is_none()
+                {
+                    self.register(code_id, addr, &mut out);
+                }
+            }
+            ClusterMsg::QueryFunction { code_id, resp } => {
+                if self.role != Role::Worker {
+                    let _ = resp.send(self.lookup(&code_id));
+                    return out;
+                }
+                if let Some((cached_at, addrs)) = self.cache.get(&code_id)
+                    && cached_at.elapsed() < self.cache_ttl
+                {
+                    let _ = resp.send(addrs.clone());
+                    return out;
+                }
+                let query_id = self.next_query_id;
+                self.next_query_id += 1;
+                out.push((
+                    self.query_target(),
+                    Control::Query {
+                        query_id,
+                        code_id: code_id.clone(),
+                        reply_to: self.me.addr,
+                    },
+                ));
+                self.queries.insert(query_id, (code_id, resp));
+            }
+            ClusterMsg::Control(control) => self.handle_control(control, &mut out),
+            ClusterMsg::Role(resp) => {
+                let _ = resp.send(self.role);
+            }
+            ClusterMsg::Flush => {
+                let warm_ttl = self.warm_ttl;
+                let cold = self
+                    .local_codes
+                    .extract_if(|_, ran_at| ran_at.elapsed() >= warm_ttl)
+                    .map(|(code_id, _)| code_id)
+                    .collect::<Vec<_>>();
+                if let Some(addr) = self.me.ip() {
+                    for code_id in cold {
+                        self.unregister(code_id, addr, &mut out);
+                    }
+                }
+                if self.role == Role::Master
+                    && (!self.pending.is_empty() || !self.pending_removals.is_empty())
+                {
+                    let table = std::mem::take(&mut self.pending);
+                    let removed = std::mem::take(&mut self.pending_removals);
+                    for replica in self.replicas() {
+                        out.push((
+                            replica.addr,
+                            Control::Updates {
+                                table: table.clone(),
+                                removed: removed.clone(),
+                            },
+                        ));
+                    }
+                }
+                // Queries that timed out.
+                self.queries.retain(|_, (_, resp)| !resp.is_closed());
+                let cache_ttl = self.cache_ttl;
+                self.cache
+                    .retain(|_, (cached_at, _)| cached_at.elapsed() < cache_ttl);
+            }
+            ClusterMsg::Stop => {}
+        }
+        out
+    }
+
+    fn handle_control(&mut self, control: Control, out: &mut Vec<(SocketAddr, Control)>) {
+        match control {
+            Control::Register { code_id, addr } => self.register(code_id, addr, out),
+            Control::Unregister { code_id, addr } => self.unregister(code_id, addr, out),
+            Control::Updates { table, removed } => {
+                merge(&mut self.routing_table, table);
+                for (code_id, addrs) in removed {
+                    for addr in addrs {
+                        remove_entry(&mut self.routing_table, &code_id, addr);
+                    }
+                }
+            }
+            Control::Snapshot { table } => self.routing_table = table,
+            Control::Query {
+                query_id,
+                code_id,
+                reply_to,
+            } => out.push((
+                reply_to,
+                Control::QueryReply {
+                    query_id,
+                    addrs: self.lookup(&code_id),
+                },
+            )),
+            Control::QueryReply { query_id, addrs } => {
+                if let Some((code_id, resp)) = self.queries.remove(&query_id) {
+                    self.cache.insert(code_id, (Instant::now(), addrs.clone()));
+                    let _ = resp.send(addrs);
+                }
+            }
+            Control::Handover { from, table } => {
+                merge(&mut self.routing_table, table);
+                if self.role == Role::Master {
+                    for replica in self.replicas() {
+                        out.push((
+                            replica.addr,
+                            Control::Snapshot {
+                                table: self.routing_table.clone(),
+                            },
+                        ));
+                    }
+                }
+                telemetry::cluster_handover("received");
+                out.push((from, Control::HandoverAck));
+            }
+            Control::HandoverAck => telemetry::cluster_handover("acked"),
+        }
+    }
+
+    fn register(&mut self, code_id: String, addr: Ipv4Addr, out: &mut Vec<(SocketAddr, Control)>) {
+        if self.role != Role::Master {
+            out.push((self.master_addr(), Control::Register { code_id, addr }));
+            return;
+        }
+        remove_entry(&mut self.pending_removals, &code_id, addr);
+        for table in [&mut self.routing_table, &mut self.pending] {
+            insert_addr(table, code_id.clone(), addr);
+        }
+    }
+
+    fn unregister(
+        &mut self,
+        code_id: String,
+        addr: Ipv4Addr,
+        out: &mut Vec<(SocketAddr, Control)>,
+    ) {
+        if self.role != Role::Master {
+            out.push((self.master_addr(), Control::Unregister { code_id, addr }));
+            return;
+        }
+        for table in [&mut self.routing_table, &mut self.pending] {
+            remove_entry(table, &code_id, addr);
+        }
+        insert_addr(&mut self.pending_removals, code_id, addr);
+    }
+
+    fn lookup(&self, code_id: &str) -> Option<Vec<Ipv4Addr>> {
+        self.routing_table.get(code_id).cloned()
+    }
+
+    fn on_topology_change(&mut self, out: &mut Vec<(SocketAddr, Control)>) {
+        let was_master = self.role == Role::Master;
+        let old_master = self.master;
+        let rank = self
+            .topology
+            .iter()
+            .position(|node| node.id == self.me.id)
+            .unwrap();
+        let role = match rank {
+            0 => Role::Master,
+            rank if rank <= replica_count(self.topology.len()) => Role::Replica,
+            _ => Role::Worker,
+        };
+        if role != self.role {
+            telemetry::cluster_role(role.as_str());
+        }
+        self.role = role;
+        self.master = self.topology[0].id;
+
+        if was_master && role != Role::Master {
+            // Hands the table over, and forwards registrations that still come here.
+            merge(&mut self.routing_table, std::mem::take(&mut self.pending));
+            self.pending_removals.clear();
+            self.synced_replicas.clear();
+            out.push((
+                self.master_addr(),
+                Control::Handover {
+                    from: self.me.addr,
+                    table: self.routing_table.clone(),
+                },
+            ));
+        }
+        if role == Role::Master {
+            let replicas = self
+                .replicas()
+                .iter()
+                .map(|node| (node.id, node.addr))
+                .collect::<Vec<_>>();
+            self.synced_replicas
+                .retain(|id| replicas.iter().any(|(replica, _)| replica == id));
+            for (id, addr) in replicas {
+                if self.synced_replicas.insert(id) {
+                    out.push((
+                        addr,
+                        Control::Snapshot {
+                            table: self.routing_table.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+        if self.master != old_master
+            && let Some(addr) = self.me.ip()
+        {
+            // In case the new master missed some, like the last batch of a failed one.
+            for code_id in self.local_codes.keys().cloned().collect::<Vec<_>>() {
+                self.register(code_id, addr, out);
+            }
+        }
+    }
+
+    fn replicas(&self) -> &[Member] {
+        let count = replica_count(self.topology.len());
+        &self.topology[1..=count]
+    }
+
+    fn master_addr(&self) -> SocketAddr {
+        self.topology[0].addr
+    }
+
+    /// A replica per worker, spread by rank. The master when there are none.
+    fn query_target(&self) -> SocketAddr {
+        let replicas = self.replicas();
+        if replicas.is_empty() {
+            return self.master_addr();
+        }
+        let rank = self
+            .topology
+            .iter()
+            .position(|node| node.id == self.me.id)
+            .unwrap();
+        replicas[rank % replicas.len()].addr
+    }
+}
+
+fn insert_addr(table: &mut RoutingTable, code_id: String, addr: Ipv4Addr) {
+    let addrs = table.entry(code_id).or_default();
+    if let Err(index) = addrs.binary_search(&addr) {
+        addrs.insert(index, addr);
+    }
+}
+
+fn remove_entry(table: &mut RoutingTable, code_id: &str, addr: Ipv4Addr) {
+    if let Some(addrs) = table.get_mut(code_id) {
+        addrs.retain(|node| *node != addr);
+        if addrs.is_empty() {
+            table.remove(code_id);
+        }
+    }
+}
+
+fn remove_addr(table: &mut RoutingTable, addr: Ipv4Addr) {
+    table.retain(|_, addrs| {
+        addrs.retain(|node| *node != addr);
+        !addrs.is_empty()
+    });
+}
+
+fn merge(table: &mut RoutingTable, other: RoutingTable) {
+    for (code_id, addrs) in other {
+        for addr in addrs {
+            insert_addr(table, code_id.clone(), addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(index: u8, generation: u64) -> Member {
+        Member {
+            id: index as u128,
+            addr: SocketAddr::from((Ipv4Addr::new(10, 0, 0, index), 7946)),
+            generation,
+            started_at: index as u64,
+        }
+    }
+
+    const WARM_TTL: Duration = Duration::from_secs(60);
+
+    fn state_with(me: u8, members: &[Member]) -> ClusterState {
+        let mut state =
+            ClusterState::new(members[me as usize - 1].clone(), Duration::ZERO, WARM_TTL);
+        for member in members {
+            state.handle(ClusterMsg::Heartbeat(member.clone()));
+        }
+        state
+    }
+
+    #[test]
+    fn test_replica_count() {
+        assert_eq!(replica_count(1), 0);
+        assert_eq!(replica_count(2), 1);
+        assert_eq!(replica_count(4), 2);
+        assert_eq!(replica_count(100), 10);
+    }
+
+    #[test]
+    fn test_roles_by_rank() {
+        let members = (1..=5).map(|index| member(index, 1)).collect::<Vec<_>>();
+        let roles = (1..=5)
+            .map(|me| state_with(me, &members).role)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            [
+                Role::Master,
+                Role::Replica,
+                Role::Replica,
+                Role::Worker,
+                Role::Worker
+            ]
+        );
+
+        // A new generation takes over, however young.
+        let mut members = members;
+        members.push(member(6, 2));
+        assert_eq!(state_with(6, &members).role, Role::Master);
+        assert_eq!(state_with(1, &members).role, Role::Replica);
+    }
+
+    #[test]
+    fn test_master_batches_to_replicas() {
+        let members = (1..=4).map(|index| member(index, 1)).collect::<Vec<_>>();
+        let mut master = state_with(1, &members);
+        let out = master.handle(ClusterMsg::Control(Control::Register {
+            code_id: "a".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 4),
+        }));
+        assert!(out.is_empty());
+
+        let out = master.handle(ClusterMsg::Flush);
+        let targets = out.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        assert_eq!(targets, [members[1].addr, members[2].addr]);
+        assert!(master.handle(ClusterMsg::Flush).is_empty());
+
+        // The node is gone, so is its entry.
+        master.handle(ClusterMsg::Leave(4));
+        assert_eq!(master.lookup("a"), None);
+    }
+
+    #[test]
+    fn test_failover_hands_over_and_registers_again() {
+        let members = (1..=3).map(|index| member(index, 1)).collect::<Vec<_>>();
+        let mut worker = state_with(3, &members);
+        let out = worker.handle(ClusterMsg::RegisterFunction {
+            code_id: "a".to_string(),
+        });
+        assert!(matches!(out[..], [(addr, Control::Register { .. })] if addr == members[0].addr));
+
+        let out = worker.handle(ClusterMsg::Leave(1));
+        assert!(matches!(out[..], [(addr, Control::Register { .. })] if addr == members[1].addr));
+
+        let mut master = state_with(1, &members);
+        let out = master.handle(ClusterMsg::Heartbeat(member(4, 2)));
+        assert_eq!(master.role, Role::Replica);
+        assert!(out.iter().any(|(addr, control)| *addr == member(4, 2).addr
+            && matches!(control, Control::Handover { .. })));
+    }
+
+    #[test]
+    fn test_cold_codes_are_unregistered() {
+        let members = (1..=4).map(|index| member(index, 1)).collect::<Vec<_>>();
+        let mut worker = state_with(4, &members);
+        worker.warm_ttl = Duration::ZERO;
+        worker.handle(ClusterMsg::RegisterFunction {
+            code_id: "a".to_string(),
+        });
+        let out = worker.handle(ClusterMsg::Flush);
+        assert!(matches!(out[..], [(addr, Control::Unregister { .. })] if addr == members[0].addr));
+        assert!(worker.local_codes.is_empty());
+
+        let mut master = state_with(1, &members);
+        let mut replica = state_with(2, &members);
+        for control in [
+            Control::Register {
+                code_id: "a".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 4),
+            },
+            Control::Register {
+                code_id: "a".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 3),
+            },
+        ] {
+            master.handle(ClusterMsg::Control(control));
+        }
+        for (addr, control) in master.handle(ClusterMsg::Flush) {
+            if addr == members[1].addr {
+                replica.handle(ClusterMsg::Control(control));
+            }
+        }
+        assert_eq!(replica.lookup("a").unwrap().len(), 2);
+
+        master.handle(ClusterMsg::Control(Control::Unregister {
+            code_id: "a".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 4),
+        }));
+        assert_eq!(master.lookup("a"), Some(vec![Ipv4Addr::new(10, 0, 0, 3)]));
+        for (addr, control) in master.handle(ClusterMsg::Flush) {
+            if addr == members[1].addr {
+                replica.handle(ClusterMsg::Control(control));
+            }
+        }
+        assert_eq!(replica.lookup("a"), Some(vec![Ipv4Addr::new(10, 0, 0, 3)]));
+    }
+
+    #[test]
+    fn test_heartbeat_with_my_address() {
+        let members = (1..=2).map(|index| member(index, 1)).collect::<Vec<_>>();
+        let mut state = state_with(2, &members);
+        let mut other = member(2, 0);
+        other.id = 3;
+        assert!(state.handle(ClusterMsg::Heartbeat(other)).is_empty());
+        assert_eq!(state.topology, members);
+        assert_eq!(state.role, Role::Replica);
+    }
+}
@@ -0,0 +1,190 @@
+//! Per-code settings and secrets.
+//!
+//! Wasm gets them through wasi:config and, if asked, as environment variables.
+//! JS handlers get them as `env`. Secret values are never logged or put in telemetry.
+
+use anyhow::{Result, anyhow};
+use doc_db::{DocDb, SecretKey};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+use wasmtime_wasi_config::WasiConfigVariables;
+
+#[derive(Clone, Debug, Default)]
+pub struct CodeConfig {
+    pub vars: BTreeMap<String, String>,
+    /// Config key to the name of a secret of the code in doc-db. Wins over `vars`.
+    pub secrets: BTreeMap<String, String>,
+    /// Also set the entries as WASI environment variables.
+    pub expose_as_env: bool,
+}
+
+/// Where the secrets referred by `CodeConfig::secrets` are.
+#[derive(Clone)]
+pub struct SecretStore {
+    pub db: DocDb,
+    pub key: SecretKey,
+}
+
+/// `CodeConfig` with secrets resolved.
+#[derive(Default)]
+pub(crate) struct CodeEnv {
+    pub entries: Vec<(String, String)>,
+    pub wasi_config: WasiConfigVariables,
+    pub expose_as_env: bool,
+}
+
+impl CodeEnv {
+    pub(crate) fn to_map(&self) -> HashMap<String, String> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+struct ResolvedEnv {
+    code_version: u64,
+    config: Arc<CodeConfig>,
+    env: Arc<CodeEnv>,
+}
+
+/// Resolved envs, kept until the code's version or `CodeConfig` changes, or `invalidate`,
+/// so rotated secrets are read again with the next update of the code.
+#[derive(Default)]
+pub(crate) struct CodeEnvCache {
+    envs: Mutex<HashMap<String, ResolvedEnv>>,
+}
+
+impl CodeEnvCache {
+    pub(crate) fn len(&self) -> usize {
+        self.envs.lock().unwrap().len()
+    }
+
+    pub(crate) fn invalidate(&self, code_id: &str) {
+        self.envs.lock().unwrap().remove(code_id);
+    }
+
+    pub(crate) async fn get(
+        &self,
+        code_id: &str,
+        code_version: u64,
+        config: &Arc<CodeConfig>,
+        secret_store: Option<&SecretStore>,
+    ) -> Result<Arc<CodeEnv>> {
+        if let Some(resolved) = self.envs.lock().unwrap().get(code_id)
+            && resolved.code_version == code_version
+            && Arc::ptr_eq(&resolved.config, config)
+        {
+            return Ok(resolved.env.clone());
+        }
+
+        let mut entries = config.vars.clone();
+        if !config.secrets.is_empty() {
+            let secret_store = secret_store
+                .ok_or_else(|| anyhow!("code {code_id} has secrets but no secret store is set"))?;
+            for (key, name) in &config.secrets {
+                let secret = secret_store
+                    .db
+                    .get_secret(&secret_store.key, code_id, name)
+                    .await?
+                    .ok_or_else(|| anyhow!("secret {name} of code {code_id} not found"))?;
+                entries.insert(key.clone(), secret);
+            }
+        }
+
+        let env = Arc::new(CodeEnv {
+            wasi_config: entries
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            entries: entries.into_iter().collect(),
+            expose_as_env: config.expose_as_env,
+        });
+        self.envs.lock().unwrap().insert(
+            code_id.to_string(),
+            ResolvedEnv {
+                code_version,
+                config: config.clone(),
+                env: env.clone(),
+            },
+        );
+        Ok(env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_secrets() {
+        let path = std::env::temp_dir().join(format!("fn0-code-config-{}.db", std::process::id()));
+        let secret_store = SecretStore {
+            db: DocDb::new_local(&path).await.unwrap(),
+            key: SecretKey::new([7; 32]),
+        };
+        secret_store
+            .db
+            .set_secret(&secret_store.key, "a", "token", "s3cret")
+            .await
+            .unwrap();
+
+        let config = Arc::new(CodeConfig {
+            vars: BTreeMap::from([
+                ("MODE".to_string(), "prod".to_string()),
+                ("TOKEN".to_string(), "plain".to_string()),
+            ]),
+            secrets: BTreeMap::from([("TOKEN".to_string(), "token".to_string())]),
+            expose_as_env: true,
+        });
+        let cache = CodeEnvCache::default();
+        let env = cache
+            .get("a", 0, &config, Some(&secret_store))
+            .await
+            .unwrap();
+        assert_eq!(
+            env.entries,
+            vec![
+                ("MODE".to_string(), "prod".to_string()),
+                ("TOKEN".to_string(), "s3cret".to_string()),
+            ]
+        );
+        assert!(Arc::ptr_eq(
+            &env,
+            &cache.get("a", 0, &config, None).await.unwrap()
+        ));
+
+        // Secrets belong to a code.
+        assert!(
+            cache
+                .get("b", 0, &config, Some(&secret_store))
+                .await
+                .is_err()
+        );
+        assert!(cache.get("b", 0, &config, None).await.is_err());
+
+        // A rotated secret is read again with the next version, or once invalidated.
+        secret_store
+            .db
+            .set_secret(&secret_store.key, "a", "token", "rotated")
+            .await
+            .unwrap();
+        let token = async |code_version| {
+            let env = cache
+                .get("a", code_version, &config, Some(&secret_store))
+                .await
+                .unwrap();
+            env.to_map()["TOKEN"].clone()
+        };
+        assert_eq!(token(0).await, "s3cret");
+        assert_eq!(token(1).await, "rotated");
+        secret_store
+            .db
+            .set_secret(&secret_store.key, "a", "token", "again")
+            .await
+            .unwrap();
+        cache.invalidate("a");
+        assert_eq!(token(1).await, "again");
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use crate::CodeConfig;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 type CodeId = String;
 type DeploymentId = String;
@@ -8,10 +9,78 @@ pub struct Deployment {
     pub codes: HashMap<CodeId, CodeManifest>,
 }
 
+#[derive(Clone)]
 pub struct CodeManifest {
     pub kind: CodeKind,
     /// Codes can communicate with each other using this ID like internal://<code_id>
     pub code_id: CodeId,
+    pub code_version: u64,
+    pub limits: CodeLimits,
+    pub config: Arc<CodeConfig>,
+}
+
+/// Per-invocation limits. Defaults suit self-hosting, see `cloud` for the fn0 Cloud limits.
+#[derive(Clone, Copy, Debug)]
+pub struct CodeLimits {
+    /// Linear memory per instance. Can't exceed the executor's `max_memory_bytes`.
+    pub memory_bytes: usize,
+    pub cpu_time: Duration,
+    /// CPU time of instantiation, including start functions. Counted apart from `cpu_time`.
+    pub init_cpu_time: Duration,
+    /// Wall-clock time from the start of the invocation.
+    pub duration: Duration,
+    /// Outgoing requests per invocation.
+    pub subrequests: usize,
+    /// Bytes of stdout and stderr kept per invocation. The rest is dropped.
+    pub log_bytes: usize,
+    /// Invocations of the code running at once on a host. More wait in the admission queue.
+    pub concurrency: usize,
+    pub keyvalue: KeyValueLimits,
+}
+
+impl Default for CodeLimits {
+    fn default() -> Self {
+        Self {
+            memory_bytes: 128 * 1024 * 1024,
+            cpu_time: Duration::from_millis(1000),
+            init_cpu_time: Duration::from_millis(100),
+            duration: Duration::from_secs(15),
+            subrequests: 50,
+            log_bytes: 256 * 1024,
+            concurrency: 128,
+            keyvalue: Default::default(),
+        }
+    }
+}
+
+impl CodeLimits {
+    /// The fn0 Cloud limits in README, tighter on CPU time.
+    pub fn cloud() -> Self {
+        Self {
+            cpu_time: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+}
+
+/// wasi:keyvalue quotas of a code.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyValueLimits {
+    /// Also bounds bucket identifiers.
+    pub key_bytes: usize,
+    pub value_bytes: usize,
+    /// Per invocation. Batch calls count each key.
+    pub operations: usize,
+}
+
+impl Default for KeyValueLimits {
+    fn default() -> Self {
+        Self {
+            key_bytes: 512,
+            value_bytes: 1024 * 1024,
+            operations: 1000,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -24,6 +93,8 @@ pub enum CodeKind {
 pub struct DeploymentMap {
     code_id_deployment_id_map: HashMap<CodeId, DeploymentId>,
     code_manifest_map: HashMap<CodeId, CodeManifest>,
+    /// Number of hq deployment records applied so far.
+    deployment_id: u64,
 }
 
 impl DeploymentMap {
@@ -31,19 +102,77 @@ impl DeploymentMap {
         Self {
             code_id_deployment_id_map: Default::default(),
             code_manifest_map: Default::default(),
+            deployment_id: 0,
         }
     }
 
     pub fn register_code(&mut self, code_id: &str, kind: CodeKind) {
+        self.register_code_manifest(CodeManifest {
+            kind,
+            code_id: code_id.to_string(),
+            code_version: 0,
+            limits: Default::default(),
+            config: Default::default(),
+        });
+    }
+
+    /// Only codes of the same deployment can call each other through internal:// URLs.
+    pub fn register_deployment(&mut self, deployment: Deployment) {
+        for (code_id, manifest) in deployment.codes {
+            self.code_id_deployment_id_map
+                .insert(code_id.clone(), deployment.id.clone());
+            self.code_manifest_map.insert(code_id, manifest);
+        }
+    }
+
+    /// Keeps the deployment of a known code. Otherwise the code is a deployment of its own,
+    /// like codes from hq, which deploys codes one by one.
+    pub fn register_code_manifest(&mut self, manifest: CodeManifest) {
         self.code_id_deployment_id_map
-            .insert(code_id.to_string(), "default".to_string());
-        self.code_manifest_map.insert(
-            code_id.to_string(),
-            CodeManifest {
-                kind,
-                code_id: code_id.to_string(),
-            },
-        );
+            .entry(manifest.code_id.clone())
+            .or_insert_with(|| manifest.code_id.clone());
+        self.code_manifest_map
+            .insert(manifest.code_id.clone(), manifest);
+    }
+
+    pub fn deployment_id(&self) -> u64 {
+        self.deployment_id
+    }
+
+    /// Applies hq's `DeploymentUpdates`, which lists every record after `deployment_id`.
+    /// Records this map already has are skipped, so a resent update is harmless.
+    /// Returns false if there is a gap between our state and the update.
+    pub fn apply_deployment_updates(
+        &mut self,
+        deployment_id: u64,
+        code_id_and_versions: &[(u64, u64)],
+    ) -> bool {
+        if deployment_id > self.deployment_id {
+            return false;
+        }
+        let already_applied = (self.deployment_id - deployment_id) as usize;
+
+        for (code_id, code_version) in code_id_and_versions.iter().skip(already_applied) {
+            let code_id = code_id.to_string();
+            // hq doesn't tell the kind and limits of code, so keep the known ones or use defaults.
+            let manifest = match self.code_manifest_map.get(&code_id) {
+                Some(manifest) => CodeManifest {
+                    code_version: *code_version,
+                    ..manifest.clone()
+                },
+                None => CodeManifest {
+                    kind: CodeKind::Wasm,
+                    code_id,
+                    code_version: *code_version,
+                    limits: Default::default(),
+                    config: Default::default(),
+                },
+            };
+            self.register_code_manifest(manifest);
+            self.deployment_id += 1;
+        }
+
+        true
     }
 
     pub fn is_code_in_same_deployment(
@@ -62,4 +191,49 @@ impl DeploymentMap {
             .get(code_id)
             .map(|manifest| manifest.kind)
     }
+
+    pub fn code_manifest(&self, code_id: &str) -> Option<&CodeManifest> {
+        self.code_manifest_map.get(code_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(code_id: &str) -> CodeManifest {
+        CodeManifest {
+            kind: CodeKind::Wasm,
+            code_id: code_id.to_string(),
+            code_version: 0,
+            limits: Default::default(),
+            config: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_deployment_isolation() {
+        let mut map = DeploymentMap::new();
+        map.register_deployment(Deployment {
+            id: "shop".to_string(),
+            codes: ["frontend", "backend"]
+                .into_iter()
+                .map(|code_id| (code_id.to_string(), manifest(code_id)))
+                .collect(),
+        });
+        map.register_code("other", CodeKind::Wasm);
+        // Codes from hq are deployments of their own, and updates keep the known deployment.
+        assert!(map.apply_deployment_updates(0, &[(7, 1), (8, 1)]));
+        map.register_code_manifest(CodeManifest {
+            code_version: 2,
+            ..manifest("backend")
+        });
+
+        let is_same = |a: &str, b: &str| map.is_code_in_same_deployment(&a.into(), &b.into());
+        assert_eq!(is_same("frontend", "backend"), Some(true));
+        assert_eq!(is_same("frontend", "other"), Some(false));
+        assert_eq!(is_same("7", "8"), Some(false));
+        assert_eq!(is_same("7", "7"), Some(true));
+        assert_eq!(is_same("frontend", "unknown"), None);
+    }
 }
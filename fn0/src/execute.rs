@@ -1,77 +1,265 @@
-use crate::{Body, Request, Response, telemetry};
+use crate::{
+    Body, CodeLimits, OutgoingHttpPolicy, Request, Response,
+    admission::AdmissionPermit,
+    artifact,
+    cgi::{self, CgiState},
+    code_config::CodeEnv,
+    fn0_error::{ErrorKind, ErrorReporter},
+    guest_log::{GuestLog, GuestLogStream},
+    keyvalue::{self, KeyValue, KeyValueCtx},
+    memory_usage::MemoryUsage,
+    outgoing_http,
+    scheduled::{self, ScheduledEvent},
+    service_binding::{INTERNAL_SCHEME, Invocation, ServiceBinding, ServiceBindingError},
+    telemetry,
+    tiering::{CompilerTier, Hotness, TierUpThreshold, TieredWasmPre},
+    trace_context,
+};
 use adapt_cache::AdaptCache;
 use anyhow::{Result, anyhow};
 use bytes::Bytes;
+use doc_db::DocDb;
 use http_body_util::BodyExt;
 use measure_cpu_time::{Clock, TimeTracker, measure_cpu_time};
+use serde::Serialize;
 use std::{
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc::Sender, oneshot, watch},
+    task::{JoinHandle, JoinSet},
 };
-use tokio::sync::{mpsc::Sender, oneshot};
 use wasmtime::{
-    Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig, Store,
+    Config, Engine, InstanceAllocationStrategy, InstancePre, Module, OptLevel,
+    PoolingAllocationConfig, Precompiled, ResourceLimiter, Store, Strategy, WasmBacktraceDetails,
     component::{Component, Linker},
 };
 use wasmtime_wasi::*;
+use wasmtime_wasi_config::WasiConfig;
 use wasmtime_wasi_http::{
-    WasiHttpCtx, WasiHttpView,
+    HttpResult, WasiHttpCtx, WasiHttpView,
     bindings::{
-        ProxyPre,
+        Proxy, ProxyPre,
         http::types::{ErrorCode, Scheme},
     },
+    body::HyperOutgoingBody,
+    types::{HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig},
 };
 
+const EPOCH_TICK: Duration = Duration::from_millis(3);
+
 pub struct Job {
-    pub req: Request,
+    pub(crate) call: Call,
     pub res_tx: oneshot::Sender<Response>,
+}
+
+/// A request to a code, with what it runs under.
+pub(crate) struct Call {
+    pub req: Request,
     pub code_id: String,
+    pub limits: CodeLimits,
+    pub env: Arc<CodeEnv>,
+    pub invocation: Invocation,
+    pub permit: AdmissionPermit,
+    pub errors: ErrorReporter,
+}
+
+/// Components serve wasi:http. Core modules run CGI-style, see `cgi`.
+pub enum WasmPre<C: Clock> {
+    Proxy(ProxyPre<ClientState<C>>),
+    Cgi(InstancePre<CgiState<C>>),
+}
+
+impl<C: Clock> Clone for WasmPre<C> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Proxy(pre) => Self::Proxy(pre.clone()),
+            Self::Cgi(pre) => Self::Cgi(pre.clone()),
+        }
+    }
+}
+
+struct Linkers<C: Clock> {
+    proxy: Linker<ClientState<C>>,
+    cgi: wasmtime::Linker<CgiState<C>>,
+}
+
+/// Engine and linkers of one compiler tier. Artifacts only load into the engine they were built for.
+struct Tier<C: Clock> {
+    engine: Engine,
+    linkers: Linkers<C>,
+}
+
+impl<C: Clock> Tier<C> {
+    fn new(tier: CompilerTier, max_memory_bytes: usize) -> Self {
+        let engine = Engine::new(&engine_config(max_memory_bytes, tier)).unwrap();
+
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_async(&mut linker).unwrap();
+        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
+        wasmtime_wasi_config::add_to_linker(&mut linker, |state: &mut ClientState<C>| {
+            WasiConfig::new(&state.env.wasi_config)
+        })
+        .unwrap();
+        keyvalue::add_to_linker(&mut linker, |state: &mut ClientState<C>| {
+            KeyValue::new(&mut state.keyvalue, &mut state.table)
+        })
+        .unwrap();
+        let linkers = Linkers {
+            proxy: linker,
+            cgi: cgi::linker(&engine),
+        };
+        Self { engine, linkers }
+    }
+
+    fn deserialize(&self, precompiled: &[u8]) -> Result<WasmPre<C>> {
+        match Engine::detect_precompiled(precompiled) {
+            Some(Precompiled::Module) => {
+                let module = unsafe { Module::deserialize(&self.engine, precompiled)? };
+                self.module_pre(&module)
+            }
+            _ => {
+                let component = unsafe { Component::deserialize(&self.engine, precompiled)? };
+                self.component_pre(&component)
+            }
+        }
+    }
+
+    fn compile(&self, source: &[u8]) -> Result<WasmPre<C>> {
+        if crate::is_component(source) {
+            self.component_pre(&Component::new(&self.engine, source)?)
+        } else {
+            self.module_pre(&Module::new(&self.engine, source)?)
+        }
+    }
+
+    fn component_pre(&self, component: &Component) -> Result<WasmPre<C>> {
+        let instance_pre = self.linkers.proxy.instantiate_pre(component)?;
+        Ok(WasmPre::Proxy(ProxyPre::new(instance_pre)?))
+    }
+
+    fn module_pre(&self, module: &Module) -> Result<WasmPre<C>> {
+        Ok(WasmPre::Cgi(self.linkers.cgi.instantiate_pre(module)?))
+    }
+}
+
+struct Tiers<C: Clock> {
+    baseline: Tier<C>,
+    optimized: Tier<C>,
+}
+
+/// Lookups of the wasm proxy cache since start, shown by the health route.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ProxyCacheStats {
+    pub hits: u64,
+    /// Lookups that deserialized the code, as it wasn't cached.
+    pub loads: u64,
+    pub errors: u64,
+}
+
+#[derive(Default)]
+struct ProxyCacheCounters {
+    hits: AtomicU64,
+    loads: AtomicU64,
+    errors: AtomicU64,
 }
 
 pub struct WasmExecutor {
     job_tx: Sender<Job>,
+    instances: Arc<AtomicU64>,
+    proxy_cache_counters: Arc<ProxyCacheCounters>,
+    shutdown_tx: watch::Sender<bool>,
+    job_loop: Mutex<Option<JoinHandle<()>>>,
+    debug_errors: bool,
+}
+
+/// What `WasmExecutor::new` needs besides the cache and the clock.
+pub(crate) struct ExecutorConfig {
+    pub max_memory_bytes: usize,
+    pub outgoing_http: Arc<OutgoingHttpPolicy>,
+    pub service_binding: Weak<dyn ServiceBinding>,
+    pub keyvalue: Option<DocDb>,
+    pub tier_up_threshold: TierUpThreshold,
+    pub debug_errors: bool,
+}
+
+/// What every job of an executor shares.
+struct JobContext<A, C: Clock> {
+    proxy_cache: A,
+    proxy_cache_counters: Arc<ProxyCacheCounters>,
+    tiers: Arc<Tiers<C>>,
+    tier_up_threshold: TierUpThreshold,
+    host: HostContext<C>,
+}
+
+/// What guests reach through the host.
+struct HostContext<C: Clock> {
+    clock: C,
+    outgoing_http: Arc<OutgoingHttpPolicy>,
+    service_binding: Weak<dyn ServiceBinding>,
+    keyvalue: Option<DocDb>,
 }
 
 impl WasmExecutor {
-    pub fn new<A, C>(proxy_cache: A, clock: C) -> Self
+    pub(crate) fn new<A, C>(proxy_cache: A, clock: C, config: ExecutorConfig) -> Self
     where
-        A: AdaptCache<ProxyPre<ClientState<C>>, wasmtime::Error>,
+        A: AdaptCache<TieredWasmPre<C>, wasmtime::Error>,
         C: Clock,
     {
         let (job_tx, mut job_rx) = tokio::sync::mpsc::channel(10 * 1024);
-        let engine = Engine::new(&engine_config()).unwrap();
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let instances = Arc::new(AtomicU64::new(0));
+        let proxy_cache_counters = Arc::new(ProxyCacheCounters::default());
+        let tiers = Arc::new(Tiers {
+            baseline: Tier::new(CompilerTier::Baseline, config.max_memory_bytes),
+            optimized: Tier::new(CompilerTier::Optimized, config.max_memory_bytes),
+        });
 
-        let mut linker = Linker::new(&engine);
-        wasmtime_wasi::p2::add_to_linker_async(&mut linker).unwrap();
-        wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).unwrap();
+        spawn_epoch_ticker(Arc::downgrade(&tiers));
+
+        let context = Arc::new(JobContext {
+            proxy_cache,
+            proxy_cache_counters: proxy_cache_counters.clone(),
+            tiers,
+            tier_up_threshold: config.tier_up_threshold,
+            host: HostContext {
+                clock,
+                outgoing_http: config.outgoing_http,
+                service_binding: config.service_binding,
+                keyvalue: config.keyvalue,
+            },
+        });
 
-        tokio::spawn({
-            let proxy_cache = proxy_cache.clone();
-            let engine = engine.clone();
-            let linker = linker.clone();
-            let clock = clock.clone();
+        let job_loop = tokio::spawn({
+            let instances = instances.clone();
 
             async move {
-                let mut interval = tokio::time::interval(Duration::from_millis(3));
+                let mut jobs = JoinSet::new();
                 loop {
                     tokio::select! {
-                        _ = interval.tick() => {
-                            engine.increment_epoch();
+                        Some(_) = jobs.join_next(), if !jobs.is_empty() => {}
+
+                        _ = shutdown_rx.wait_for(|is_shutdown| *is_shutdown) => {
+                            // Callers of dropped jobs get an error as `res_tx` is dropped.
+                            job_rx.close();
+                            jobs.abort_all();
+                            break;
                         }
 
                         res = job_rx.recv() => {
                             match res {
                                 Some(job) => {
-                                    let proxy_cache = proxy_cache.clone();
-                                    let engine = engine.clone();
-                                    let linker = linker.clone();
-                                    let clock = clock.clone();
+                                    let context = context.clone();
+                                    // Dropped with the job, even when it's aborted.
+                                    let instance = InstanceCount::new(instances.clone());
 
-                                    tokio::spawn(async move {
-                                        run_job(job, proxy_cache, engine, linker, clock).await;
+                                    jobs.spawn(async move {
+                                        run_job(job, context).await;
+                                        drop(instance);
                                     });
                                 },
                                 None => break,
@@ -82,15 +270,66 @@ impl WasmExecutor {
             }
         });
 
-        Self { job_tx }
+        Self {
+            job_tx,
+            instances,
+            proxy_cache_counters,
+            shutdown_tx,
+            job_loop: Mutex::new(Some(job_loop)),
+            debug_errors: config.debug_errors,
+        }
+    }
+
+    /// Aborts the jobs still running.
+    /// Callers should wait for in-flight jobs first, as aborted ones get no response.
+    pub(crate) async fn shutdown(&self) {
+        self.shutdown_tx.send_replace(true);
+        let job_loop = self.job_loop.lock().unwrap().take();
+        if let Some(job_loop) = job_loop {
+            let _ = job_loop.await;
+        }
+    }
+
+    /// Number of jobs currently holding a wasm instance.
+    pub(crate) fn instances(&self) -> u64 {
+        self.instances.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn proxy_cache_stats(&self) -> ProxyCacheStats {
+        let counters = &self.proxy_cache_counters;
+        ProxyCacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            loads: counters.loads.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+        }
     }
 
-    pub(crate) async fn run(&self, code_id: &str, request: Request) -> Result<Response> {
+    pub(crate) async fn run(
+        &self,
+        code_id: &str,
+        limits: CodeLimits,
+        env: Arc<CodeEnv>,
+        request: Request,
+        invocation: Invocation,
+        permit: AdmissionPermit,
+    ) -> Result<Response> {
         let (res_tx, res_rx) = oneshot::channel();
+        let errors = ErrorReporter::new(
+            code_id.to_string(),
+            invocation.request_id().clone(),
+            self.debug_errors,
+        );
         let job = Job {
-            req: request,
+            call: Call {
+                req: request,
+                code_id: code_id.to_string(),
+                limits,
+                env,
+                invocation,
+                permit,
+                errors,
+            },
             res_tx,
-            code_id: code_id.to_string(),
         };
 
         self.job_tx
@@ -102,20 +341,39 @@ impl WasmExecutor {
     }
 }
 
-pub fn engine_config() -> Config {
-    const MB: usize = 1024 * 1024;
+/// Ticks the epochs on its own thread, as guests busy on every tokio worker would starve a task.
+/// Stops once the tiers are dropped, after the last job using them.
+fn spawn_epoch_ticker<C: Clock>(tiers: Weak<Tiers<C>>) {
+    std::thread::Builder::new()
+        .name("fn0-epoch-ticker".to_string())
+        .spawn(move || {
+            while let Some(tiers) = tiers.upgrade() {
+                tiers.baseline.engine.increment_epoch();
+                tiers.optimized.engine.increment_epoch();
+                drop(tiers);
+                std::thread::sleep(EPOCH_TICK);
+            }
+        })
+        .unwrap();
+}
 
+/// Instances the pooling allocator of an engine can hold.
+pub(crate) fn max_instance_count(max_memory_bytes: usize) -> usize {
     let mut sys = sysinfo::System::new_all();
     sys.refresh_all();
 
     let total_memory_bytes = sys.total_memory() as usize;
-    let total_memory_mb = total_memory_bytes / (1024 * 1024);
-    const MAX_MEMORY_MB: usize = 128;
-    let max_instance_count = total_memory_mb / MAX_MEMORY_MB;
+    total_memory_bytes / max_memory_bytes
+}
+
+pub fn engine_config(max_memory_bytes: usize, tier: CompilerTier) -> Config {
+    const MB: usize = 1024 * 1024;
+
+    let max_instance_count = max_instance_count(max_memory_bytes);
 
     let mut pooling_allocation_config = PoolingAllocationConfig::new();
     pooling_allocation_config
-        .max_memory_size(MB * MAX_MEMORY_MB)
+        .max_memory_size(max_memory_bytes)
         .linear_memory_keep_resident(MB * 16)
         .table_keep_resident(MB)
         .total_core_instances(max_instance_count as _)
@@ -132,102 +390,245 @@ pub fn engine_config() -> Config {
             pooling_allocation_config,
         ))
         .epoch_interruption(true)
+        // Backtraces in error details get file and line numbers from DWARF, if codes have it.
+        .wasm_backtrace_details(WasmBacktraceDetails::Enable)
         .wasm_component_model(true)
-        .cranelift_opt_level(wasmtime::OptLevel::None)
         .cache(Some(
             wasmtime::Cache::new(wasmtime::CacheConfig::new()).unwrap(),
         ))
         .parallel_compilation(true);
 
+    match tier {
+        CompilerTier::Baseline => {
+            config.strategy(Strategy::Winch);
+        }
+        CompilerTier::Optimized => {
+            config
+                .strategy(Strategy::Cranelift)
+                .cranelift_opt_level(OptLevel::Speed);
+        }
+    }
+
     config
 }
 
-async fn run_job<A, C>(
-    job: Job,
-    proxy_cache: A,
-    engine: Engine,
-    linker: Linker<ClientState<C>>,
-    clock: C,
-) where
-    A: AdaptCache<ProxyPre<ClientState<C>>, wasmtime::Error>,
+async fn run_job<A, C>(job: Job, context: Arc<JobContext<A, C>>)
+where
+    A: AdaptCache<TieredWasmPre<C>, wasmtime::Error>,
     C: Clock,
 {
-    let Ok(proxy_pre) = get_proxy_pre(job.code_id.clone(), proxy_cache, engine, linker).await
-    else {
-        let _ = job.res_tx.send(internal_error_response());
-        return;
+    let tiered = match get_wasm_pre(
+        job.call.code_id.clone(),
+        context.proxy_cache.clone(),
+        &context.proxy_cache_counters,
+        context.tiers.clone(),
+    )
+    .await
+    {
+        Ok(tiered) => tiered,
+        Err(error) => {
+            let response = job
+                .call
+                .errors
+                .response(ErrorKind::CodeLoadFailed, Some(&error));
+            let _ = job.res_tx.send(response);
+            return;
+        }
     };
 
-    let response = handle_request(proxy_pre, job.req, job.code_id, clock).await;
+    let hotness = tiered.hotness();
+    hotness.record_invocation();
+    if let Some(source) = tiered.start_tier_up(&context.tier_up_threshold) {
+        let code_id = job.call.code_id.clone();
+        let tiered = tiered.clone();
+        let tiers = context.tiers.clone();
+        tokio::task::spawn_blocking(move || tier_up(code_id, tiered, tiers, source));
+    }
+
+    let (response, guest_task) = match tiered.current() {
+        WasmPre::Proxy(proxy_pre) => {
+            handle_request(proxy_pre, job.call, hotness, &context.host).await
+        }
+        WasmPre::Cgi(instance_pre) => {
+            let response =
+                cgi::handle_request(instance_pre, job.call, context.host.clock.clone(), hotness)
+                    .await;
+            (response, None)
+        }
+    };
 
     let _ = job.res_tx.send(response);
+    // Counted in `instances` until the guest is done, and `context` keeps the epoch ticker for it.
+    if let Some(mut guest_task) = guest_task {
+        let _ = (&mut guest_task.0).await;
+    }
+    drop(context);
+}
+
+/// Counts a job in `WasmExecutor::instances` while alive.
+struct InstanceCount(Arc<AtomicU64>);
+
+impl InstanceCount {
+    fn new(instances: Arc<AtomicU64>) -> Self {
+        instances.fetch_add(1, Ordering::Relaxed);
+        Self(instances)
+    }
 }
 
-async fn get_proxy_pre<A, C>(
+impl Drop for InstanceCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn get_wasm_pre<A, C>(
     code_id: String,
     proxy_cache: A,
-    engine: Engine,
-    linker: Linker<ClientState<C>>,
-) -> Result<ProxyPre<ClientState<C>>, ()>
+    counters: &ProxyCacheCounters,
+    tiers: Arc<Tiers<C>>,
+) -> Result<TieredWasmPre<C>, String>
 where
-    A: AdaptCache<ProxyPre<ClientState<C>>, wasmtime::Error>,
+    A: AdaptCache<TieredWasmPre<C>, wasmtime::Error>,
     C: Clock,
 {
+    let mut loaded = false;
     match proxy_cache
         .get(&code_id.clone(), |bytes| {
-            let component = unsafe { Component::deserialize(&engine, &bytes)? };
-            let instance_pre = linker.instantiate_pre(&component)?;
-            let proxy_pre = ProxyPre::new(instance_pre)?;
+            loaded = true;
+            let artifact = artifact::decode(&bytes)?;
+            let tier = match artifact.source {
+                Some(_) => &tiers.baseline,
+                None => &tiers.optimized,
+            };
+            let wasm_pre = tier.deserialize(artifact.precompiled)?;
+            // Copied, so tier-up frees it without keeping the whole artifact alive.
+            let source = artifact.source.map(Bytes::copy_from_slice);
+            // Fixed at load, so it still counts the source after tier-up drops it.
+            let weight = artifact.precompiled.len() + source.as_ref().map_or(0, Bytes::len);
 
-            telemetry::create_instance(&code_id);
-            Ok((proxy_pre, bytes.len()))
+            telemetry::create_instance(&code_id, artifact.header.pre_initialized);
+            Ok((TieredWasmPre::new(wasm_pre, source), weight))
         })
         .await
     {
-        Ok(proxy_pre) => Ok(proxy_pre),
+        Ok(wasm_pre) => {
+            let counter = if loaded {
+                &counters.loads
+            } else {
+                &counters.hits
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(wasm_pre)
+        }
+        Err(error) => {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+            let error = format!("{error:?}");
+            telemetry::proxy_cache_error(&code_id, &error);
+            Err(error)
+        }
+    }
+}
+
+/// Runs on a blocking thread. On failure the code stays on the baseline tier.
+fn tier_up<C: Clock>(
+    code_id: String,
+    tiered: TieredWasmPre<C>,
+    tiers: Arc<Tiers<C>>,
+    source: Bytes,
+) {
+    let started_at = Instant::now();
+    match tiers.optimized.compile(&source) {
+        Ok(wasm_pre) => {
+            tiered.finish_tier_up(wasm_pre);
+            telemetry::tier_up(&code_id, started_at.elapsed());
+        }
         Err(error) => {
-            telemetry::proxy_cache_error(&code_id, &format!("{error:?}"));
-            Err(())
+            telemetry::tier_up_failed(&code_id, &format!("{error:?}"));
         }
     }
 }
 
 async fn handle_request<C>(
     pre: ProxyPre<ClientState<C>>,
-    req: Request,
-    code_id: String,
-    clock: C,
-) -> Response
+    call: Call,
+    hotness: Arc<Hotness>,
+    host: &HostContext<C>,
+) -> (Response, Option<GuestTask>)
 where
     C: Clock + Send + 'static,
 {
-    let time_tracker = TimeTracker::new(clock);
+    let Call {
+        req,
+        code_id,
+        limits,
+        env,
+        invocation,
+        permit,
+        errors,
+    } = call;
+    let started_at = tokio::time::Instant::now();
+    let scheduled = req.extensions().get::<ScheduledEvent>().cloned();
+    let init_time_tracker = TimeTracker::new(host.clock.clone());
+    let time_tracker = TimeTracker::new(host.clock.clone());
     let is_timeout = Arc::new(AtomicBool::new(false));
+    let is_duration_timeout = Arc::new(AtomicBool::new(false));
+    let guest_log = GuestLog::new(&code_id, invocation.request_id().clone(), limits.log_bytes);
+    let span = invocation.span().clone();
+    let memory = MemoryUsage::new(code_id.clone(), span.clone(), limits.memory_bytes);
+    let is_memory_denied = memory.is_denied();
+    let mut wasi = WasiCtx::builder();
+    wasi.stdout(guest_log.output(GuestLogStream::Stdout))
+        .stderr(guest_log.output(GuestLogStream::Stderr));
+    if env.expose_as_env {
+        wasi.envs(&env.entries);
+    }
 
     let mut store = Store::new(
         pre.engine(),
         ClientState {
             table: ResourceTable::new(),
-            wasi: WasiCtx::builder().inherit_stdio().build(),
+            wasi: wasi.build(),
             http: WasiHttpCtx::new(),
+            keyvalue: KeyValueCtx::new(host.keyvalue.clone(), code_id.clone(), limits.keyvalue),
+            env,
             time_tracker: time_tracker.clone(),
+            init_time_tracker: init_time_tracker.clone(),
+            is_initializing: true,
+            is_init_timeout: false,
             code_id: code_id.clone(),
             is_timeout: is_timeout.clone(),
+            limits,
+            subrequests: 0,
+            outgoing_http: host.outgoing_http.clone(),
+            service_binding: host.service_binding.clone(),
+            invocation,
+            memory,
+            _permit: permit,
         },
     );
+    store.limiter(|state| state);
     store.epoch_deadline_trap();
     store.set_epoch_deadline(1);
-    store.epoch_deadline_async_yield_and_update(1);
     store.epoch_deadline_callback({
-        |context| {
-            let state = context.data();
+        |mut context| {
+            let state = context.data_mut();
+            if state.is_initializing {
+                let cpu_time = state.init_time_tracker.duration();
+                if cpu_time > state.limits.init_cpu_time {
+                    telemetry::init_timeout(&state.code_id, cpu_time);
+                    state.is_init_timeout = true;
+                    return Ok(wasmtime::UpdateDeadline::Interrupt);
+                }
+                return Ok(wasmtime::UpdateDeadline::Yield(1));
+            }
             let cpu_time = state.time_tracker.duration();
-            if cpu_time > Duration::from_millis(1000) {
+            if cpu_time > state.limits.cpu_time {
                 telemetry::cpu_timeout(&state.code_id, cpu_time);
                 state.is_timeout.store(true, Ordering::Relaxed);
                 return Ok(wasmtime::UpdateDeadline::Interrupt);
             }
-            Ok(wasmtime::UpdateDeadline::Continue(1))
+            // Yields, so the wall-clock deadline also stops code that never waits.
+            Ok(wasmtime::UpdateDeadline::Yield(1))
         }
     });
 
@@ -242,117 +643,250 @@ where
         ) {
             Ok(x) => x,
             Err(error) => {
-                telemetry::wasmtime_error("new_incoming_request", &code_id, &format!("{error:?}"));
-                return internal_error_response();
+                let error = format!("{error:?}");
+                telemetry::wasmtime_error("new_incoming_request", &code_id, &error);
+                return (errors.response(ErrorKind::Internal, Some(&error)), None);
             }
         };
     let out = match store.data_mut().new_response_outparam(tx) {
         Ok(x) => x,
         Err(error) => {
-            telemetry::wasmtime_error("new_response_outparam", &code_id, &format!("{error:?}"));
-            return internal_error_response();
+            let error = format!("{error:?}");
+            telemetry::wasmtime_error("new_response_outparam", &code_id, &error);
+            return (errors.response(ErrorKind::Internal, Some(&error)), None);
         }
     };
 
-    // NOTE: Bad guys can put infinite loop in initialization code so it would be needed to measure cpu time from here.
-    // But also it includes wasmtime's instantiation.
-    // I guess we can put limit with fuel for initialization.
-    let proxy = match pre.instantiate_async(&mut store).await {
+    // Initialization code can loop forever, so it has its own cpu time budget.
+    // It also includes wasmtime's instantiation, so the budget should be generous.
+    let result = measure_cpu_time(
+        init_time_tracker.clone(),
+        pre.instance_pre().instantiate_async(&mut store),
+    )
+    .await;
+    telemetry::init_cpu_time(&code_id, init_time_tracker.duration());
+    let instance = match result {
         Ok(x) => x,
         Err(error) => {
-            telemetry::wasmtime_error("instantiate_async", &code_id, &format!("{error:?}"));
-            return internal_error_response();
+            let error = format!("{error:?}");
+            if store.data().is_init_timeout {
+                return (errors.response(ErrorKind::InitTimeout, Some(&error)), None);
+            }
+            if is_memory_denied.load(Ordering::Relaxed) {
+                return (
+                    errors.response(ErrorKind::MemoryLimitExceeded, Some(&error)),
+                    None,
+                );
+            }
+            telemetry::wasmtime_error("instantiate_async", &code_id, &error);
+            return (
+                errors.response(ErrorKind::InstantiateFailed, Some(&error)),
+                None,
+            );
         }
     };
+    store.data_mut().is_initializing = false;
+
+    // Cron runs go to the `scheduled` export if there is one, else to the handler.
+    let export = match scheduled
+        .and_then(|event| Some((event, scheduled::scheduled_func(&mut store, &instance)?)))
+    {
+        Some((event, func)) => Export::Scheduled(event, func),
+        None => match Proxy::new(&mut store, &instance) {
+            Ok(proxy) => Export::Proxy(proxy),
+            Err(error) => {
+                let error = format!("{error:?}");
+                telemetry::wasmtime_error("proxy_new", &code_id, &error);
+                return (
+                    errors.response(ErrorKind::InstantiateFailed, Some(&error)),
+                    None,
+                );
+            }
+        },
+    };
 
-    let task = tokio::task::spawn({
+    // The store lives in this task, so dropping the future on the deadline frees the instance
+    // even if the guest is idle in an outgoing request or a sleep, or is still streaming the body.
+    let mut task = GuestTask(tokio::task::spawn({
         let code_id = code_id.clone();
+        let is_duration_timeout = is_duration_timeout.clone();
         async move {
-            let result = measure_cpu_time(
-                time_tracker.clone(),
-                proxy
-                    .wasi_http_incoming_handler()
-                    .call_handle(store, req, out),
+            let result = tokio::time::timeout_at(
+                started_at + limits.duration,
+                measure_cpu_time(time_tracker.clone(), export.call(store, req, out)),
             )
             .await;
 
             telemetry::cpu_time(&code_id, time_tracker.duration());
+            trace_context::record_cpu_time(&span, time_tracker.duration());
+            hotness.record_cpu_time(time_tracker.duration());
 
-            result
+            match result {
+                Ok(result) => result,
+                Err(_elapsed) => {
+                    telemetry::duration_timeout(&code_id, started_at.elapsed());
+                    is_duration_timeout.store(true, Ordering::Relaxed);
+                    Err(anyhow!("wall-clock duration limit exceeded"))
+                }
+            }
         }
-    });
+    }));
 
     let result = rx.await;
 
     if let Err(_oneshot_recv_err) = result {
-        let result = task.await;
+        let result = (&mut task.0).await;
         if let Err(error) = result {
-            telemetry::request_task_join_error(&code_id, &format!("{error:?}"));
-            return internal_error_response();
+            let error = format!("{error:?}");
+            telemetry::request_task_join_error(&code_id, &error);
+            return (errors.response(ErrorKind::Internal, Some(&error)), None);
         }
         let result = result.unwrap();
 
-        if let Err(error) = result {
-            match error.downcast::<wasmtime::Trap>() {
-                Ok(trap) => {
-                    telemetry::trapped(&code_id, &format!("{trap:?}"));
-                }
-                Err(error) => {
-                    telemetry::canceled_unexpectedly(&code_id, &format!("{error:?}"));
+        let (kind, detail) = match result {
+            Ok(Some(response)) => return (response, None),
+            // The handler returned without setting the response.
+            Ok(None) => (ErrorKind::Internal, None),
+            // The debug format has the trap message and the wasm backtrace.
+            Err(error) => {
+                let detail = format!("{error:?}");
+                if error.is::<wasmtime::Trap>() {
+                    telemetry::trapped(&code_id, &detail);
+                    (ErrorKind::Trapped, Some(detail))
+                } else {
+                    telemetry::canceled_unexpectedly(&code_id, &detail);
+                    (ErrorKind::Internal, Some(detail))
                 }
             }
-        }
-
-        if is_timeout.load(Ordering::Relaxed) {
-            return timeout_response();
-        }
+        };
 
-        return internal_error_response();
+        let kind = if is_timeout.load(Ordering::Relaxed) {
+            ErrorKind::CpuTimeout
+        } else if is_duration_timeout.load(Ordering::Relaxed) {
+            ErrorKind::DurationTimeout
+        } else if is_memory_denied.load(Ordering::Relaxed) {
+            ErrorKind::MemoryLimitExceeded
+        } else {
+            kind
+        };
+        return (errors.response(kind, detail.as_deref()), None);
     }
 
     let result = result.unwrap();
 
     if let Ok(response) = result {
-        return response.map(|body| {
+        let response = response.map(|body| {
             body.map_err(|error_code| anyhow!("error_code: {error_code:?}"))
                 .boxed_unsync()
         });
+        return (response, Some(task));
     }
 
     let error_code: ErrorCode = result.unwrap_err();
 
-    telemetry::proxy_returns_error_code(&code_id, &format!("{error_code:?}"));
-    internal_error_response()
+    let error_code = format!("{error_code:?}");
+    telemetry::proxy_returns_error_code(&code_id, &error_code);
+    (
+        errors.response(ErrorKind::GuestErrorCode, Some(&error_code)),
+        Some(task),
+    )
+}
+
+/// The task running a guest, which can outlive its response, like to stream the body.
+/// Aborted when dropped, so shutting the executor down stops it with its job.
+struct GuestTask(JoinHandle<Result<Option<Response>>>);
+
+impl Drop for GuestTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
-fn response(status: hyper::StatusCode, body: Bytes) -> Response {
-    let body = http_body_util::Full::new(body).map_err(|never| match never {});
-    let mut res = hyper::Response::new(Body::new(body));
-    *res.status_mut() = status;
-    res
+enum Export {
+    Proxy(Proxy),
+    Scheduled(ScheduledEvent, scheduled::ScheduledFunc),
 }
 
-fn timeout_response() -> Response {
-    response(
-        hyper::StatusCode::GATEWAY_TIMEOUT,
-        Bytes::from("Gateway Timeout"),
-    )
+impl Export {
+    /// The response of `scheduled`. A handler responds through `out` instead.
+    async fn call<C: Clock>(
+        self,
+        mut store: Store<ClientState<C>>,
+        req: wasmtime::component::Resource<wasmtime_wasi_http::types::HostIncomingRequest>,
+        out: wasmtime::component::Resource<wasmtime_wasi_http::types::HostResponseOutparam>,
+    ) -> Result<Option<Response>> {
+        match self {
+            Self::Proxy(proxy) => {
+                proxy
+                    .wasi_http_incoming_handler()
+                    .call_handle(store, req, out)
+                    .await?;
+                Ok(None)
+            }
+            Self::Scheduled(event, func) => {
+                let (result,) = func
+                    .call_async(&mut store, (event.schedule, event.scheduled_at))
+                    .await?;
+                func.post_return_async(&mut store).await?;
+                let status = match result {
+                    Ok(()) => hyper::StatusCode::NO_CONTENT,
+                    // The message may carry anything of the code, so it's not reported.
+                    Err(_message) => hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                Ok(Some(response(status, Bytes::new())))
+            }
+        }
+    }
 }
 
-fn internal_error_response() -> Response {
-    response(
-        hyper::StatusCode::INTERNAL_SERVER_ERROR,
-        Bytes::from("Internal Server Error"),
-    )
+pub(crate) fn response(status: hyper::StatusCode, body: Bytes) -> Response {
+    let body = http_body_util::Full::new(body).map_err(|never| match never {});
+    let mut res = hyper::Response::new(Body::new(body));
+    *res.status_mut() = status;
+    res
 }
 
 pub struct ClientState<C: Clock> {
     wasi: WasiCtx,
     http: WasiHttpCtx,
+    keyvalue: KeyValueCtx,
+    env: Arc<CodeEnv>,
     table: ResourceTable,
     time_tracker: TimeTracker<C>,
+    init_time_tracker: TimeTracker<C>,
+    /// Until instantiation finishes, the epoch callback checks `init_time_tracker`.
+    is_initializing: bool,
+    is_init_timeout: bool,
     code_id: String,
     is_timeout: Arc<AtomicBool>,
+    limits: CodeLimits,
+    subrequests: usize,
+    outgoing_http: Arc<OutgoingHttpPolicy>,
+    service_binding: Weak<dyn ServiceBinding>,
+    invocation: Invocation,
+    memory: MemoryUsage,
+    /// Released with the store, which can outlive the job while the body streams.
+    _permit: AdmissionPermit,
+}
+
+impl<C: Clock> ResourceLimiter for ClientState<C> {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(self.memory.memory_growing(current, desired))
+    }
+
+    fn table_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(self.memory.table_growing(current, desired))
+    }
 }
 
 impl<C: Clock> WasiView for ClientState<C> {
@@ -372,4 +906,263 @@ impl<C: Clock> WasiHttpView for ClientState<C> {
     fn table(&mut self) -> &mut ResourceTable {
         &mut self.table
     }
+
+    fn send_request(
+        &mut self,
+        mut request: hyper::Request<HyperOutgoingBody>,
+        mut config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        // Internal requests are limited by `ServiceBindingLimits`, not by subrequests.
+        if request.uri().scheme_str() == Some(INTERNAL_SCHEME) {
+            return Ok(self.send_internal_request(request));
+        }
+
+        self.subrequests += 1;
+        if self.subrequests > self.limits.subrequests {
+            telemetry::subrequest(&self.code_id, "quota_exceeded");
+            return Ok(HostFutureIncomingResponse::ready(Ok(Err(
+                ErrorCode::HttpRequestDenied,
+            ))));
+        }
+
+        let Some(host) = request.uri().host() else {
+            telemetry::subrequest(&self.code_id, "invalid_uri");
+            return Ok(HostFutureIncomingResponse::ready(Ok(Err(
+                ErrorCode::HttpRequestUriInvalid,
+            ))));
+        };
+        if !self.outgoing_http.is_host_allowed(host) {
+            telemetry::subrequest(&self.code_id, "denied_host");
+            return Ok(HostFutureIncomingResponse::ready(Ok(Err(
+                ErrorCode::HttpRequestDenied,
+            ))));
+        }
+
+        self.outgoing_http.apply_timeouts(&mut config);
+        trace_context::inject_span(self.invocation.span(), request.headers_mut());
+
+        let outgoing_http = self.outgoing_http.clone();
+        let code_id = self.code_id.clone();
+        let handle = wasmtime_wasi::runtime::spawn(async move {
+            let started_at = Instant::now();
+            let result = outgoing_http::send_request(outgoing_http, request, config).await;
+            let outcome = match &result {
+                Ok(_) => "sent",
+                Err(ErrorCode::DestinationIpProhibited) => "denied_ip",
+                Err(ErrorCode::ConnectionTimeout | ErrorCode::ConnectionReadTimeout) => "timeout",
+                Err(_) => "error",
+            };
+            telemetry::subrequest(&code_id, outcome);
+            telemetry::subrequest_duration(&code_id, started_at.elapsed());
+            Ok(result)
+        });
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
+}
+
+impl<C: Clock> ClientState<C> {
+    fn send_internal_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+    ) -> HostFutureIncomingResponse {
+        let Some(service_binding) = self.service_binding.upgrade() else {
+            return HostFutureIncomingResponse::ready(Ok(Err(ErrorCode::InternalError(Some(
+                "fn0 is dropped".to_string(),
+            )))));
+        };
+        let code_id = self.code_id.clone();
+        let invocation = self.invocation.clone();
+        // The caller can't wait longer than its own duration limit anyway.
+        let between_bytes_timeout = self.limits.duration;
+        let request = request.map(|body| body.map_err(anyhow::Error::from).boxed_unsync());
+
+        let handle = wasmtime_wasi::runtime::spawn(async move {
+            let response = match service_binding.call(code_id, request, invocation).await {
+                Ok(response) => response,
+                Err(ServiceBindingError::Run(err)) => {
+                    return Ok(Err(ErrorCode::InternalError(Some(err.to_string()))));
+                }
+                Err(_) => return Ok(Err(ErrorCode::HttpRequestDenied)),
+            };
+            let resp = response.map(|body| {
+                body.map_err(|err| ErrorCode::InternalError(Some(err.to_string())))
+                    .boxed_unsync()
+            });
+            Ok(Ok(IncomingResponse {
+                resp,
+                worker: None,
+                between_bytes_timeout,
+            }))
+        });
+        HostFutureIncomingResponse::pending(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CodeKind, ERROR_HEADER, Fn0, JsCode,
+        admission::Admission,
+        test_util::{MemoryCache, component, request},
+    };
+    use measure_cpu_time::SystemClock;
+
+    fn executor(cache: MemoryCache<TieredWasmPre<SystemClock>>) -> WasmExecutor {
+        WasmExecutor::new(
+            cache,
+            SystemClock,
+            ExecutorConfig {
+                max_memory_bytes: CodeLimits::default().memory_bytes,
+                outgoing_http: Default::default(),
+                service_binding: Weak::<Fn0<MemoryCache<JsCode>>>::new(),
+                keyvalue: None,
+                tier_up_threshold: Default::default(),
+                // So tests can check the detail of errors.
+                debug_errors: true,
+            },
+        )
+    }
+
+    async fn run(wasm: &[u8], limits: CodeLimits) -> Response {
+        let cache = MemoryCache::default();
+        cache.insert("code", crate::compile(wasm).unwrap());
+        let executor = executor(cache);
+        let request = request("http://code/");
+        let invocation = Invocation::new("code", &request);
+        let permit = Admission::new(Default::default(), 1)
+            .admit(CodeKind::Wasm, "code", 1)
+            .await
+            .unwrap();
+        executor
+            .run(
+                "code",
+                limits,
+                Default::default(),
+                request,
+                invocation,
+                permit,
+            )
+            .await
+            .unwrap()
+    }
+
+    fn error_kind(response: &Response) -> &str {
+        response.headers()[ERROR_HEADER].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cpu_time_limit() {
+        let limits = CodeLimits {
+            cpu_time: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let response = run(&component("(loop br 0)", ""), limits).await;
+        assert_eq!(error_kind(&response), "cpu_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_init_cpu_time_limit() {
+        let limits = CodeLimits {
+            init_cpu_time: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let wasm = component("", "(func $init (loop br 0)) (start $init)");
+        let response = run(&wasm, limits).await;
+        assert_eq!(error_kind(&response), "init_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_duration_limit() {
+        let limits = CodeLimits {
+            cpu_time: Duration::from_secs(60),
+            duration: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let started_at = Instant::now();
+        let response = run(&component("(loop br 0)", ""), limits).await;
+        assert_eq!(error_kind(&response), "duration_timeout");
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let limits = CodeLimits {
+            memory_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+        // Grows past the limit by 100 pages, and fails like a guest out of memory.
+        let wasm = component(
+            "(if (i32.eq (memory.grow (i32.const 100)) (i32.const -1)) (then unreachable))",
+            "(memory 1)",
+        );
+        let response = run(&wasm, limits).await;
+        assert_eq!(error_kind(&response), "memory_limit_exceeded");
+    }
+
+    #[tokio::test]
+    async fn test_trap_detail() {
+        let wasm = component("call $fail", "(func $fail unreachable)");
+        let response = run(&wasm, Default::default()).await;
+        assert_eq!(error_kind(&response), "trapped");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("unreachable"), "{body}");
+        // The backtrace names the function from the name section.
+        assert!(body.contains("fail"), "{body}");
+    }
+
+    fn engine(pre: &WasmPre<SystemClock>) -> &Engine {
+        match pre {
+            WasmPre::Proxy(pre) => pre.instance_pre().component().engine(),
+            WasmPre::Cgi(pre) => pre.module().engine(),
+        }
+    }
+
+    #[test]
+    fn test_tier_up() {
+        let tiers = Arc::new(Tiers {
+            baseline: Tier::new(CompilerTier::Baseline, CodeLimits::default().memory_bytes),
+            optimized: Tier::new(CompilerTier::Optimized, CodeLimits::default().memory_bytes),
+        });
+        let bytes = crate::compile(&component("", "")).unwrap();
+        let artifact = artifact::decode(&bytes).unwrap();
+        let tiered = TieredWasmPre::new(
+            tiers.baseline.deserialize(artifact.precompiled).unwrap(),
+            artifact.source.map(Bytes::copy_from_slice),
+        );
+        let threshold = TierUpThreshold {
+            invocations: 2,
+            cpu_time: Duration::from_secs(60),
+        };
+
+        tiered.hotness().record_invocation();
+        assert!(tiered.start_tier_up(&threshold).is_none());
+        tiered.hotness().record_invocation();
+        let source = tiered.start_tier_up(&threshold).unwrap();
+        assert!(tiered.start_tier_up(&threshold).is_none());
+        let baseline = tiered.current();
+        assert!(Engine::same(engine(&baseline), &tiers.baseline.engine));
+
+        tier_up("code".to_string(), tiered.clone(), tiers.clone(), source);
+        assert!(Engine::same(
+            engine(&tiered.current()),
+            &tiers.optimized.engine
+        ));
+        // Invocations holding the baseline pre can still instantiate it.
+        assert!(Engine::same(engine(&baseline), &tiers.baseline.engine));
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_cranelift() {
+        let wasm = component(
+            "(return_call $respond (local.get 0) (local.get 1))",
+            "(func $respond (param i32 i32))",
+        );
+        let bytes = crate::compile(&wasm).unwrap();
+        assert_eq!(artifact::decode(&bytes).unwrap().source, None);
+
+        let response = run(&wasm, Default::default()).await;
+        assert_ne!(error_kind(&response), "code_load_failed");
+    }
 }
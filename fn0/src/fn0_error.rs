@@ -0,0 +1,139 @@
+//! Responses of fn0 when a code fails, rather than the code's own.
+//!
+//! They carry the kind in `x-fn0-error` and a JSON body like
+//! `{"error":"cpu_timeout","request_id":"..."}`. Telemetry has the full detail. In debug mode
+//! the body also has it as `detail`, including the trap message and the wasm backtrace
+//! with function names from the name section.
+
+use crate::{Response, execute::response, telemetry};
+use bytes::Bytes;
+use hyper::{
+    StatusCode,
+    header::{CONTENT_TYPE, HeaderValue},
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub const ERROR_HEADER: &str = "x-fn0-error";
+
+/// Stable across versions, so clients may match on `as_str`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The code's artifact couldn't be fetched or deserialized.
+    CodeLoadFailed,
+    /// Instantiation failed, or the code doesn't export a handler.
+    InstantiateFailed,
+    InitTimeout,
+    CpuTimeout,
+    DurationTimeout,
+    Trapped,
+    /// A memory was denied growth past `CodeLimits::memory_bytes`, and the code failed.
+    MemoryLimitExceeded,
+    /// A component responded with a wasi:http `error-code`.
+    GuestErrorCode,
+    /// A CGI module exited with a non-zero code.
+    ExitCode,
+    /// A CGI module wrote a response that doesn't parse.
+    InvalidOutput,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CodeLoadFailed => "code_load_failed",
+            Self::InstantiateFailed => "instantiate_failed",
+            Self::InitTimeout => "init_timeout",
+            Self::CpuTimeout => "cpu_timeout",
+            Self::DurationTimeout => "duration_timeout",
+            Self::Trapped => "trapped",
+            Self::MemoryLimitExceeded => "memory_limit_exceeded",
+            Self::GuestErrorCode => "guest_error_code",
+            Self::ExitCode => "exit_code",
+            Self::InvalidOutput => "invalid_output",
+            Self::Internal => "internal",
+        }
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            Self::InitTimeout | Self::CpuTimeout | Self::DurationTimeout => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Self::InvalidOutput => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Makes the error responses of one request.
+#[derive(Clone)]
+pub(crate) struct ErrorReporter {
+    code_id: String,
+    request_id: Arc<str>,
+    debug: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    request_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+impl ErrorReporter {
+    pub(crate) fn new(code_id: String, request_id: Arc<str>, debug: bool) -> Self {
+        Self {
+            code_id,
+            request_id,
+            debug,
+        }
+    }
+
+    /// `detail` only goes to the body in debug mode.
+    pub(crate) fn response(&self, kind: ErrorKind, detail: Option<&str>) -> Response {
+        telemetry::error_response(&self.code_id, kind.as_str());
+        let body = ErrorBody {
+            error: kind.as_str(),
+            request_id: &self.request_id,
+            detail: detail.filter(|_| self.debug),
+        };
+        let mut response = response(kind.status(), Bytes::from(sonic_rs::to_vec(&body).unwrap()));
+        let headers = response.headers_mut();
+        headers.insert(ERROR_HEADER, HeaderValue::from_static(kind.as_str()));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn body(response: Response) -> String {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_response() {
+        let reporter = ErrorReporter::new("code".to_string(), Arc::from("abc"), false);
+        let response = reporter.response(ErrorKind::CpuTimeout, Some("cpu time 2s"));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response.headers()[ERROR_HEADER], "cpu_timeout");
+        assert_eq!(
+            body(response).await,
+            r#"{"error":"cpu_timeout","request_id":"abc"}"#
+        );
+
+        let reporter = ErrorReporter::new("code".to_string(), Arc::from("abc"), true);
+        let response = reporter.response(ErrorKind::Trapped, Some("wasm trap: unreachable"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body(response).await,
+            r#"{"error":"trapped","request_id":"abc","detail":"wasm trap: unreachable"}"#
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! memberlist setup shared by `cluster` and `cluster_manager`.
+
+use crate::{telemetry, warm_up_map::NodeId};
+use anyhow::{Result, anyhow};
+use memberlist::{
+    Options,
+    delegate::{CompositeDelegate, EventDelegate, NodeDelegate, VoidDelegate},
+    net::NetTransportOptions,
+    proto::{MaybeResolvedAddress, Node},
+    tokio::{TokioSocketAddrResolver, TokioTcpMemberlist},
+};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// `D` gets the gossip events and the user messages.
+pub(crate) type Delegate<D> = CompositeDelegate<
+    NodeId,
+    SocketAddr,
+    VoidDelegate<NodeId, SocketAddr>,
+    VoidDelegate<NodeId, SocketAddr>,
+    D,
+    VoidDelegate<NodeId, SocketAddr>,
+    D,
+>;
+
+pub(crate) type Memberlist<D> = TokioTcpMemberlist<NodeId, TokioSocketAddrResolver, Delegate<D>>;
+
+/// Starts memberlist on `gossip_addr`, which listens with TCP and UDP.
+pub(crate) async fn start<D>(
+    node_id: NodeId,
+    gossip_addr: SocketAddrV4,
+    delegate: D,
+) -> Result<Memberlist<D>>
+where
+    D: EventDelegate<Id = NodeId, Address = SocketAddr> + NodeDelegate + Clone,
+{
+    let mut transport = NetTransportOptions::new(node_id);
+    transport.add_bind_address(gossip_addr.into());
+    Memberlist::with_delegate(
+        CompositeDelegate::new()
+            .with_event_delegate(delegate.clone())
+            .with_node_delegate(delegate),
+        transport,
+        Options::lan(),
+    )
+    .await
+    .map_err(|err| anyhow!("Failed to start memberlist: {err}"))
+}
+
+/// Joins through `seeds`, which listen on the same port as `gossip_addr`.
+pub(crate) async fn join<D>(
+    memberlist: &Memberlist<D>,
+    gossip_addr: SocketAddrV4,
+    seeds: &[Ipv4Addr],
+) where
+    D: EventDelegate<Id = NodeId, Address = SocketAddr> + NodeDelegate,
+{
+    let port = gossip_addr.port();
+    let seeds = seeds
+        .iter()
+        .filter(|ip| *ip != gossip_addr.ip())
+        .map(|ip| {
+            // Only the address is used to join.
+            Node::new(0, MaybeResolvedAddress::Resolved((*ip, port).into()))
+        })
+        .collect::<Vec<_>>();
+    // Failing to join isn't fatal, as the seeds can still join this node later.
+    if !seeds.is_empty()
+        && let Err((joined, err)) = memberlist.join_many(seeds.into_iter()).await
+        && joined.is_empty()
+    {
+        telemetry::cluster_error("join", &err.to_string());
+    }
+}
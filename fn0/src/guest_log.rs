@@ -0,0 +1,284 @@
+//! Captures guest stdout/stderr per invocation and emits each line as a log record.
+
+use crate::telemetry;
+use bytes::Bytes;
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::{self, AsyncWrite};
+use wasmtime_wasi::{
+    cli::{IsTerminal, StdoutStream},
+    p2::{OutputStream, Pollable, StreamResult},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestLogStream {
+    Stdout,
+    Stderr,
+}
+
+impl GuestLogStream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+impl From<ski::LogStream> for GuestLogStream {
+    fn from(stream: ski::LogStream) -> Self {
+        match stream {
+            ski::LogStream::Stdout => Self::Stdout,
+            ski::LogStream::Stderr => Self::Stderr,
+        }
+    }
+}
+
+/// Log sink of one invocation. Clones share the byte budget and the partial lines.
+#[derive(Clone)]
+pub(crate) struct GuestLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    code_id: String,
+    request_id: Arc<str>,
+    /// Shared by stdout and stderr. Output beyond this is dropped.
+    remaining_bytes: usize,
+    is_truncated: bool,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl GuestLog {
+    pub(crate) fn new(code_id: &str, request_id: Arc<str>, max_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                code_id: code_id.to_string(),
+                request_id,
+                remaining_bytes: max_bytes,
+                is_truncated: false,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn write(&self, stream: GuestLogStream, bytes: &[u8]) {
+        self.inner.lock().unwrap().write(stream, bytes);
+    }
+
+    pub(crate) fn output(&self, stream: GuestLogStream) -> GuestLogOutput {
+        GuestLogOutput {
+            log: self.clone(),
+            stream,
+        }
+    }
+}
+
+impl Inner {
+    fn write(&mut self, stream: GuestLogStream, bytes: &[u8]) {
+        let len = bytes.len().min(self.remaining_bytes);
+        if len < bytes.len() && !self.is_truncated {
+            self.is_truncated = true;
+            telemetry::guest_log_truncated(&self.code_id);
+        }
+        self.remaining_bytes -= len;
+
+        let buffer = self.buffer(stream);
+        buffer.extend_from_slice(&bytes[..len]);
+        let Some(end) = buffer.iter().rposition(|byte| *byte == b'\n') else {
+            return;
+        };
+        let lines = buffer.drain(..=end).collect::<Vec<_>>();
+        for line in lines[..end].split(|byte| *byte == b'\n') {
+            self.emit(stream, line);
+        }
+    }
+
+    fn buffer(&mut self, stream: GuestLogStream) -> &mut Vec<u8> {
+        match stream {
+            GuestLogStream::Stdout => &mut self.stdout,
+            GuestLogStream::Stderr => &mut self.stderr,
+        }
+    }
+
+    fn emit(&self, stream: GuestLogStream, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        telemetry::guest_log(
+            &self.code_id,
+            &self.request_id,
+            stream,
+            &String::from_utf8_lossy(line),
+        );
+    }
+}
+
+impl Drop for Inner {
+    /// Output without a trailing newline is emitted once the invocation is gone.
+    fn drop(&mut self) {
+        for stream in [GuestLogStream::Stdout, GuestLogStream::Stderr] {
+            let line = std::mem::take(self.buffer(stream));
+            if !line.is_empty() {
+                self.emit(stream, &line);
+            }
+        }
+    }
+}
+
+/// Guest stdout or stderr, given to `WasiCtxBuilder`.
+#[derive(Clone)]
+pub(crate) struct GuestLogOutput {
+    log: GuestLog,
+    stream: GuestLogStream,
+}
+
+impl IsTerminal for GuestLogOutput {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl StdoutStream for GuestLogOutput {
+    fn p2_stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn async_stream(&self) -> Box<dyn AsyncWrite + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for GuestLogOutput {
+    async fn ready(&mut self) {}
+}
+
+impl OutputStream for GuestLogOutput {
+    /// Never fails, even over the budget, so guests don't break on logging.
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.log.write(self.stream, &bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(1024 * 1024)
+    }
+}
+
+impl AsyncWrite for GuestLogOutput {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.log.write(self.stream, buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{
+        Event, Subscriber,
+        field::{Field, Visit},
+    };
+    use tracing_subscriber::{Layer, layer::Context as LayerContext, prelude::*};
+
+    /// Collects `(stream, line)` of the guest log records.
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl<S: Subscriber> Layer<S> for Lines {
+        fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+            if event.metadata().target() != "fn0::guest" {
+                return;
+            }
+            let mut line = LineVisitor::default();
+            event.record(&mut line);
+            self.0.lock().unwrap().push((line.stream, line.message));
+        }
+    }
+
+    #[derive(Default)]
+    struct LineVisitor {
+        stream: String,
+        message: String,
+    }
+
+    impl Visit for LineVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "stream" {
+                self.stream = value.to_string();
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                self.message = format!("{value:?}");
+            }
+        }
+    }
+
+    fn capture(max_bytes: usize, write: impl FnOnce(&GuestLog)) -> Vec<(String, String)> {
+        let lines = Lines::default();
+        let subscriber = tracing_subscriber::registry().with(lines.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            write(&GuestLog::new("code", Arc::from("request"), max_bytes));
+        });
+        lines.0.lock().unwrap().clone()
+    }
+
+    fn line(stream: &str, message: &str) -> (String, String) {
+        (stream.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn test_splits_lines() {
+        let lines = capture(1024, |log| {
+            log.write(GuestLogStream::Stdout, b"hel");
+            log.write(GuestLogStream::Stderr, b"oops\r\n");
+            log.write(GuestLogStream::Stdout, b"lo\n\nworld\nrest");
+        });
+        assert_eq!(
+            lines,
+            [
+                line("stderr", "oops"),
+                line("stdout", "hello"),
+                line("stdout", ""),
+                line("stdout", "world"),
+                // Without a trailing newline, emitted when the log is dropped.
+                line("stdout", "rest"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_caps_bytes() {
+        let lines = capture(10, |log| {
+            let mut stdout = log.output(GuestLogStream::Stdout);
+            let mut stderr = log.output(GuestLogStream::Stderr);
+            OutputStream::write(&mut stdout, Bytes::from("1234\n")).unwrap();
+            // Over the budget shared with stdout, but the guest isn't told.
+            OutputStream::write(&mut stderr, Bytes::from("5678\n90ab\n")).unwrap();
+            OutputStream::write(&mut stdout, Bytes::from("dropped\n")).unwrap();
+        });
+        assert_eq!(lines, [line("stdout", "1234"), line("stderr", "5678")]);
+    }
+}
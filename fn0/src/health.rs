@@ -0,0 +1,158 @@
+//! `GET /health`, probed by worker-health-checker.
+//!
+//! The body is `good` or `graceful_shutting_down`, the only ones the checker accepts,
+//! so an overloaded host still answers `good`: anything else reads as a dead host.
+//! With `?detail` the body is JSON with the state and the executor's stats, for trusted callers
+//! only, as it shows the deployment and the caches. Others get the plain body.
+
+use crate::{AdmissionStats, ProxyCacheStats, Request, Response, execute::response};
+use bytes::Bytes;
+use hyper::{
+    Method, StatusCode,
+    header::{CACHE_CONTROL, CONTENT_TYPE, HeaderValue},
+};
+use serde::Serialize;
+
+pub const HEALTH_PATH: &str = "/health";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Ready,
+    /// Jobs are waiting for an instance.
+    Overloaded,
+    /// `Fn0::start_draining` was called. New requests are refused.
+    Draining,
+}
+
+impl HealthState {
+    /// The body of the plain text contract.
+    pub fn as_status(&self) -> &'static str {
+        match self {
+            Self::Ready | Self::Overloaded => "good",
+            Self::Draining => "graceful_shutting_down",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub state: HealthState,
+    pub deployment_id: u64,
+    /// Requests past `Fn0::run`, including ones waiting for admission.
+    pub in_flight: u64,
+    /// Jobs holding a wasm instance.
+    pub instances: u64,
+    pub admission: AdmissionStats,
+    pub proxy_cache: ProxyCacheStats,
+    /// Codes with their config and secrets resolved.
+    pub code_envs: usize,
+}
+
+pub(crate) fn is_health_request(request: &Request) -> bool {
+    request.method() == Method::GET && request.uri().path() == HEALTH_PATH
+}
+
+pub(crate) fn health_response(request: &Request, health: Health, allow_detail: bool) -> Response {
+    let is_detail = allow_detail
+        && request.uri().query().is_some_and(|query| {
+            query
+                .split('&')
+                .any(|pair| pair.split('=').next() == Some("detail"))
+        });
+
+    let (body, content_type) = if is_detail {
+        (sonic_rs::to_vec(&health).unwrap(), "application/json")
+    } else {
+        (health.status.as_bytes().to_vec(), "text/plain")
+    };
+    let mut response = response(StatusCode::OK, Bytes::from(body));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Body;
+    use http_body_util::{BodyExt, Empty};
+
+    fn request(method: Method, uri: &str) -> Request {
+        hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::new(Empty::new().map_err(|never| match never {})))
+            .unwrap()
+    }
+
+    fn health(state: HealthState) -> Health {
+        Health {
+            status: state.as_status(),
+            state,
+            deployment_id: 3,
+            in_flight: 2,
+            instances: 1,
+            admission: AdmissionStats {
+                max_instances: 4,
+                available_instances: 3,
+                max_isolates: 4,
+                available_isolates: 4,
+                queue_len: 0,
+            },
+            proxy_cache: ProxyCacheStats {
+                hits: 5,
+                loads: 1,
+                errors: 0,
+            },
+            code_envs: 1,
+        }
+    }
+
+    async fn body(response: Response) -> String {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_health_response() {
+        assert!(is_health_request(&request(
+            Method::GET,
+            "http://a.fn0.dev/health"
+        )));
+        assert!(is_health_request(&request(Method::GET, "/health?detail")));
+        assert!(!is_health_request(&request(Method::POST, "/health")));
+        assert!(!is_health_request(&request(Method::GET, "/healthz")));
+
+        let plain = request(Method::GET, "/health");
+        for (state, expected) in [
+            (HealthState::Ready, "good"),
+            (HealthState::Overloaded, "good"),
+            (HealthState::Draining, "graceful_shutting_down"),
+        ] {
+            let response = health_response(&plain, health(state), true);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body(response).await, expected);
+        }
+
+        let response = health_response(
+            &request(Method::GET, "/health?detail=1"),
+            health(HealthState::Overloaded),
+            true,
+        );
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(
+            body(response).await,
+            r#"{"status":"good","state":"overloaded","deployment_id":3,"in_flight":2,"instances":1,"admission":{"max_instances":4,"available_instances":3,"max_isolates":4,"available_isolates":4,"queue_len":0},"proxy_cache":{"hits":5,"loads":1,"errors":0},"code_envs":1}"#
+        );
+
+        let response = health_response(
+            &request(Method::GET, "/health?detail"),
+            health(HealthState::Ready),
+            false,
+        );
+        assert_eq!(body(response).await, "good");
+    }
+}
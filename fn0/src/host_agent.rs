@@ -0,0 +1,354 @@
+//! Host side of `host-hq-protocol`.
+//!
+//! hq connects to every host over QUIC, pings it with datagrams and pushes deployment updates
+//! and graceful shutdown over uni streams. The host answers with `NotifyHostStatus` datagrams.
+
+use crate::{Fn0, JsCode, ScheduledEvent, telemetry};
+use adapt_cache::AdaptCache;
+use anyhow::Result;
+use host_hq_protocol::{HostToHq, HqToHostDatagram, HqToHostReliable};
+use quinn::{Connection, Endpoint, Incoming, RecvStream, ServerConfig};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    string::FromUtf8Error,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::watch, time::MissedTickBehavior};
+use tracing::{info, warn};
+
+/// hq always dials this port.
+pub const HOST_AGENT_PORT: u16 = 10000;
+
+const MAX_RELIABLE_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct HostAgentConfig {
+    pub listen_addr: SocketAddr,
+    /// hq verifies this chain with server name `host.fn0`.
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub private_key: PrivateKeyDer<'static>,
+    pub status_interval: Duration,
+    /// How long running requests get to finish on graceful shutdown.
+    pub drain_timeout: Duration,
+}
+
+impl HostAgentConfig {
+    pub fn new(
+        cert_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        Self {
+            listen_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), HOST_AGENT_PORT),
+            cert_chain,
+            private_key,
+            status_interval: Duration::from_secs(1),
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Serves hq until it asks for graceful shutdown.
+/// On shutdown, `fn0` stops accepting requests and this returns once running requests are gone
+/// or `drain_timeout` has passed.
+pub async fn run<J>(fn0: Arc<Fn0<J>>, config: HostAgentConfig) -> Result<()>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let server_config = ServerConfig::with_single_cert(config.cert_chain, config.private_key)?;
+    let endpoint = Endpoint::server(server_config, config.listen_addr)?;
+    info!(addr = %config.listen_addr, "host agent listening");
+
+    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                let fn0 = fn0.clone();
+                let shutdown_tx = shutdown_tx.clone();
+                let status_interval = config.status_interval;
+                tokio::spawn(async move {
+                    if let Err(err) = serve_hq(incoming, fn0, status_interval, shutdown_tx).await {
+                        warn!(%err, "hq connection closed");
+                    }
+                });
+            }
+            _ = shutdown_rx.changed() => {
+                break;
+            }
+        }
+    }
+
+    let report = fn0
+        .shutdown(tokio::time::Instant::now() + config.drain_timeout)
+        .await;
+    info!(
+        finished = report.finished,
+        dropped = report.dropped,
+        "fn0 drained"
+    );
+
+    endpoint.close(0_u8.into(), b"graceful shutdown");
+    endpoint.wait_idle().await;
+
+    Ok(())
+}
+
+async fn serve_hq<J>(
+    incoming: Incoming,
+    fn0: Arc<Fn0<J>>,
+    status_interval: Duration,
+    shutdown_tx: watch::Sender<bool>,
+) -> Result<()>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let connection = match incoming.await {
+        Ok(connection) => {
+            telemetry::host_agent_connection_status(true);
+            connection
+        }
+        Err(err) => {
+            telemetry::host_agent_connection_status(false);
+            return Err(err.into());
+        }
+    };
+    info!(remote = %connection.remote_address(), "hq connected");
+
+    let mut interval = tokio::time::interval(status_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                send_status(&connection, &fn0);
+            }
+            bytes = connection.read_datagram() => {
+                match HqToHostDatagram::from_bytes(bytes?) {
+                    Ok(HqToHostDatagram::AdvertiseLatestDeploymentId { deployment_id }) => {
+                        // hq replies to our status with the updates we are missing.
+                        if deployment_id != fn0.deployment_id() {
+                            send_status(&connection, &fn0);
+                        }
+                    }
+                    Err(err) => {
+                        telemetry::host_agent_message_parse_error(&err.to_string());
+                    }
+                }
+            }
+            recv = connection.accept_uni() => {
+                // Read apart, so a slow stream doesn't hold up status and datagrams.
+                tokio::spawn(handle_reliable(
+                    recv?,
+                    connection.clone(),
+                    fn0.clone(),
+                    shutdown_tx.clone(),
+                ));
+            }
+        }
+    }
+}
+
+async fn handle_reliable<J>(
+    mut recv: RecvStream,
+    connection: Connection,
+    fn0: Arc<Fn0<J>>,
+    shutdown_tx: watch::Sender<bool>,
+) where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let bytes = match recv.read_to_end(MAX_RELIABLE_MESSAGE_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            warn!(%err, "Failed to read a message of hq");
+            return;
+        }
+    };
+    match HqToHostReliable::from_bytes(bytes.into()) {
+        Ok(HqToHostReliable::DeploymentUpdates {
+            deployment_id,
+            code_id_and_versions,
+        }) => {
+            let applied = fn0.apply_deployment_updates(deployment_id, &code_id_and_versions);
+            telemetry::host_agent_deployment_updates(applied, code_id_and_versions.len());
+            send_status(&connection, &fn0);
+        }
+        Ok(HqToHostReliable::GracefulShutdown) => {
+            telemetry::host_agent_graceful_shutdown();
+            shutdown_tx.send_replace(true);
+        }
+        Ok(HqToHostReliable::RunScheduled {
+            run_id,
+            code_id,
+            schedule,
+            scheduled_at,
+        }) => {
+            let event = ScheduledEvent {
+                schedule,
+                scheduled_at,
+            };
+            run_scheduled(connection, fn0, run_id, code_id.to_string(), event).await;
+        }
+        Err(err) => {
+            telemetry::host_agent_message_parse_error(&err.to_string());
+        }
+    }
+}
+
+/// Refused while draining, so hq sends the run to another host.
+async fn run_scheduled<J>(
+    connection: Connection,
+    fn0: Arc<Fn0<J>>,
+    run_id: u64,
+    code_id: String,
+    event: ScheduledEvent,
+) where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let ok = match fn0.run_scheduled(&code_id, event).await {
+        Ok(response) => response.status().is_success(),
+        Err(err) => {
+            warn!(%err, %code_id, "scheduled run failed");
+            false
+        }
+    };
+    telemetry::host_agent_scheduled_run(&code_id, ok);
+
+    let result = send_reliable(&connection, HostToHq::ScheduledRunResult { run_id, ok }).await;
+    telemetry::host_agent_scheduled_run_result_sent(result.is_ok());
+}
+
+async fn send_reliable(connection: &Connection, message: HostToHq) -> Result<()> {
+    let bytes = message.to_bytes()?;
+    let mut send = connection.open_uni().await?;
+    send.write_all(&bytes).await?;
+    send.finish()?;
+    Ok(())
+}
+
+fn send_status<J>(connection: &Connection, fn0: &Fn0<J>)
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let status = HostToHq::NotifyHostStatus {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        deployment_id: fn0.deployment_id(),
+        instances: fn0.instances(),
+    };
+
+    let result = status
+        .to_bytes()
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| Ok(connection.send_datagram(bytes)?));
+
+    telemetry::host_agent_status_sent(result.is_ok());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeploymentMap, test_util::MemoryCache};
+    use quinn::ClientConfig;
+
+    const CERT: &[u8] = include_bytes!("../testdata/host.fn0.der");
+    const KEY: &[u8] = include_bytes!("../testdata/host.fn0.key.der");
+
+    /// Plays hq.
+    async fn connect(addr: SocketAddr) -> Connection {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(CERT)).unwrap();
+        let client_config = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+        let endpoint = Endpoint::client((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        for _ in 0..100 {
+            if let Ok(connection) = endpoint
+                .connect_with(client_config.clone(), addr, "host.fn0")
+                .unwrap()
+                .await
+            {
+                return connection;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("the host agent isn't listening");
+    }
+
+    async fn send(connection: &Connection, message: HqToHostReliable) {
+        let mut send = connection.open_uni().await.unwrap();
+        send.write_all(&message.to_bytes().unwrap()).await.unwrap();
+        send.finish().unwrap();
+    }
+
+    async fn status_deployment_id(connection: &Connection) -> u64 {
+        let bytes = connection.read_datagram().await.unwrap();
+        match HostToHq::from_bytes(bytes).unwrap() {
+            HostToHq::NotifyHostStatus { deployment_id, .. } => deployment_id,
+            HostToHq::ScheduledRunResult { .. } => panic!("a result as a datagram"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_hq() {
+        let fn0 = Fn0::new(
+            MemoryCache::default(),
+            MemoryCache::<JsCode>::default(),
+            DeploymentMap::new(),
+            Default::default(),
+        );
+        let mut config = HostAgentConfig::new(
+            vec![CertificateDer::from(CERT)],
+            PrivateKeyDer::try_from(KEY).unwrap().clone_key(),
+        );
+        config.listen_addr = (Ipv4Addr::LOCALHOST, crate::test_util::free_port()).into();
+        config.status_interval = Duration::from_millis(50);
+        config.drain_timeout = Duration::from_secs(1);
+        let addr = config.listen_addr;
+        let agent = tokio::spawn(run(fn0.clone(), config));
+
+        let connection = connect(addr).await;
+        assert_eq!(status_deployment_id(&connection).await, 0);
+
+        let updates = HqToHostReliable::DeploymentUpdates {
+            deployment_id: 0,
+            code_id_and_versions: vec![(7, 1)],
+        };
+        send(&connection, updates).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status_deployment_id(&connection).await != 1 {}
+        })
+        .await
+        .unwrap();
+        assert_eq!(fn0.deployment_id(), 1);
+
+        let run = HqToHostReliable::RunScheduled {
+            run_id: 3,
+            code_id: 7,
+            schedule: "nightly".to_string(),
+            scheduled_at: 0,
+        };
+        send(&connection, run).await;
+        let mut recv = connection.accept_uni().await.unwrap();
+        let bytes = recv.read_to_end(1024).await.unwrap();
+        // Code 7 has no source, so the run fails.
+        assert!(matches!(
+            HostToHq::from_bytes(bytes.into()).unwrap(),
+            HostToHq::ScheduledRunResult {
+                run_id: 3,
+                ok: false
+            }
+        ));
+
+        send(&connection, HqToHostReliable::GracefulShutdown).await;
+        tokio::time::timeout(Duration::from_secs(5), agent)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(fn0.is_draining());
+    }
+}
@@ -0,0 +1,160 @@
+//! HTTP server of a host, where clients and other hosts of the cluster send requests.
+//!
+//! The code of a request is the first label of its host, like `<code_id>.{domain}`.
+//! Requests go through `Cluster::route`, so they run on a host with the code warm.
+//! `a.{domain}` is where worker-health-checker probes `/health`, so `a` isn't a code.
+
+use crate::{Cluster, ClusterConfig, Fn0, JsCode, RunError, execute::response, telemetry};
+use adapt_cache::AdaptCache;
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::{StatusCode, body::Incoming, header::HOST, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    string::FromUtf8Error,
+    sync::Arc,
+};
+use tokio::net::TcpListener;
+use tracing::warn;
+
+const HEALTH_LABEL: &str = "a";
+
+/// Joins the cluster and serves `cluster_config.http_port` on every address.
+pub async fn run<J>(fn0: Arc<Fn0<J>>, cluster_config: ClusterConfig) -> Result<()>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, cluster_config.http_port)).await?;
+    let cluster = Cluster::join(cluster_config, {
+        let fn0 = fn0.clone();
+        move || fn0.instances() as u32
+    })
+    .await?;
+    serve(listener, fn0, cluster).await
+}
+
+async fn serve<J>(listener: TcpListener, fn0: Arc<Fn0<J>>, cluster: Arc<Cluster>) -> Result<()>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let fn0 = fn0.clone();
+        let cluster = cluster.clone();
+        let service = service_fn(move |request: hyper::Request<Incoming>| {
+            let request = request.map(|body| body.map_err(anyhow::Error::from).boxed_unsync());
+            handle_request(fn0.clone(), cluster.clone(), request, remote.ip())
+        });
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!(%err, "Failed to serve connection");
+            }
+        });
+    }
+}
+
+async fn handle_request<J>(
+    fn0: Arc<Fn0<J>>,
+    cluster: Arc<Cluster>,
+    request: crate::Request,
+    remote_ip: IpAddr,
+) -> Result<crate::Response>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    let Some(code_id) = code_id(&request) else {
+        return Ok(response(StatusCode::NOT_FOUND, Bytes::from("Not Found")));
+    };
+    if code_id == HEALTH_LABEL {
+        let allow_detail = remote_ip.is_loopback() || cluster.is_member(remote_ip).await;
+        return Ok(fn0
+            .health_response(&request, allow_detail)
+            .unwrap_or_else(|| response(StatusCode::NOT_FOUND, Bytes::from("Not Found"))));
+    }
+    let result = cluster
+        .route(&code_id, request, remote_ip, |request| async {
+            fn0.run(&code_id, request).await
+        })
+        .await;
+    Ok(
+        result.unwrap_or_else(|err| match err.downcast_ref::<RunError>() {
+            // Another host should take it.
+            Some(RunError::Draining) => {
+                telemetry::cluster_rejected(&code_id, &err.to_string());
+                response(StatusCode::SERVICE_UNAVAILABLE, Bytes::new())
+            }
+            Some(RunError::CodeNotFound) => {
+                response(StatusCode::NOT_FOUND, Bytes::from("Not Found"))
+            }
+            None => {
+                warn!(%err, %code_id, "Failed to run a request");
+                response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Bytes::from("Internal Server Error"),
+                )
+            }
+        }),
+    )
+}
+
+fn code_id(request: &crate::Request) -> Option<String> {
+    let host = match request.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => request.uri().host()?,
+    };
+    let code_id = host.split(['.', ':']).next()?;
+    (!code_id.is_empty()).then(|| code_id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        DeploymentMap,
+        test_util::{MemoryCache, free_port},
+    };
+    use hyper::header::CONTENT_TYPE;
+    use std::net::SocketAddrV4;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let fn0 = Fn0::new(
+            MemoryCache::default(),
+            MemoryCache::<JsCode>::default(),
+            DeploymentMap::new(),
+            Default::default(),
+        );
+        let ip = Ipv4Addr::LOCALHOST;
+        let http_port = free_port();
+        let config = ClusterConfig::new(1, SocketAddrV4::new(ip, free_port()), http_port);
+        let cluster = Cluster::join(config, || 0).await.unwrap();
+        let listener = TcpListener::bind((ip, http_port)).await.unwrap();
+        tokio::spawn(serve(listener, fn0, cluster));
+
+        let get = |host: &'static str, path: &'static str| async move {
+            reqwest::Client::new()
+                .get(format!("http://{ip}:{http_port}{path}"))
+                .header(HOST, host)
+                .send()
+                .await
+                .unwrap()
+        };
+        assert_eq!(
+            get("code.test", "/__fn0/queue").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get("missing.test", "/").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(get("a.test", "/health").await.text().await.unwrap(), "good");
+        let detail = get("a.test", "/health?detail").await;
+        assert_eq!(detail.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(get("a.test", "/").await.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,163 @@
+//! HTTP limits of fn0 Cloud on external requests and their responses.
+//!
+//! Requests known to be too large get a 431 or a 413 before they run. A body without
+//! `content-length` is cut at the limit while the code reads it, and if the code fails then,
+//! the client gets a 413 too. Responses with too large headers become a 502.
+
+use crate::{Request, Response, execute::response};
+use anyhow::anyhow;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Limited};
+use hyper::{HeaderMap, StatusCode, header::CONTENT_LENGTH};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Applied to external requests in `Fn0::run`. Defaults are the fn0 Cloud limits in README.
+#[derive(Clone, Copy, Debug)]
+pub struct HttpLimits {
+    pub max_request_header_bytes: usize,
+    pub max_request_body_bytes: usize,
+    pub max_response_header_bytes: usize,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self {
+            max_request_header_bytes: 128 * 1024,
+            max_request_body_bytes: 100 * 1024 * 1024,
+            max_response_header_bytes: 128 * 1024,
+        }
+    }
+}
+
+impl HttpLimits {
+    /// 431 or 413 if the request is known to be too large.
+    pub(crate) fn reject_request(&self, request: &Request) -> Option<Response> {
+        let header_bytes = request.uri().to_string().len() + header_bytes(request.headers());
+        if header_bytes > self.max_request_header_bytes {
+            return Some(response(
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                Bytes::from("Request Header Fields Too Large"),
+            ));
+        }
+
+        let content_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if content_length.is_some_and(|len| len > self.max_request_body_bytes as u64) {
+            return Some(payload_too_large_response());
+        }
+        None
+    }
+
+    /// A body without `content-length` fails while streaming once it goes over the limit,
+    /// which sets the returned flag.
+    pub(crate) fn limit_request_body(&self, request: Request) -> (Request, Arc<AtomicBool>) {
+        let max_request_body_bytes = self.max_request_body_bytes;
+        let is_too_large = Arc::new(AtomicBool::new(false));
+        let request = request.map(|body| {
+            let is_too_large = is_too_large.clone();
+            Limited::new(body, max_request_body_bytes)
+                .map_err(move |err| {
+                    if err.is::<http_body_util::LengthLimitError>() {
+                        is_too_large.store(true, Ordering::Relaxed);
+                    }
+                    anyhow!(err)
+                })
+                .boxed_unsync()
+        });
+        (request, is_too_large)
+    }
+
+    pub(crate) fn is_response_header_too_large(&self, response: &Response) -> bool {
+        header_bytes(response.headers()) > self.max_response_header_bytes
+    }
+}
+
+/// Size as sent in HTTP/1.1, `name: value\r\n` for each field.
+fn header_bytes(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+pub(crate) fn payload_too_large_response() -> Response {
+    response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Bytes::from("Payload Too Large"),
+    )
+}
+
+pub(crate) fn response_header_too_large_response() -> Response {
+    response(
+        StatusCode::BAD_GATEWAY,
+        Bytes::from("Bad Gateway: response header too large"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        let mut builder = hyper::Request::builder().uri("/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder
+            .body(Empty::new().map_err(|never| match never {}).boxed_unsync())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_reject_request() {
+        let limits = HttpLimits {
+            max_request_header_bytes: 64,
+            max_request_body_bytes: 10,
+            ..Default::default()
+        };
+
+        assert!(limits.reject_request(&request(&[("x-a", "b")])).is_none());
+
+        let long_value = "a".repeat(64);
+        let response = limits
+            .reject_request(&request(&[("x-a", &long_value)]))
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        let response = limits
+            .reject_request(&request(&[("content-length", "11")]))
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_limit_request_body() {
+        let limits = HttpLimits {
+            max_request_body_bytes: 10,
+            ..Default::default()
+        };
+        let body = |len: usize| {
+            http_body_util::Full::new(Bytes::from(vec![0; len]))
+                .map_err(|never| match never {})
+                .boxed_unsync()
+        };
+
+        let (request, is_too_large) = limits.limit_request_body(hyper::Request::new(body(10)));
+        assert!(request.into_body().collect().await.is_ok());
+        assert!(!is_too_large.load(Ordering::Relaxed));
+
+        let (request, is_too_large) = limits.limit_request_body(hyper::Request::new(body(11)));
+        assert!(request.into_body().collect().await.is_err());
+        assert!(is_too_large.load(Ordering::Relaxed));
+    }
+}
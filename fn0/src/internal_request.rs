@@ -0,0 +1,77 @@
+//! Requests fn0 makes itself, like `scheduled` and `queue` ones, go to `/__fn0/` paths with
+//! `x-fn0-*` headers. A client can't make one, as its requests are checked here.
+//! Executors tell fn0's own requests apart by their extensions, like `ScheduledEvent`.
+
+use crate::{Request, Response, ScheduledEvent, execute::response};
+use bytes::Bytes;
+use hyper::StatusCode;
+
+const PATH_PREFIX: &str = "/__fn0/";
+const HEADER_PREFIX: &str = "x-fn0-";
+
+/// 404 for fn0's own paths.
+pub(crate) fn reject_client_request(request: &Request) -> Option<Response> {
+    request
+        .uri()
+        .path()
+        .starts_with(PATH_PREFIX)
+        .then(|| response(StatusCode::NOT_FOUND, Bytes::from("Not Found")))
+}
+
+/// Drops fn0's headers and extensions.
+pub(crate) fn strip_client_request(mut request: Request) -> Request {
+    let names = request
+        .headers()
+        .keys()
+        .filter(|name| name.as_str().starts_with(HEADER_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in names {
+        request.headers_mut().remove(name);
+    }
+    request.extensions_mut().remove::<ScheduledEvent>();
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::request;
+
+    #[test]
+    fn test_client_request() {
+        assert_eq!(
+            reject_client_request(&request("http://code/__fn0/scheduled"))
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        let event = ScheduledEvent {
+            schedule: "nightly".to_string(),
+            scheduled_at: 0,
+        };
+        assert!(reject_client_request(&event.into_request()).is_some());
+
+        let mut client_request = request("http://code/__fn0");
+        client_request
+            .headers_mut()
+            .insert("x-fn0-schedule", "nightly".parse().unwrap());
+        client_request
+            .headers_mut()
+            .insert("x-other", "1".parse().unwrap());
+        client_request.extensions_mut().insert(ScheduledEvent {
+            schedule: "nightly".to_string(),
+            scheduled_at: 0,
+        });
+        assert!(reject_client_request(&client_request).is_none());
+        let client_request = strip_client_request(client_request);
+        assert!(!client_request.headers().contains_key("x-fn0-schedule"));
+        assert_eq!(client_request.headers()["x-other"], "1");
+        assert!(
+            client_request
+                .extensions()
+                .get::<ScheduledEvent>()
+                .is_none()
+        );
+    }
+}
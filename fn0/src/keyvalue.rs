@@ -0,0 +1,327 @@
+//! wasi:keyvalue for components, stored in the doc-db `docs` table.
+//!
+//! A bucket of a code lives under `pk = "kv/<code_id length>/<code_id>/<identifier>"`,
+//! so codes can't see each other's, even with `/` in their id.
+
+use crate::{KeyValueLimits, telemetry};
+use anyhow::Result;
+use doc_db::DocDb;
+use wasmtime::component::{HasData, Linker, Resource, ResourceTable, ResourceTableError};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "../wasmtime/crates/wasi-keyvalue/wit",
+        world: "wasi:keyvalue/imports",
+        imports: { default: async | trappable },
+        with: {
+            "wasi:keyvalue/store.bucket": super::Bucket,
+        },
+        trappable_error_type: {
+            "wasi:keyvalue/store.error" => super::Error,
+        },
+    });
+}
+
+use bindings::wasi::keyvalue::{atomics, batch, store};
+
+/// Keys per `list-keys` page.
+const LIST_KEYS_PAGE: u64 = 1000;
+
+pub enum Error {
+    NoSuchStore,
+    /// Recorded with the code_id, as the guest only sees a generic error.
+    Storage(doc_db::Error),
+    Other(String),
+}
+
+impl From<ResourceTableError> for Error {
+    fn from(err: ResourceTableError) -> Self {
+        Self::Other(err.to_string())
+    }
+}
+
+impl From<doc_db::Error> for Error {
+    fn from(err: doc_db::Error) -> Self {
+        Self::Storage(err)
+    }
+}
+
+pub struct Bucket {
+    pk: Vec<u8>,
+}
+
+/// Per-invocation wasi:keyvalue state.
+pub(crate) struct KeyValueCtx {
+    /// No bucket can be opened without it.
+    db: Option<DocDb>,
+    code_id: String,
+    limits: KeyValueLimits,
+    operations: usize,
+}
+
+impl KeyValueCtx {
+    pub(crate) fn new(db: Option<DocDb>, code_id: String, limits: KeyValueLimits) -> Self {
+        Self {
+            db,
+            code_id,
+            limits,
+            operations: 0,
+        }
+    }
+
+    /// Batch calls count each key.
+    fn start(&mut self, operations: usize) -> Result<&DocDb, Error> {
+        self.operations += operations;
+        if self.operations > self.limits.operations {
+            telemetry::keyvalue_quota_exceeded(&self.code_id, "operations");
+            return Err(Error::Other(
+                "keyvalue operations limit exceeded".to_string(),
+            ));
+        }
+        self.db.as_ref().ok_or(Error::NoSuchStore)
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), Error> {
+        if key.len() > self.limits.key_bytes {
+            telemetry::keyvalue_quota_exceeded(&self.code_id, "key_bytes");
+            return Err(Error::Other("key too large".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, value: &[u8]) -> Result<(), Error> {
+        if value.len() > self.limits.value_bytes {
+            telemetry::keyvalue_quota_exceeded(&self.code_id, "value_bytes");
+            return Err(Error::Other("value too large".to_string()));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct KeyValue<'a> {
+    ctx: &'a mut KeyValueCtx,
+    table: &'a mut ResourceTable,
+}
+
+impl<'a> KeyValue<'a> {
+    pub(crate) fn new(ctx: &'a mut KeyValueCtx, table: &'a mut ResourceTable) -> Self {
+        Self { ctx, table }
+    }
+
+    fn pk(&self, bucket: &Resource<Bucket>) -> Result<Vec<u8>, Error> {
+        Ok(self.table.get(bucket)?.pk.clone())
+    }
+}
+
+impl store::Host for KeyValue<'_> {
+    async fn open(&mut self, identifier: String) -> Result<Resource<Bucket>, Error> {
+        self.ctx.check_key(&identifier)?;
+        if self.ctx.db.is_none() {
+            return Err(Error::NoSuchStore);
+        }
+        let pk = bucket_pk(&self.ctx.code_id, &identifier);
+        Ok(self.table.push(Bucket { pk })?)
+    }
+
+    fn convert_error(&mut self, err: Error) -> Result<store::Error> {
+        match err {
+            Error::NoSuchStore => Ok(store::Error::NoSuchStore),
+            Error::Storage(err) => {
+                telemetry::keyvalue_error(&self.ctx.code_id, &err.to_string());
+                Ok(store::Error::Other("storage error".to_string()))
+            }
+            Error::Other(e) => Ok(store::Error::Other(e)),
+        }
+    }
+}
+
+impl store::HostBucket for KeyValue<'_> {
+    async fn get(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let pk = self.pk(&bucket)?;
+        self.ctx.check_key(&key)?;
+        Ok(self.ctx.start(1)?.kv_get(&pk, &key).await?)
+    }
+
+    async fn set(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        value: Vec<u8>,
+    ) -> Result<(), Error> {
+        let pk = self.pk(&bucket)?;
+        self.ctx.check_key(&key)?;
+        self.ctx.check_value(&value)?;
+        Ok(self.ctx.start(1)?.kv_set(&pk, &key, &value).await?)
+    }
+
+    async fn delete(&mut self, bucket: Resource<Bucket>, key: String) -> Result<(), Error> {
+        let pk = self.pk(&bucket)?;
+        self.ctx.check_key(&key)?;
+        Ok(self.ctx.start(1)?.kv_delete(&pk, &key).await?)
+    }
+
+    async fn exists(&mut self, bucket: Resource<Bucket>, key: String) -> Result<bool, Error> {
+        let pk = self.pk(&bucket)?;
+        self.ctx.check_key(&key)?;
+        Ok(self.ctx.start(1)?.kv_get(&pk, &key).await?.is_some())
+    }
+
+    /// `cursor` is the offset of the next key.
+    async fn list_keys(
+        &mut self,
+        bucket: Resource<Bucket>,
+        cursor: Option<u64>,
+    ) -> Result<store::KeyResponse, Error> {
+        let pk = self.pk(&bucket)?;
+        let offset = cursor.unwrap_or(0);
+        let keys = self
+            .ctx
+            .start(1)?
+            .kv_list_keys(&pk, offset, LIST_KEYS_PAGE)
+            .await?;
+        let cursor = (keys.len() as u64 == LIST_KEYS_PAGE).then_some(offset + LIST_KEYS_PAGE);
+        Ok(store::KeyResponse { keys, cursor })
+    }
+
+    async fn drop(&mut self, bucket: Resource<Bucket>) -> Result<()> {
+        self.table.delete(bucket)?;
+        Ok(())
+    }
+}
+
+impl atomics::Host for KeyValue<'_> {
+    async fn increment(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key: String,
+        delta: u64,
+    ) -> Result<u64, Error> {
+        let pk = self.pk(&bucket)?;
+        self.ctx.check_key(&key)?;
+        Ok(self.ctx.start(1)?.kv_increment(&pk, &key, delta).await?)
+    }
+}
+
+impl batch::Host for KeyValue<'_> {
+    async fn get_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<(String, Vec<u8>)>>, Error> {
+        let pk = self.pk(&bucket)?;
+        for key in &keys {
+            self.ctx.check_key(key)?;
+        }
+        let db = self.ctx.start(keys.len())?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = db.kv_get(&pk, &key).await?;
+            values.push(value.map(|value| (key, value)));
+        }
+        Ok(values)
+    }
+
+    async fn set_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        key_values: Vec<(String, Vec<u8>)>,
+    ) -> Result<(), Error> {
+        let pk = self.pk(&bucket)?;
+        for (key, value) in &key_values {
+            self.ctx.check_key(key)?;
+            self.ctx.check_value(value)?;
+        }
+        let db = self.ctx.start(key_values.len())?;
+        Ok(db.kv_set_many(&pk, &key_values).await?)
+    }
+
+    async fn delete_many(
+        &mut self,
+        bucket: Resource<Bucket>,
+        keys: Vec<String>,
+    ) -> Result<(), Error> {
+        let pk = self.pk(&bucket)?;
+        for key in &keys {
+            self.ctx.check_key(key)?;
+        }
+        let db = self.ctx.start(keys.len())?;
+        Ok(db.kv_delete_many(&pk, &keys).await?)
+    }
+}
+
+struct HasKeyValue;
+
+impl HasData for HasKeyValue {
+    type Data<'a> = KeyValue<'a>;
+}
+
+fn bucket_pk(code_id: &str, identifier: &str) -> Vec<u8> {
+    format!("kv/{}/{code_id}/{identifier}", code_id.len()).into_bytes()
+}
+
+pub(crate) fn add_to_linker<T: Send + 'static>(
+    linker: &mut Linker<T>,
+    f: fn(&mut T) -> KeyValue<'_>,
+) -> Result<()> {
+    store::add_to_linker::<_, HasKeyValue>(linker, f)?;
+    atomics::add_to_linker::<_, HasKeyValue>(linker, f)?;
+    batch::add_to_linker::<_, HasKeyValue>(linker, f)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::{Host, HostBucket};
+
+    #[tokio::test]
+    async fn test_code_isolation_and_quotas() {
+        let path = std::env::temp_dir().join(format!("fn0-keyvalue-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = DocDb::new_local(&path).await.unwrap();
+        let limits = KeyValueLimits {
+            key_bytes: 8,
+            value_bytes: 8,
+            operations: 2,
+        };
+        let mut table = ResourceTable::new();
+
+        let mut a = KeyValueCtx::new(Some(db.clone()), "a".to_string(), limits);
+        let mut kv = KeyValue::new(&mut a, &mut table);
+        let bucket = kv.open("".to_string()).await.ok().unwrap();
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert!(
+            kv.set(borrowed, "k".to_string(), b"v".to_vec())
+                .await
+                .is_ok()
+        );
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert!(kv.set(borrowed, "k".to_string(), vec![0; 9]).await.is_err());
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert!(kv.get(borrowed, "k".repeat(9)).await.is_err());
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert_eq!(
+            kv.get(borrowed, "k".to_string()).await.ok().unwrap(),
+            Some(b"v".to_vec())
+        );
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert!(kv.get(borrowed, "k".to_string()).await.is_err());
+
+        let mut b = KeyValueCtx::new(Some(db), "b".to_string(), limits);
+        let mut kv = KeyValue::new(&mut b, &mut table);
+        let bucket = kv.open("".to_string()).await.ok().unwrap();
+        let borrowed = Resource::new_borrow(bucket.rep());
+        assert_eq!(kv.get(borrowed, "k".to_string()).await.ok().unwrap(), None);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_bucket_pk() {
+        assert_ne!(bucket_pk("a/b", "c"), bucket_pk("a", "b/c"));
+    }
+}
@@ -1,72 +1,594 @@
+mod admission;
+mod artifact;
+mod cgi;
+mod cluster;
+mod cluster_manager;
+mod code_config;
 mod deployment;
 mod execute;
+mod fn0_error;
+mod gossip;
+mod guest_log;
+mod health;
+pub mod host_agent;
+pub mod host_http;
+mod http_limits;
+mod internal_request;
+mod keyvalue;
+mod list_neighbors;
+mod memory_usage;
+mod outgoing_http;
+mod pre_init;
+mod queue;
+mod scheduled;
+mod service_binding;
 pub mod telemetry;
+#[cfg(test)]
+mod test_util;
+mod tiering;
+mod trace_context;
+mod warm_up_map;
 
 use adapt_cache::AdaptCache;
+use admission::Admission;
+pub use admission::{AdmissionLimits, AdmissionStats};
 use anyhow::*;
+use artifact::ArtifactHeader;
 use bytes::Bytes;
-use deployment::*;
+pub use cluster::{Cluster, ClusterConfig, FORWARDED_HEADER};
+pub use cluster_manager::{ClusterManager, ClusterManagerConfig, Role};
+use code_config::CodeEnvCache;
+pub use code_config::{CodeConfig, SecretStore};
+pub use deployment::{
+    CodeKind, CodeLimits, CodeManifest, Deployment, DeploymentMap, KeyValueLimits,
+};
+pub use execute::ProxyCacheStats;
 use execute::*;
-pub use deployment::{CodeKind, DeploymentMap};
-use http_body_util::combinators::UnsyncBoxBody;
+pub use fn0_error::{ERROR_HEADER, ErrorKind};
+use futures::future::BoxFuture;
+use guest_log::GuestLog;
+pub use guest_log::GuestLogStream;
+pub use health::{HEALTH_PATH, Health, HealthState};
+use http_body_util::{BodyExt, combinators::UnsyncBoxBody};
+pub use http_limits::HttpLimits;
+pub use list_neighbors::{
+    CompositeListNeighbors, DnsListNeighbors, DnsRecord, FileListNeighbors, ListNeighbors,
+    StaticListNeighbors, oci::OciListNeighbors,
+};
 use measure_cpu_time::SystemClock;
-use std::string::FromUtf8Error;
+pub use outgoing_http::OutgoingHttpPolicy;
+pub use queue::{DocDbQueue, MemoryQueue, QueueBackend, QueueConsumer, QueueMessage};
+pub use scheduled::ScheduledEvent;
+use service_binding::*;
+pub use service_binding::{ServiceBindingError, ServiceBindingLimits};
+pub use ski::{Code as JsCode, IsolatePoolLimits};
+use std::{
+    string::FromUtf8Error,
+    sync::{
+        Arc, RwLock, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+use tiering::TieredWasmPre;
+pub use tiering::{CompilerTier, TierUpThreshold};
+use tokio::sync::Notify;
 use wasmtime::Engine;
-use wasmtime_wasi_http::bindings::ProxyPre;
 
 pub type Body = UnsyncBoxBody<Bytes, anyhow::Error>;
 pub type Request = hyper::Request<Body>;
 pub type Response = hyper::Response<Body>;
 
+/// Rough memory of a JS isolate with its thread. `js_cache` weighs a code by the isolates it
+/// can hold, so evicting codes also bounds the isolates of the host.
+const ISOLATE_WEIGHT: usize = 16 * 1024 * 1024;
+
+pub struct Fn0Config {
+    /// Sizes the pooling allocator's memory slots,
+    /// so it must cover the largest `CodeLimits::memory_bytes` of all codes.
+    pub max_memory_bytes: usize,
+    pub outgoing_http: OutgoingHttpPolicy,
+    pub service_binding: ServiceBindingLimits,
+    pub http_limits: HttpLimits,
+    pub tier_up: TierUpThreshold,
+    pub admission: AdmissionLimits,
+    /// Isolates kept per JS code, which `js_cache` holds as `JsCode` and weighs by `max_isolates`.
+    pub js_isolates: IsolatePoolLimits,
+    /// Backs wasi:keyvalue. Without it, components can't open a bucket.
+    pub keyvalue: Option<doc_db::DocDb>,
+    /// Needed by codes with `CodeConfig::secrets`.
+    pub secrets: Option<SecretStore>,
+    /// Puts the detail of failures, like traps and their wasm backtrace, in error responses.
+    /// For local development only, as it shows the internals of codes to clients.
+    pub debug_errors: bool,
+}
+
+impl Default for Fn0Config {
+    fn default() -> Self {
+        Self {
+            max_memory_bytes: CodeLimits::default().memory_bytes,
+            outgoing_http: Default::default(),
+            service_binding: Default::default(),
+            http_limits: Default::default(),
+            tier_up: Default::default(),
+            admission: Default::default(),
+            js_isolates: Default::default(),
+            keyvalue: None,
+            secrets: None,
+            debug_errors: false,
+        }
+    }
+}
+
 pub struct Fn0<J>
 where
-    J: AdaptCache<String, FromUtf8Error>,
+    J: AdaptCache<JsCode, FromUtf8Error>,
 {
     js_cache: J,
-    deployment_map: DeploymentMap,
+    js_isolates: IsolatePoolLimits,
+    deployment_map: RwLock<DeploymentMap>,
     wasm_executor: WasmExecutor,
+    admission: Admission,
+    is_draining: AtomicBool,
+    in_flight: Arc<AtomicU64>,
+    idle: Arc<Notify>,
+    service_binding_limits: ServiceBindingLimits,
+    http_limits: HttpLimits,
+    secret_store: Option<SecretStore>,
+    code_envs: CodeEnvCache,
+    this: Weak<Self>,
 }
 
 impl<J> Fn0<J>
 where
-    J: AdaptCache<String, FromUtf8Error>,
+    J: AdaptCache<JsCode, FromUtf8Error>,
 {
-    pub fn new<W>(wasm_proxy_cache: W, js_cache: J, deployment_map: DeploymentMap) -> Self
+    pub fn new<W>(
+        wasm_proxy_cache: W,
+        js_cache: J,
+        deployment_map: DeploymentMap,
+        config: Fn0Config,
+    ) -> Arc<Self>
     where
-        W: AdaptCache<ProxyPre<ClientState<SystemClock>>, wasmtime::Error>,
+        W: AdaptCache<TieredWasmPre<SystemClock>, wasmtime::Error>,
     {
-        Self {
-            js_cache,
-            deployment_map,
-            wasm_executor: WasmExecutor::new(wasm_proxy_cache, SystemClock),
-        }
+        // Executors hold a weak handle back to `Fn0` to run internal:// requests.
+        Arc::new_cyclic(|this: &Weak<Self>| {
+            let service_binding: Weak<dyn ServiceBinding> = this.clone();
+            Self {
+                js_cache,
+                js_isolates: config.js_isolates,
+                deployment_map: RwLock::new(deployment_map),
+                wasm_executor: WasmExecutor::new(
+                    wasm_proxy_cache,
+                    SystemClock,
+                    ExecutorConfig {
+                        max_memory_bytes: config.max_memory_bytes,
+                        outgoing_http: Arc::new(config.outgoing_http),
+                        service_binding,
+                        keyvalue: config.keyvalue,
+                        tier_up_threshold: config.tier_up,
+                        debug_errors: config.debug_errors,
+                    },
+                ),
+                admission: Admission::new(
+                    config.admission,
+                    max_instance_count(config.max_memory_bytes),
+                ),
+                is_draining: AtomicBool::new(false),
+                in_flight: Default::default(),
+                idle: Default::default(),
+                service_binding_limits: config.service_binding,
+                http_limits: config.http_limits,
+                secret_store: config.secrets,
+                code_envs: Default::default(),
+                this: this.clone(),
+            }
+        })
     }
     pub async fn run(&self, code_id: &str, request: Request) -> Result<Response> {
-        let Some(code_kind) = self.deployment_map.code_kind(code_id) else {
-            return Err(anyhow!("code_id not found"));
+        // Counted before the check, so `shutdown` waits for every request that got past it.
+        let in_flight = InFlight::new(self);
+        if self.is_draining() {
+            return Err(RunError::Draining.into());
+        }
+        if let Some(response) = self.http_limits.reject_request(&request) {
+            telemetry::request_too_large(code_id, response.status().as_u16());
+            return Ok(response);
+        }
+        if let Some(response) = internal_request::reject_client_request(&request) {
+            return Ok(response);
+        }
+        let request = internal_request::strip_client_request(request);
+        let (request, is_body_too_large) = self.http_limits.limit_request_body(request);
+        let invocation = Invocation::new(code_id, &request);
+        let response = self.run_invocation(code_id, request, invocation).await;
+        // The code failed on a body over the limit.
+        if is_body_too_large.load(Ordering::Relaxed)
+            && !response
+                .as_ref()
+                .is_ok_and(|response| response.status().is_success())
+        {
+            let response = http_limits::payload_too_large_response();
+            telemetry::request_too_large(code_id, response.status().as_u16());
+            return Ok(response);
+        }
+        let response = response?;
+        if self.http_limits.is_response_header_too_large(&response) {
+            telemetry::response_header_too_large(code_id);
+            return Ok(http_limits::response_header_too_large_response());
+        }
+        Ok(in_flight.hold_until_sent(response))
+    }
+
+    /// Runs a cron run pushed by hq, see `scheduled`.
+    pub async fn run_scheduled(&self, code_id: &str, event: ScheduledEvent) -> Result<Response> {
+        self.run_trigger(code_id, event.into_request()).await
+    }
+
+    /// Delivers batches of `consumer.queue` to `consumer.code_id` until draining, see `queue`.
+    pub async fn run_queue_consumer(
+        self: Arc<Self>,
+        backend: Arc<dyn QueueBackend>,
+        consumer: QueueConsumer,
+    ) {
+        let this = self.this.clone();
+        let code_id = consumer.code_id.clone();
+        let deliver: queue::Deliver = Arc::new(move |request| {
+            let this = this.clone();
+            let code_id = code_id.clone();
+            Box::pin(async move {
+                let this = this.upgrade().ok_or_else(|| anyhow!("fn0 is dropped"))?;
+                this.run_trigger(&code_id, request).await
+            })
+        });
+        queue::consume(backend, consumer, deliver, || self.is_draining()).await;
+    }
+
+    /// Runs a request made by fn0 itself, not by a client.
+    async fn run_trigger(&self, code_id: &str, request: Request) -> Result<Response> {
+        let in_flight = InFlight::new(self);
+        if self.is_draining() {
+            return Err(RunError::Draining.into());
+        }
+        let invocation = Invocation::new(code_id, &request);
+        let response = self.run_invocation(code_id, request, invocation).await?;
+        Ok(in_flight.hold_until_sent(response))
+    }
+
+    async fn run_invocation(
+        &self,
+        code_id: &str,
+        request: Request,
+        invocation: Invocation,
+    ) -> Result<Response> {
+        let manifest = self
+            .deployment_map
+            .read()
+            .unwrap()
+            .code_manifest(code_id)
+            .cloned();
+        let Some(manifest) = manifest else {
+            return Err(RunError::CodeNotFound.into());
+        };
+        let env = self
+            .code_envs
+            .get(
+                code_id,
+                manifest.code_version,
+                &manifest.config,
+                self.secret_store.as_ref(),
+            )
+            .await?;
+        let admitted = self
+            .admission
+            .admit(manifest.kind, code_id, manifest.limits.concurrency)
+            .await;
+        let permit = match admitted {
+            Err(rejection) => {
+                telemetry::admission_rejected(code_id, rejection.as_str());
+                return Ok(self.admission.rejected_response());
+            }
+            std::result::Result::Ok(permit) => permit,
         };
-        match code_kind {
-            CodeKind::Wasm => Ok(self.wasm_executor.run(code_id, request).await?),
+        match manifest.kind {
+            CodeKind::Wasm => Ok(self
+                .wasm_executor
+                .run(code_id, manifest.limits, env, request, invocation, permit)
+                .await?),
             CodeKind::Js => {
                 let js_code = self
                     .js_cache
                     .get(code_id, |bytes| {
-                        String::from_utf8(bytes.to_vec()).map(|str| (str, bytes.len()))
+                        let weight = bytes.len() + self.js_isolates.max_isolates * ISOLATE_WEIGHT;
+                        String::from_utf8(bytes.to_vec())
+                            .map(|str| (JsCode::new(str, self.js_isolates), weight))
                     })
                     .await
                     .map_err(|err| anyhow!("Failed to get JS code: {:?}", err))?;
-                let response = ski::run(&js_code, request).await?;
-                Ok(response)
+                let guest_log = GuestLog::new(
+                    code_id,
+                    invocation.request_id().clone(),
+                    manifest.limits.log_bytes,
+                );
+                let options = ski::RunOptions {
+                    internal_fetch: Some(self.internal_fetch(code_id, invocation)),
+                    log: Some(Arc::new(move |stream, msg| {
+                        guest_log.write(stream.into(), msg.as_bytes())
+                    })),
+                    env: env.to_map(),
+                    scheduled: request.extensions().get::<ScheduledEvent>().map(Into::into),
+                };
+                let response = js_code.run(request, options).await?;
+                // The isolate is busy until the body is sent, see `ski::IsolatePoolLimits`.
+                Ok(hold_until_sent(response, permit))
             }
         }
     }
+
+    /// Lets ski's `fetch` reach other codes through internal:// URLs.
+    fn internal_fetch(&self, code_id: &str, invocation: Invocation) -> ski::InternalFetch {
+        let this = self.this.clone();
+        let code_id = code_id.to_string();
+        Arc::new(move |request| {
+            let this = this.clone();
+            let code_id = code_id.clone();
+            let invocation = invocation.clone();
+            Box::pin(async move {
+                let this = this.upgrade().ok_or_else(|| anyhow!("fn0 is dropped"))?;
+                Ok(this.call(code_id, request, invocation).await?)
+            })
+        })
+    }
+
+    pub fn deployment_id(&self) -> u64 {
+        self.deployment_map.read().unwrap().deployment_id()
+    }
+
+    pub fn apply_deployment_updates(
+        &self,
+        deployment_id: u64,
+        code_id_and_versions: &[(u64, u64)],
+    ) -> bool {
+        let applied = self
+            .deployment_map
+            .write()
+            .unwrap()
+            .apply_deployment_updates(deployment_id, code_id_and_versions);
+        if applied {
+            for (code_id, _) in code_id_and_versions {
+                self.code_envs.invalidate(&code_id.to_string());
+            }
+        }
+        applied
+    }
+
+    pub fn instances(&self) -> u64 {
+        self.wasm_executor.instances()
+    }
+
+    pub fn health(&self) -> Health {
+        let admission = self.admission.stats();
+        let state = if self.is_draining() {
+            HealthState::Draining
+        } else if admission.queue_len > 0 {
+            HealthState::Overloaded
+        } else {
+            HealthState::Ready
+        };
+        Health {
+            status: state.as_status(),
+            state,
+            deployment_id: self.deployment_id(),
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            instances: self.instances(),
+            admission,
+            proxy_cache: self.wasm_executor.proxy_cache_stats(),
+            code_envs: self.code_envs.len(),
+        }
+    }
+
+    /// Answers `GET /health`, see `health`. `allow_detail` is for trusted callers, like members of
+    /// the cluster.
+    pub fn health_response(&self, request: &Request, allow_detail: bool) -> Option<Response> {
+        health::is_health_request(request)
+            .then(|| health::health_response(request, self.health(), allow_detail))
+    }
+
+    /// Stop accepting new requests. Running requests are not affected.
+    pub fn start_draining(&self) {
+        self.is_draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.is_draining.load(Ordering::SeqCst)
+    }
+
+    /// Stops accepting requests, waits for running ones until `deadline`,
+    /// then drops the rest, stops the executor and flushes telemetry.
+    pub async fn shutdown(&self, deadline: tokio::time::Instant) -> ShutdownReport {
+        self.start_draining();
+        let in_flight = self.in_flight.load(Ordering::SeqCst);
+
+        let _ = tokio::time::timeout_at(deadline, self.wait_idle()).await;
+        let dropped = self.in_flight.load(Ordering::SeqCst);
+        self.wasm_executor.shutdown().await;
+
+        let report = ShutdownReport {
+            finished: in_flight.saturating_sub(dropped),
+            dropped,
+        };
+        telemetry::shutdown(report.finished, report.dropped);
+        telemetry::force_flush();
+        report
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            tokio::pin!(idle);
+            idle.as_mut().enable();
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+/// Errors of `Fn0::run` that aren't failures of the host, found with `downcast_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunError {
+    /// `Fn0::start_draining` was called, so another host should take the request.
+    Draining,
+    CodeNotFound,
+}
+
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Draining => f.write_str("fn0 is draining"),
+            Self::CodeNotFound => f.write_str("code_id not found"),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Requests that were running when shutdown started and finished before the deadline.
+    pub finished: u64,
+    /// Requests still running at the deadline.
+    pub dropped: u64,
+}
+
+/// Counts a request in `Fn0::in_flight` while alive.
+struct InFlight {
+    in_flight: Arc<AtomicU64>,
+    idle: Arc<Notify>,
+}
+
+impl InFlight {
+    fn new<J>(fn0: &Fn0<J>) -> Self
+    where
+        J: AdaptCache<JsCode, FromUtf8Error>,
+    {
+        fn0.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self {
+            in_flight: fn0.in_flight.clone(),
+            idle: fn0.idle.clone(),
+        }
+    }
+
+    /// Keeps counting the request until its response body is sent or dropped.
+    fn hold_until_sent(self, response: Response) -> Response {
+        hold_until_sent(response, self)
+    }
+}
+
+/// Drops `guard` with the response body, once it's sent or the client is gone.
+fn hold_until_sent<G: Send + 'static>(response: Response, guard: G) -> Response {
+    response.map(|body| {
+        body.map_frame(move |frame| {
+            let _ = &guard;
+            frame
+        })
+        .boxed_unsync()
+    })
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+impl<J> ServiceBinding for Fn0<J>
+where
+    J: AdaptCache<JsCode, FromUtf8Error>,
+{
+    fn call(
+        self: Arc<Self>,
+        caller_code_id: String,
+        request: Request,
+        invocation: Invocation,
+    ) -> BoxFuture<'static, Result<Response, ServiceBindingError>> {
+        Box::pin(async move {
+            let callee_code_id = request.uri().host().unwrap_or_default().to_string();
+            let result = async {
+                let is_same_deployment = self
+                    .deployment_map
+                    .read()
+                    .unwrap()
+                    .is_code_in_same_deployment(&caller_code_id, &callee_code_id);
+                match is_same_deployment {
+                    None => return Err(ServiceBindingError::UnknownCode),
+                    Some(false) => return Err(ServiceBindingError::NotInSameDeployment),
+                    Some(true) => {}
+                }
+                if let Some(response) = internal_request::reject_client_request(&request) {
+                    return std::result::Result::Ok(response);
+                }
+                let request = internal_request::strip_client_request(request);
+                let invocation = invocation.enter(&callee_code_id, self.service_binding_limits)?;
+                self.run_invocation(&callee_code_id, request, invocation)
+                    .await
+                    .map_err(ServiceBindingError::Run)
+            }
+            .await;
+            let outcome = match &result {
+                Err(err) => err.outcome(),
+                _ => "ok",
+            };
+            telemetry::service_binding(&caller_code_id, &callee_code_id, outcome);
+            result
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompileOptions {
+    /// Export to run under Wizer before compiling, like `wizer-initialize`.
+    /// Its memory is snapshotted, so instances start already initialized.
+    /// Compiling fails if it runs for more than 10 seconds.
+    pub pre_initialize: Option<String>,
 }
 
 pub fn compile(wasm_bytes: &[u8]) -> Result<Vec<u8>> {
-    let engine = Engine::new(&engine_config())?;
+    precompile(wasm_bytes, ArtifactHeader::default())
+}
+
+pub async fn compile_with_options(wasm_bytes: &[u8], options: &CompileOptions) -> Result<Vec<u8>> {
+    let Some(init_func) = &options.pre_initialize else {
+        return compile(wasm_bytes);
+    };
+    let wasm_bytes =
+        pre_init::pre_initialize(wasm_bytes, init_func, pre_init::INIT_TIMEOUT).await?;
+    precompile(
+        &wasm_bytes,
+        ArtifactHeader {
+            pre_initialized: true,
+        },
+    )
+}
+
+/// Builds the baseline tier and embeds the source for the optimized tier, see `tiering`.
+fn precompile(wasm_bytes: &[u8], header: ArtifactHeader) -> Result<Vec<u8>> {
+    precompile_with(CompilerTier::Baseline, wasm_bytes)
+        .map(|precompiled| artifact::encode(header, precompiled, Some(wasm_bytes)))
+        // Winch doesn't support every proposal, like tail calls.
+        .or_else(|_| {
+            let precompiled = precompile_with(CompilerTier::Optimized, wasm_bytes)?;
+            Ok(artifact::encode(header, precompiled, None))
+        })
+}
+
+fn precompile_with(tier: CompilerTier, wasm_bytes: &[u8]) -> Result<Vec<u8>> {
+    let engine = Engine::new(&engine_config(CodeLimits::default().memory_bytes, tier))?;
 
-    // Check if it's a component by looking for component-specific markers
-    if wasm_bytes.len() > 8 && wasm_bytes[4..8] == [0x0d, 0x00, 0x01, 0x00] {
+    if is_component(wasm_bytes) {
         // This is a WebAssembly Component
         engine.precompile_component(wasm_bytes)
     } else {
@@ -74,3 +596,148 @@ pub fn compile(wasm_bytes: &[u8]) -> Result<Vec<u8>> {
         engine.precompile_module(wasm_bytes)
     }
 }
+
+// Check if it's a component by looking for component-specific markers
+fn is_component(wasm_bytes: &[u8]) -> bool {
+    wasm_bytes.len() > 8 && wasm_bytes[4..8] == [0x0d, 0x00, 0x01, 0x00]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MemoryCache, component, eventually, request};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    /// A code whose handler loops until it runs out of `limits`.
+    fn looping_code(limits: CodeLimits) -> Arc<Fn0<MemoryCache<JsCode>>> {
+        let wasm_cache = MemoryCache::default();
+        wasm_cache.insert("code", compile(&component("(loop br 0)", "")).unwrap());
+        let mut deployment_map = DeploymentMap::new();
+        deployment_map.register_code_manifest(CodeManifest {
+            kind: CodeKind::Wasm,
+            code_id: "code".to_string(),
+            code_version: 0,
+            limits,
+            config: Default::default(),
+        });
+        Fn0::new(
+            wasm_cache,
+            MemoryCache::default(),
+            deployment_map,
+            Default::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_response_bodies() {
+        let fn0 = looping_code(CodeLimits {
+            cpu_time: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let response = fn0.run("code", request("http://code/")).await.unwrap();
+        assert_eq!(fn0.health().in_flight, 1);
+
+        let shutdown = tokio::spawn({
+            let fn0 = fn0.clone();
+            async move { fn0.shutdown(Instant::now() + Duration::from_secs(60)).await }
+        });
+        eventually(|| std::future::ready(fn0.is_draining())).await;
+        assert!(fn0.run("code", request("http://code/")).await.is_err());
+
+        drop(response);
+        let report = shutdown.await.unwrap();
+        assert_eq!(
+            report,
+            ShutdownReport {
+                finished: 1,
+                dropped: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_guests_at_the_deadline() {
+        let fn0 = looping_code(CodeLimits {
+            cpu_time: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let run = tokio::spawn({
+            let fn0 = fn0.clone();
+            async move { fn0.run("code", request("http://code/")).await }
+        });
+        eventually(|| std::future::ready(fn0.instances() == 1)).await;
+
+        let report = fn0
+            .shutdown(Instant::now() + Duration::from_millis(100))
+            .await;
+        assert_eq!(
+            report,
+            ShutdownReport {
+                finished: 0,
+                dropped: 1
+            }
+        );
+        assert!(run.await.unwrap().is_err());
+        // The guest task is aborted with its job, which frees its instance.
+        eventually(|| {
+            let admission = fn0.health().admission;
+            std::future::ready(admission.available_instances == admission.max_instances)
+        })
+        .await;
+        assert_eq!(fn0.instances(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_body_over_the_limit() {
+        let wasm =
+            wat::parse_str(r#"(module (memory (export "memory") 1) (func (export "_start")))"#)
+                .unwrap();
+        let wasm_cache = MemoryCache::default();
+        wasm_cache.insert("code", compile(&wasm).unwrap());
+        let mut deployment_map = DeploymentMap::new();
+        deployment_map.register_code_manifest(CodeManifest {
+            kind: CodeKind::Wasm,
+            code_id: "code".to_string(),
+            code_version: 0,
+            limits: Default::default(),
+            config: Default::default(),
+        });
+        let fn0 = Fn0::new(
+            wasm_cache,
+            MemoryCache::default(),
+            deployment_map,
+            Fn0Config {
+                http_limits: HttpLimits {
+                    max_request_body_bytes: 10,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+
+        // A chunked body has no content-length to reject up front.
+        let request = hyper::Request::builder()
+            .uri("http://code/")
+            .body(Body::new(
+                http_body_util::Full::new(Bytes::from(vec![0; 20])).map_err(|never| match never {}),
+            ))
+            .unwrap();
+        let response = fn0.run("code", request).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_compile_records_pre_initialization() {
+        let wasm = wat::parse_str(r#"(module (func (export "init")))"#).unwrap();
+        let header = |bytes: &[u8]| artifact::decode(bytes).unwrap().header;
+        assert!(!header(&compile(&wasm).unwrap()).pre_initialized);
+
+        let options = CompileOptions {
+            pre_initialize: Some("init".to_string()),
+        };
+        let bytes = compile_with_options(&wasm, &options).await.unwrap();
+        assert!(header(&bytes).pre_initialized);
+    }
+}
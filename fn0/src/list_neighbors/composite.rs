@@ -0,0 +1,116 @@
+use super::*;
+use crate::telemetry;
+use futures::future::BoxFuture;
+
+/// Merges the neighbors of several sources, in the order they were added, without duplicates.
+///
+/// A failing source is reported and skipped, so a missing seed file doesn't stop
+/// bootstrapping from DNS. It's an error only when every source fails.
+#[derive(Default)]
+pub struct CompositeListNeighbors {
+    sources: Vec<(&'static str, Box<dyn DynListNeighbors>)>,
+}
+
+impl CompositeListNeighbors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `name` tells the source apart in telemetry.
+    pub fn with(
+        mut self,
+        name: &'static str,
+        source: impl ListNeighbors + Send + Sync + 'static,
+    ) -> Self {
+        self.sources.push((name, Box::new(source)));
+        self
+    }
+}
+
+impl ListNeighbors for CompositeListNeighbors {
+    async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error> {
+        let results = futures::future::join_all(
+            self.sources
+                .iter()
+                .map(|(_, source)| source.dyn_list_neighbors()),
+        )
+        .await;
+
+        let mut neighbors = vec![];
+        let mut last_err = None;
+        for ((name, _), result) in self.sources.iter().zip(results) {
+            match result {
+                Ok(ips) => {
+                    for ip in ips {
+                        if !neighbors.contains(&ip) {
+                            neighbors.push(ip);
+                        }
+                    }
+                }
+                Err(err) => {
+                    telemetry::list_neighbors_error(name, &format!("{err:?}"));
+                    last_err = Some(err.context(format!("{name} neighbors")));
+                }
+            }
+        }
+
+        match last_err {
+            Some(err) if neighbors.is_empty() && !self.sources.is_empty() => Err(err),
+            _ => Ok(neighbors),
+        }
+    }
+}
+
+/// `ListNeighbors` isn't object safe, so sources are boxed through this.
+trait DynListNeighbors: Send + Sync {
+    fn dyn_list_neighbors(&self) -> BoxFuture<'_, Result<Vec<IpAddr>, anyhow::Error>>;
+}
+
+impl<T: ListNeighbors + Send + Sync> DynListNeighbors for T {
+    fn dyn_list_neighbors(&self) -> BoxFuture<'_, Result<Vec<IpAddr>, anyhow::Error>> {
+        Box::pin(self.list_neighbors())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Failing;
+
+    impl ListNeighbors for Failing {
+        async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error> {
+            Err(anyhow::anyhow!("unreachable"))
+        }
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_merges_sources_and_skips_failures() {
+        let composite = CompositeListNeighbors::new()
+            .with(
+                "seeds",
+                StaticListNeighbors::new(ips(&["10.0.0.1", "10.0.0.2"])),
+            )
+            .with("broken", Failing)
+            .with(
+                "more",
+                StaticListNeighbors::new(ips(&["10.0.0.2", "10.0.0.3"])),
+            );
+        assert_eq!(
+            composite.list_neighbors().await.unwrap(),
+            ips(&["10.0.0.1", "10.0.0.2", "10.0.0.3"])
+        );
+
+        let composite = CompositeListNeighbors::new()
+            .with("broken", Failing)
+            .with("empty", StaticListNeighbors::default());
+        assert!(composite.list_neighbors().await.is_err());
+
+        let composite = CompositeListNeighbors::new();
+        assert!(composite.list_neighbors().await.unwrap().is_empty());
+    }
+}
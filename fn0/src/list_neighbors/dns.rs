@@ -0,0 +1,196 @@
+use super::*;
+use anyhow::Context;
+use hickory_resolver::{
+    TokioAsyncResolver,
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
+};
+use std::net::SocketAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsRecord {
+    /// A and AAAA records of the name, like a headless service.
+    Ip,
+    /// SRV records of the name, like `_fn0._udp.example.com`, with their targets resolved.
+    /// Ports are ignored, as every host uses the same ones.
+    Srv,
+}
+
+/// Resolves neighbors from DNS on every call. Caching follows the records' ttl.
+pub struct DnsListNeighbors {
+    name: String,
+    record: DnsRecord,
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsListNeighbors {
+    /// Uses the name servers of the system, like /etc/resolv.conf.
+    pub fn new(name: impl Into<String>, record: DnsRecord) -> Result<Self, anyhow::Error> {
+        let (config, options) = read_system_conf()?;
+        Ok(Self::with_config(name, record, config, options))
+    }
+
+    pub fn with_name_servers(
+        name: impl Into<String>,
+        record: DnsRecord,
+        name_servers: &[SocketAddr],
+    ) -> Self {
+        let mut config = ResolverConfig::new();
+        for name_server in name_servers {
+            config.add_name_server(NameServerConfig::new(*name_server, Protocol::Udp));
+        }
+        Self::with_config(name, record, config, ResolverOpts::default())
+    }
+
+    fn with_config(
+        name: impl Into<String>,
+        record: DnsRecord,
+        config: ResolverConfig,
+        mut options: ResolverOpts,
+    ) -> Self {
+        // The default stops at A records when there are some.
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Self {
+            name: name.into(),
+            record,
+            resolver: TokioAsyncResolver::tokio(config, options),
+        }
+    }
+
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>, anyhow::Error> {
+        let lookup = self
+            .resolver
+            .lookup_ip(name)
+            .await
+            .with_context(|| format!("Failed to resolve {name}"))?;
+        Ok(lookup.iter().collect())
+    }
+}
+
+impl ListNeighbors for DnsListNeighbors {
+    async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error> {
+        match self.record {
+            DnsRecord::Ip => self.lookup_ip(&self.name).await,
+            DnsRecord::Srv => {
+                let lookup = self
+                    .resolver
+                    .srv_lookup(self.name.as_str())
+                    .await
+                    .with_context(|| format!("Failed to resolve SRV {}", self.name))?;
+
+                // Servers usually put the targets' addresses in the additional section.
+                let mut neighbors: Vec<IpAddr> = lookup.ip_iter().collect();
+                if neighbors.is_empty() {
+                    let targets = lookup.iter().map(|srv| srv.target().to_string());
+                    let ips = futures::future::try_join_all(
+                        targets.map(async |target| self.lookup_ip(&target).await),
+                    )
+                    .await?;
+                    neighbors = ips.into_iter().flatten().collect();
+                }
+                neighbors.sort_unstable();
+                neighbors.dedup();
+                Ok(neighbors)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{
+            Name, RData, Record, RecordType,
+            rdata::{A, AAAA, SRV},
+        },
+    };
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::net::UdpSocket;
+
+    /// Answers queries for `workers.test.` and `_fn0._udp.test.`.
+    /// The SRV answer has no additional section, to check that targets are resolved.
+    async fn start_dns_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_op_code(request.op_code())
+                    .set_recursion_desired(request.recursion_desired())
+                    .set_recursion_available(true)
+                    .set_authoritative(true)
+                    .add_queries(request.queries().to_vec());
+
+                let query = &request.queries()[0];
+                let name = query.name().clone();
+                let answers: Vec<RData> = match (name.to_ascii().as_str(), query.query_type()) {
+                    ("workers.test.", RecordType::A) => vec![
+                        RData::A(A(Ipv4Addr::new(10, 0, 0, 1))),
+                        RData::A(A(Ipv4Addr::new(10, 0, 0, 2))),
+                    ],
+                    ("workers.test.", RecordType::AAAA) => {
+                        vec![RData::AAAA(AAAA(Ipv6Addr::new(
+                            0xfd00, 0, 0, 0, 0, 0, 0, 3,
+                        )))]
+                    }
+                    ("_fn0._udp.test.", RecordType::SRV) => vec![
+                        RData::SRV(SRV::new(0, 0, 7946, Name::from_ascii("a.test.").unwrap())),
+                        RData::SRV(SRV::new(0, 0, 7946, Name::from_ascii("b.test.").unwrap())),
+                    ],
+                    ("a.test.", RecordType::A) => vec![RData::A(A(Ipv4Addr::new(10, 0, 1, 1)))],
+                    ("b.test.", RecordType::A) => vec![RData::A(A(Ipv4Addr::new(10, 0, 1, 2)))],
+                    (_, RecordType::A | RecordType::AAAA | RecordType::SRV) => vec![],
+                    _ => {
+                        response.set_response_code(ResponseCode::NotImp);
+                        vec![]
+                    }
+                };
+                response.add_answers(
+                    answers
+                        .into_iter()
+                        .map(|rdata| Record::from_rdata(name.clone(), 60, rdata)),
+                );
+                socket
+                    .send_to(&response.to_vec().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_resolves_ip_and_srv_records() {
+        let dns_server = start_dns_server().await;
+
+        let source =
+            DnsListNeighbors::with_name_servers("workers.test.", DnsRecord::Ip, &[dns_server]);
+        let mut neighbors = source.list_neighbors().await.unwrap();
+        neighbors.sort();
+        assert_eq!(
+            neighbors,
+            [
+                IpAddr::from([10, 0, 0, 1]),
+                IpAddr::from([10, 0, 0, 2]),
+                "fd00::3".parse().unwrap()
+            ]
+        );
+
+        let source =
+            DnsListNeighbors::with_name_servers("_fn0._udp.test.", DnsRecord::Srv, &[dns_server]);
+        let mut neighbors = source.list_neighbors().await.unwrap();
+        neighbors.sort();
+        assert_eq!(
+            neighbors,
+            [IpAddr::from([10, 0, 1, 1]), IpAddr::from([10, 0, 1, 2])]
+        );
+    }
+}
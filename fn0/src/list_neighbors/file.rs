@@ -0,0 +1,102 @@
+use super::*;
+use anyhow::Context;
+use std::{path::PathBuf, sync::Mutex, time::SystemTime};
+
+/// Reads neighbors from a file with an ip per line. `#` starts a comment.
+///
+/// The file is parsed again only when its modification time or length changes,
+/// so an operator or a config agent can rewrite it while the host runs.
+pub struct FileListNeighbors {
+    path: PathBuf,
+    loaded: Mutex<Option<Loaded>>,
+}
+
+struct Loaded {
+    version: (SystemTime, u64),
+    neighbors: Vec<IpAddr>,
+}
+
+impl FileListNeighbors {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            loaded: Mutex::new(None),
+        }
+    }
+}
+
+impl ListNeighbors for FileListNeighbors {
+    async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error> {
+        let metadata = tokio::fs::metadata(&self.path)
+            .await
+            .with_context(|| format!("Failed to stat {}", self.path.display()))?;
+        let version = (metadata.modified()?, metadata.len());
+
+        if let Some(loaded) = self.loaded.lock().unwrap().as_ref()
+            && loaded.version == version
+        {
+            return Ok(loaded.neighbors.clone());
+        }
+
+        let text = tokio::fs::read_to_string(&self.path)
+            .await
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        let neighbors = parse(&text)?;
+        *self.loaded.lock().unwrap() = Some(Loaded {
+            version,
+            neighbors: neighbors.clone(),
+        });
+        Ok(neighbors)
+    }
+}
+
+fn parse(text: &str) -> Result<Vec<IpAddr>, anyhow::Error> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some((index, line))
+        })
+        .map(|(index, line)| {
+            line.parse()
+                .with_context(|| format!("Invalid ip {line:?} at line {}", index + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reloads_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("fn0-neighbors-{}", std::process::id()));
+        std::fs::write(&path, "# seeds\n10.0.0.1\n\n10.0.0.2 # rack 2\n").unwrap();
+        let source = FileListNeighbors::new(&path);
+
+        assert_eq!(
+            source.list_neighbors().await.unwrap(),
+            [
+                "10.0.0.1".parse::<IpAddr>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ]
+        );
+
+        std::fs::write(&path, "10.0.0.3\n::1\n10.0.0.4\n").unwrap();
+        assert_eq!(
+            source.list_neighbors().await.unwrap(),
+            [
+                "10.0.0.3".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap(),
+                "10.0.0.4".parse().unwrap()
+            ]
+        );
+
+        std::fs::write(&path, "10.0.0.5\nnot-an-ip\n").unwrap();
+        let err = source.list_neighbors().await.unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err}");
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(source.list_neighbors().await.is_err());
+    }
+}
@@ -1,7 +1,20 @@
-use std::net::IpAddr;
+//! Sources of neighbor hosts to bootstrap the cluster from.
+//!
+//! `CompositeListNeighbors` merges several of them, like a static seed list with DNS,
+//! so the same bootstrap works on OCI, on bare metal and in tests.
 
+mod composite;
+mod dns;
+mod file;
 pub mod oci;
+mod static_list;
+
+pub use composite::CompositeListNeighbors;
+pub use dns::{DnsListNeighbors, DnsRecord};
+pub use file::FileListNeighbors;
+pub use static_list::StaticListNeighbors;
+use std::net::IpAddr;
 
 pub trait ListNeighbors {
-    async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error>;
+    fn list_neighbors(&self) -> impl Future<Output = Result<Vec<IpAddr>, anyhow::Error>> + Send;
 }
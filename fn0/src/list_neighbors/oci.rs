@@ -1,18 +1,18 @@
 use super::*;
-use oci_rust_sdk::virtual_network::{Lifetime, ListPublicIpsRequest, Scope};
+use oci_rust_sdk::core::{
+    CoreClient, ListPublicIpsRequest, ListPublicIpsRequestLifetime, ListPublicIpsRequestRequired,
+    ListPublicIpsRequestScope,
+};
 use sonic_rs::JsonValueTrait;
 use std::{str::FromStr, sync::Arc};
 
 pub struct OciListNeighbors {
     worker_port: u16,
-    oci_client: Arc<dyn oci_rust_sdk::virtual_network::VirtualNetwork>,
+    oci_client: Arc<CoreClient>,
 }
 
 impl OciListNeighbors {
-    pub fn new(
-        worker_port: u16,
-        oci_client: Arc<dyn oci_rust_sdk::virtual_network::VirtualNetwork>,
-    ) -> Self {
+    pub fn new(worker_port: u16, oci_client: Arc<CoreClient>) -> Self {
         Self {
             worker_port,
             oci_client,
@@ -29,23 +29,19 @@ impl OciListNeighbors {
         loop {
             let result = self
                 .oci_client
-                .list_public_ips(ListPublicIpsRequest {
-                    scope: Scope::AvailabilityDomain,
-                    compartment_id: compartment_id.clone(),
-                    limit: None,
-                    page: next_page,
-                    availability_domain: None,
-                    lifetime: Some(Lifetime::Ephemeral),
-                    public_ip_pool_id: None,
-                })
+                .list_public_ips(
+                    ListPublicIpsRequest::new(ListPublicIpsRequestRequired {
+                        scope: ListPublicIpsRequestScope::AvailabilityDomain,
+                        compartment_id: compartment_id.clone(),
+                    })
+                    .set_page(next_page)
+                    .set_lifetime(Some(ListPublicIpsRequestLifetime::Ephemeral)),
+                )
                 .await?;
 
-            ips.extend(
-                result
-                    .items
-                    .into_iter()
-                    .map(|ip| IpAddr::from_str(&ip.ip_address).unwrap()),
-            );
+            for ip in result.items.into_iter().filter_map(|ip| ip.ip_address) {
+                ips.push(IpAddr::from_str(&ip)?);
+            }
             next_page = result.opc_next_page;
             if next_page.is_none() {
                 break;
@@ -63,11 +59,7 @@ impl ListNeighbors for OciListNeighbors {
 
         let worker_port = self.worker_port;
         let futures = public_ips.into_iter().map(move |ip| async move {
-            let is_worker = reqwest::get(format!("http://{ip}:{worker_port}/role"))
-                .await?
-                .text()
-                .await?
-                == "worker";
+            let is_worker = check_role_worker(ip, worker_port).await?;
             Ok::<Option<IpAddr>, anyhow::Error>(is_worker.then_some(ip))
         });
         let neighbors = futures::future::try_join_all(futures)
@@ -0,0 +1,21 @@
+use super::*;
+
+/// A fixed seed list, like one given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct StaticListNeighbors {
+    neighbors: Vec<IpAddr>,
+}
+
+impl StaticListNeighbors {
+    pub fn new(neighbors: impl IntoIterator<Item = IpAddr>) -> Self {
+        Self {
+            neighbors: neighbors.into_iter().collect(),
+        }
+    }
+}
+
+impl ListNeighbors for StaticListNeighbors {
+    async fn list_neighbors(&self) -> Result<Vec<IpAddr>, anyhow::Error> {
+        Ok(self.neighbors.clone())
+    }
+}
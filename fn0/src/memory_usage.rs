@@ -0,0 +1,93 @@
+//! Linear memory and table growth of one invocation, for `ResourceLimiter` of the store states.
+//!
+//! Peaks are summed over all memories and tables of the instance, and recorded when the store
+//! is dropped. They are what `ScaleConfig::instances_per_gb` should be tuned from.
+
+use crate::{telemetry, trace_context};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use tracing::Span;
+
+pub(crate) struct MemoryUsage {
+    code_id: String,
+    span: Span,
+    /// Per memory, see `CodeLimits::memory_bytes`.
+    limit_bytes: usize,
+    memory_bytes: usize,
+    peak_memory_bytes: usize,
+    table_elements: usize,
+    peak_table_elements: usize,
+    /// Shared, as the store may be gone by the time the response is made.
+    is_denied: Arc<AtomicBool>,
+}
+
+impl MemoryUsage {
+    pub(crate) fn new(code_id: String, span: Span, limit_bytes: usize) -> Self {
+        Self {
+            code_id,
+            span,
+            limit_bytes,
+            memory_bytes: 0,
+            peak_memory_bytes: 0,
+            table_elements: 0,
+            peak_table_elements: 0,
+            is_denied: Default::default(),
+        }
+    }
+
+    /// Set once a memory was denied growth. The guest sees `memory.grow` fail, and usually traps.
+    pub(crate) fn is_denied(&self) -> Arc<AtomicBool> {
+        self.is_denied.clone()
+    }
+
+    pub(crate) fn memory_growing(&mut self, current: usize, desired: usize) -> bool {
+        if desired > self.limit_bytes {
+            if !self.is_denied.swap(true, Ordering::Relaxed) {
+                telemetry::memory_growth_denied(&self.code_id);
+            }
+            return false;
+        }
+        self.memory_bytes += desired - current;
+        self.peak_memory_bytes = self.peak_memory_bytes.max(self.memory_bytes);
+        true
+    }
+
+    pub(crate) fn table_growing(&mut self, current: usize, desired: usize) -> bool {
+        self.table_elements += desired - current;
+        self.peak_table_elements = self.peak_table_elements.max(self.table_elements);
+        true
+    }
+}
+
+impl Drop for MemoryUsage {
+    fn drop(&mut self) {
+        telemetry::peak_memory(&self.code_id, self.peak_memory_bytes);
+        telemetry::peak_table_elements(&self.code_id, self.peak_table_elements);
+        trace_context::record_peak_memory(&self.span, self.peak_memory_bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peak_over_memories() {
+        let mut usage = MemoryUsage::new("code".to_string(), Span::none(), 100);
+        assert!(usage.memory_growing(0, 60));
+        assert!(usage.memory_growing(0, 30));
+        assert!(usage.memory_growing(30, 50));
+        assert_eq!(usage.peak_memory_bytes, 110);
+        assert!(!usage.is_denied().load(Ordering::Relaxed));
+
+        assert!(!usage.memory_growing(60, 120));
+        assert!(usage.is_denied().load(Ordering::Relaxed));
+        assert_eq!(usage.peak_memory_bytes, 110);
+
+        assert!(usage.table_growing(0, 10));
+        assert!(usage.table_growing(10, 20));
+        assert_eq!(usage.peak_table_elements, 20);
+    }
+}
@@ -0,0 +1,388 @@
+use http_body_util::BodyExt;
+use hyper::{client::conn::http1::SendRequest, http::uri::PathAndQuery};
+use rustls::pki_types::ServerName;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::TlsConnector;
+use wasmtime_wasi::runtime::AbortOnDropJoinHandle;
+use wasmtime_wasi_http::{
+    bindings::http::types::{DnsErrorPayload, ErrorCode},
+    body::HyperOutgoingBody,
+    hyper_request_error,
+    io::TokioIo,
+    types::{IncomingResponse, OutgoingRequestConfig, default_send_request_handler},
+};
+
+/// Where and how wasm guests may send outgoing requests.
+/// The per-invocation count is limited by `CodeLimits::subrequests`.
+#[derive(Clone, Debug)]
+pub struct OutgoingHttpPolicy {
+    /// If not empty, only these hosts are allowed.
+    /// An entry matches the host itself and its subdomains.
+    pub allow_hosts: Vec<String>,
+    /// Checked after `allow_hosts`, with the same matching.
+    pub deny_hosts: Vec<String>,
+    /// Allow loopback, private, link-local (including cloud metadata) and other non-global addresses.
+    pub allow_private_ips: bool,
+    /// Upper bound of the guest's connect timeout.
+    pub connect_timeout: Duration,
+    /// Upper bound of the guest's first byte timeout.
+    pub first_byte_timeout: Duration,
+}
+
+impl Default for OutgoingHttpPolicy {
+    fn default() -> Self {
+        Self {
+            allow_hosts: vec![],
+            deny_hosts: vec![],
+            allow_private_ips: false,
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl OutgoingHttpPolicy {
+    pub fn is_host_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if !self.allow_hosts.is_empty()
+            && !self
+                .allow_hosts
+                .iter()
+                .any(|entry| host_matches(&host, entry))
+        {
+            return false;
+        }
+        !self
+            .deny_hosts
+            .iter()
+            .any(|entry| host_matches(&host, entry))
+    }
+
+    pub(crate) fn apply_timeouts(&self, config: &mut OutgoingRequestConfig) {
+        config.connect_timeout = config.connect_timeout.min(self.connect_timeout);
+        config.first_byte_timeout = config.first_byte_timeout.min(self.first_byte_timeout);
+    }
+}
+
+fn host_matches(host: &str, entry: &str) -> bool {
+    let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+    host == entry
+        || host
+            .strip_suffix(entry.as_str())
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Resolves the host by itself to refuse non-global addresses, then connects to the addresses
+/// it checked, so a DNS answer that changes in between can't send the request elsewhere.
+pub(crate) async fn send_request(
+    policy: Arc<OutgoingHttpPolicy>,
+    request: hyper::Request<HyperOutgoingBody>,
+    config: OutgoingRequestConfig,
+) -> Result<IncomingResponse, ErrorCode> {
+    if policy.allow_private_ips {
+        return default_send_request_handler(request, config).await;
+    }
+    let Some(host) = request.uri().host() else {
+        return Err(ErrorCode::HttpRequestUriInvalid);
+    };
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if config.use_tls { 443 } else { 80 });
+
+    let addrs = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|_| dns_error("address not available"))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(dns_error("address not available"));
+    }
+    if addrs.iter().any(|addr| !is_global_ip(addr.ip())) {
+        return Err(ErrorCode::DestinationIpProhibited);
+    }
+
+    send_request_to(&addrs, &host, request, config).await
+}
+
+/// `default_send_request_handler`, but connecting to `addrs` rather than resolving the host again.
+/// `host` is still the TLS server name.
+async fn send_request_to(
+    addrs: &[SocketAddr],
+    host: &str,
+    mut request: hyper::Request<HyperOutgoingBody>,
+    config: OutgoingRequestConfig,
+) -> Result<IncomingResponse, ErrorCode> {
+    let scheme = request.uri().scheme();
+    if scheme != Some(&hyper::http::uri::Scheme::HTTP)
+        && scheme != Some(&hyper::http::uri::Scheme::HTTPS)
+    {
+        return Err(ErrorCode::HttpProtocolError);
+    }
+
+    let tcp_stream = timeout(config.connect_timeout, TcpStream::connect(addrs))
+        .await
+        .map_err(|_| ErrorCode::ConnectionTimeout)?
+        .map_err(|_| ErrorCode::ConnectionRefused)?;
+
+    let (mut sender, worker) = if config.use_tls {
+        let server_name =
+            ServerName::try_from(host.to_string()).map_err(|_| dns_error("invalid dns name"))?;
+        let stream = TLS_CONNECTOR
+            .connect(server_name, tcp_stream)
+            .await
+            .map_err(|_| ErrorCode::TlsProtocolError)?;
+        handshake(TokioIo::new(stream), config.connect_timeout).await?
+    } else {
+        handshake(TokioIo::new(tcp_stream), config.connect_timeout).await?
+    };
+
+    // The request line only has the path, as there is no proxy in between.
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/"));
+    *request.uri_mut() = hyper::Uri::builder()
+        .path_and_query(path_and_query)
+        .build()
+        .map_err(|_| ErrorCode::HttpRequestUriInvalid)?;
+
+    let resp = timeout(config.first_byte_timeout, sender.send_request(request))
+        .await
+        .map_err(|_| ErrorCode::ConnectionReadTimeout)?
+        .map_err(hyper_request_error)?
+        .map(|body| body.map_err(hyper_request_error).boxed_unsync());
+
+    Ok(IncomingResponse {
+        resp,
+        worker: Some(worker),
+        between_bytes_timeout: config.between_bytes_timeout,
+    })
+}
+
+static TLS_CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.into(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+async fn handshake<S>(
+    stream: TokioIo<S>,
+    connect_timeout: Duration,
+) -> Result<(SendRequest<HyperOutgoingBody>, AbortOnDropJoinHandle<()>), ErrorCode>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = timeout(
+        connect_timeout,
+        hyper::client::conn::http1::handshake(stream),
+    )
+    .await
+    .map_err(|_| ErrorCode::ConnectionTimeout)?
+    .map_err(hyper_request_error)?;
+    let worker = wasmtime_wasi::runtime::spawn(async move {
+        let _ = conn.await;
+    });
+    Ok((sender, worker))
+}
+
+fn dns_error(rcode: &str) -> ErrorCode {
+    ErrorCode::DnsError(DnsErrorPayload {
+        rcode: Some(rcode.to_string()),
+        info_code: Some(0),
+    })
+}
+
+pub(crate) fn is_global_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_ipv4(ip),
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_global_ipv4(ip),
+            None => is_global_ipv6(ip),
+        },
+    }
+}
+
+/// The IPv4 address that an IPv4-mapped or a NAT64 (64:ff9b::/96) address reaches.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return Some(ip);
+    }
+    let [a, b, c, d, e, f, ..] = ip.segments();
+    ((a, b, c, d, e, f) == (0x64, 0xff9b, 0, 0, 0, 0))
+        .then(|| Ipv4Addr::from_bits(ip.to_bits() as u32))
+}
+
+fn is_global_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8
+        || a == 0
+        // 100.64.0.0/10, shared address space
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // 192.0.0.0/24, IETF protocol assignments
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        // 198.18.0.0/15, benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4, reserved
+        || a >= 240)
+}
+
+fn is_global_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // 2001:db8::/32, documentation
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // 64:ff9b:1::/48, local-use NAT64
+        || (first == 0x0064 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_global_ips() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00:ec2::254",
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+        ] {
+            assert!(!is_global_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_global_ips() {
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "2606:4700:4700::1111",
+            "64:ff9b::808:808",
+        ] {
+            assert!(is_global_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_host_lists() {
+        let policy = OutgoingHttpPolicy {
+            deny_hosts: vec!["example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(!policy.is_host_allowed("example.com"));
+        assert!(!policy.is_host_allowed("API.Example.com."));
+        assert!(policy.is_host_allowed("notexample.com"));
+
+        let policy = OutgoingHttpPolicy {
+            allow_hosts: vec!["example.com".to_string()],
+            deny_hosts: vec!["admin.example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.is_host_allowed("api.example.com"));
+        assert!(!policy.is_host_allowed("admin.example.com"));
+        assert!(!policy.is_host_allowed("example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_connects_to_the_checked_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = hyper::service::service_fn(|request: hyper::Request<_>| async move {
+                let body = http_body_util::Full::new(bytes::Bytes::from(request.uri().to_string()));
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(body))
+            });
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        // The host doesn't resolve, so the request can only get there through `addrs`.
+        let request = hyper::Request::builder()
+            .uri("http://unresolvable.invalid/path?query")
+            .body(
+                http_body_util::Empty::new()
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .unwrap();
+        let config = OutgoingRequestConfig {
+            use_tls: false,
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: Duration::from_secs(5),
+            between_bytes_timeout: Duration::from_secs(5),
+        };
+        let response = send_request_to(&[addr], "unresolvable.invalid", request, config)
+            .await
+            .unwrap();
+        let body = response
+            .resp
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert_eq!(body, "/path?query");
+    }
+
+    #[tokio::test]
+    async fn test_denies_hosts_resolving_to_private_ips() {
+        let request = hyper::Request::builder()
+            .uri("http://localhost/")
+            .body(
+                http_body_util::Empty::new()
+                    .map_err(|never| match never {})
+                    .boxed_unsync(),
+            )
+            .unwrap();
+        let config = OutgoingRequestConfig {
+            use_tls: false,
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: Duration::from_secs(5),
+            between_bytes_timeout: Duration::from_secs(5),
+        };
+        let result = send_request(Default::default(), request, config).await;
+        assert!(matches!(result, Err(ErrorCode::DestinationIpProhibited)));
+    }
+}
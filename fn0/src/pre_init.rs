@@ -0,0 +1,147 @@
+//! Wizer pre-initialization for `compile_with_options`.
+
+use anyhow::Result;
+use std::{sync::mpsc, time::Duration};
+use wasmtime::{Config, Engine, Store, component::ResourceTable};
+use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView, p1};
+use wasmtime_wizer::Wizer;
+
+/// How long `init_func` runs before it's interrupted, and `compile_with_options` fails.
+pub(crate) const INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs `init_func` once and returns the wasm with the resulting state baked in.
+/// WASI is there without env, args or preopens, and its output is dropped, as initializers
+/// of toolchains like wasi-libc call it. Other imports trap during initialization.
+pub(crate) async fn pre_initialize(
+    wasm_bytes: &[u8],
+    init_func: &str,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let mut config = Config::new();
+    config
+        .async_support(true)
+        .wasm_component_model(true)
+        .epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    // A thread, as the initializer may never yield to the runtime. Dropping `_done` stops it.
+    let (_done, done_rx) = mpsc::channel::<()>();
+    std::thread::spawn({
+        let engine = engine.clone();
+        move || {
+            if done_rx.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                engine.increment_epoch();
+            }
+        }
+    });
+
+    let mut wizer = Wizer::new();
+    wizer.init_func(init_func);
+
+    if crate::is_component(wasm_bytes) {
+        let mut store = Store::new(
+            &engine,
+            InitState {
+                wasi: WasiCtx::builder().build(),
+                table: ResourceTable::new(),
+            },
+        );
+        store.set_epoch_deadline(1);
+        wizer
+            .run_component(&mut store, wasm_bytes, async |store, component| {
+                let mut linker = wasmtime::component::Linker::new(store.engine());
+                wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
+                linker.define_unknown_imports_as_traps(component)?;
+                linker.instantiate_async(store, component).await
+            })
+            .await
+    } else {
+        let mut store = Store::new(&engine, WasiCtx::builder().build_p1());
+        store.set_epoch_deadline(1);
+        wizer
+            .run(&mut store, wasm_bytes, async |store, module| {
+                let mut linker = wasmtime::Linker::new(store.engine());
+                p1::add_to_linker_async(&mut linker, |wasi| wasi)?;
+                linker.define_unknown_imports_as_traps(module)?;
+                linker.instantiate_async(store, module).await
+            })
+            .await
+    }
+}
+
+struct InitState {
+    wasi: WasiCtx,
+    table: ResourceTable,
+}
+
+impl WasiView for InitState {
+    fn ctx(&mut self) -> WasiCtxView<'_> {
+        WasiCtxView {
+            ctx: &mut self.wasi,
+            table: &mut self.table,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(init_body: &str) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(module
+                (import "wasi_snapshot_preview1" "clock_time_get"
+                    (func $clock_time_get (param i32 i64 i32) (result i32)))
+                (import "env" "host" (func $host))
+                (memory (export "memory") 1)
+                (func (export "init") {init_body})
+                (func (export "get") (result i32) (i32.load (i32.const 0)))
+            )"#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_initializer_calls_wasi() {
+        let wasm = pre_initialize(
+            &module(
+                "(if (i32.eqz (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 8)))
+                    (then (i32.store (i32.const 0) (i32.const 42))))",
+            ),
+            "init",
+            INIT_TIMEOUT,
+        )
+        .await
+        .unwrap();
+
+        let engine = Engine::default();
+        let module = wasmtime::Module::new(&engine, &wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker.define_unknown_imports_as_traps(&module).unwrap();
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let get = instance
+            .get_typed_func::<(), i32>(&mut store, "get")
+            .unwrap();
+        assert_eq!(get.call(&mut store, ()).unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_other_imports_trap() {
+        assert!(
+            pre_initialize(&module("(call $host)"), "init", INIT_TIMEOUT)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_initializer_times_out() {
+        let result = pre_initialize(
+            &module("(loop $forever (br $forever))"),
+            "init",
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Queues in doc-db. `DocDb::new_local` keeps them in a local SQLite file.
+
+use super::*;
+use ::doc_db::DocDb;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct DocDbQueue {
+    db: DocDb,
+}
+
+impl DocDbQueue {
+    pub fn new(db: DocDb) -> Self {
+        Self { db }
+    }
+}
+
+impl QueueBackend for DocDbQueue {
+    fn send<'a>(&'a self, queue: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { Ok(self.db.queue_send(queue, &body, now_ms()).await?) })
+    }
+
+    fn receive<'a>(
+        &'a self,
+        queue: &'a str,
+        max: usize,
+        visibility_timeout: Duration,
+    ) -> BoxFuture<'a, Result<Vec<QueueMessage>>> {
+        Box::pin(async move {
+            let now_ms = now_ms();
+            let visible_at_ms = now_ms + visibility_timeout.as_millis() as u64;
+            let items = self
+                .db
+                .queue_receive(queue, now_ms, max, visible_at_ms)
+                .await?;
+            Ok(items
+                .into_iter()
+                .map(|item| QueueMessage {
+                    id: item.id,
+                    body: Bytes::from(item.body),
+                    attempts: item.deliveries,
+                    receipt: item.receipt,
+                })
+                .collect())
+        })
+    }
+
+    fn ack<'a>(&'a self, queue: &'a str, receipt: u128) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { Ok(self.db.queue_delete(queue, receipt).await?) })
+    }
+
+    fn retry<'a>(
+        &'a self,
+        queue: &'a str,
+        receipt: u128,
+        delay: Duration,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let visible_at_ms = now_ms() + delay.as_millis() as u64;
+            Ok(self.db.queue_retry(queue, receipt, visible_at_ms).await?)
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_doc_db_queue() {
+        let path = std::env::temp_dir().join(format!("fn0-queue-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = DocDb::new_local(&path).await.unwrap();
+        let queue = DocDbQueue::new(db);
+
+        queue.send("q", Bytes::from("a")).await.unwrap();
+        let messages = queue.receive("q", 10, Duration::ZERO).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].body, Bytes::from("a"));
+        assert_eq!(messages[0].attempts, 1);
+
+        queue
+            .retry("q", messages[0].receipt, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(
+            queue
+                .receive("q", 10, Duration::ZERO)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
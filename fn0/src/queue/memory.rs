@@ -0,0 +1,101 @@
+//! Queues of this process, lost on exit. For tests and local development.
+
+use super::*;
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct MemoryQueue {
+    queues: Mutex<HashMap<String, Vec<Entry>>>,
+    /// Ids and receipts.
+    next_id: AtomicU64,
+}
+
+struct Entry {
+    id: u64,
+    body: Bytes,
+    attempts: u32,
+    visible_at: Instant,
+    receipt: u128,
+}
+
+impl MemoryQueue {
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl QueueBackend for MemoryQueue {
+    fn send<'a>(&'a self, queue: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>> {
+        let entry = Entry {
+            id: self.next_id(),
+            body,
+            attempts: 0,
+            visible_at: Instant::now(),
+            receipt: 0,
+        };
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_default()
+            .push(entry);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn receive<'a>(
+        &'a self,
+        queue: &'a str,
+        max: usize,
+        visibility_timeout: Duration,
+    ) -> BoxFuture<'a, Result<Vec<QueueMessage>>> {
+        let now = Instant::now();
+        let mut queues = self.queues.lock().unwrap();
+        let messages = queues
+            .get_mut(queue)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.visible_at <= now)
+            .take(max)
+            .map(|entry| {
+                entry.attempts += 1;
+                entry.visible_at = now + visibility_timeout;
+                entry.receipt = self.next_id() as u128;
+                QueueMessage {
+                    id: entry.id,
+                    body: entry.body.clone(),
+                    attempts: entry.attempts,
+                    receipt: entry.receipt,
+                }
+            })
+            .collect();
+        Box::pin(async { Ok(messages) })
+    }
+
+    fn ack<'a>(&'a self, queue: &'a str, receipt: u128) -> BoxFuture<'a, Result<()>> {
+        if let Some(entries) = self.queues.lock().unwrap().get_mut(queue) {
+            entries.retain(|entry| entry.receipt != receipt);
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn retry<'a>(
+        &'a self,
+        queue: &'a str,
+        receipt: u128,
+        delay: Duration,
+    ) -> BoxFuture<'a, Result<()>> {
+        if let Some(entries) = self.queues.lock().unwrap().get_mut(queue)
+            && let Some(entry) = entries.iter_mut().find(|entry| entry.receipt == receipt)
+        {
+            entry.visible_at = Instant::now() + delay;
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
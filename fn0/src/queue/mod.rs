@@ -0,0 +1,355 @@
+//! Codes bound as consumers of named queues.
+//!
+//! A batch is delivered as `POST /__fn0/queue` with `x-fn0-queue` and a JSON body like
+//! `{"queue":"q","messages":[{"id":"1","attempts":1,"body":"<base64>"}]}`.
+//! A 2xx response acks the batch, except ids listed in a `{"retry":["1"]}` body.
+//! Any other response or body, or an error, retries the whole batch.
+//! Only fn0 makes these requests, see `internal_request`.
+//! Retried messages come back after a backoff, and go to the dead-letter queue after `max_attempts`.
+
+mod doc_db;
+mod memory;
+
+use crate::{Request, Response, telemetry};
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::Bytes;
+pub use doc_db::DocDbQueue;
+use futures::future::BoxFuture;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    Method,
+    header::{CONTENT_TYPE, HOST},
+};
+pub use memory::MemoryQueue;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+const QUEUE_PATH: &str = "/__fn0/queue";
+const QUEUE_HEADER: &str = "x-fn0-queue";
+/// Bytes of a response body read for the ids to retry.
+const MAX_RESPONSE_BODY: usize = 64 * 1024;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Where queue messages are kept. Messages are delivered at least once.
+pub trait QueueBackend: Send + Sync + 'static {
+    fn send<'a>(&'a self, queue: &'a str, body: Bytes) -> BoxFuture<'a, Result<()>>;
+
+    /// Up to `max` visible messages, hidden for `visibility_timeout` unless acked or retried.
+    fn receive<'a>(
+        &'a self,
+        queue: &'a str,
+        max: usize,
+        visibility_timeout: Duration,
+    ) -> BoxFuture<'a, Result<Vec<QueueMessage>>>;
+
+    fn ack<'a>(&'a self, queue: &'a str, receipt: u128) -> BoxFuture<'a, Result<()>>;
+
+    /// Makes the message visible again after `delay`.
+    fn retry<'a>(
+        &'a self,
+        queue: &'a str,
+        receipt: u128,
+        delay: Duration,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueMessage {
+    pub id: u64,
+    pub body: Bytes,
+    /// Deliveries so far, including this one.
+    pub attempts: u32,
+    /// Changes with each delivery, so an ack or retry of an older one does nothing.
+    pub receipt: u128,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueConsumer {
+    pub queue: String,
+    pub code_id: String,
+    /// Messages per delivery.
+    pub batch_size: usize,
+    /// Batches being delivered at once.
+    pub concurrency: usize,
+    pub max_attempts: u32,
+    /// Messages out of attempts are dropped without one.
+    pub dead_letter_queue: Option<String>,
+    /// Should cover the code's duration limit, or messages are delivered twice.
+    pub visibility_timeout: Duration,
+    /// Doubles with each attempt.
+    pub retry_delay: Duration,
+    /// Wait after finding the queue empty.
+    pub poll_interval: Duration,
+}
+
+impl QueueConsumer {
+    pub fn new(queue: impl Into<String>, code_id: impl Into<String>) -> Self {
+        Self {
+            queue: queue.into(),
+            code_id: code_id.into(),
+            batch_size: 10,
+            concurrency: 1,
+            max_attempts: 3,
+            dead_letter_queue: None,
+            visibility_timeout: Duration::from_secs(30),
+            retry_delay: Duration::from_secs(1),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+pub(crate) type Deliver =
+    Arc<dyn Fn(Request) -> BoxFuture<'static, Result<Response>> + Send + Sync>;
+
+#[derive(Serialize)]
+struct Batch<'a> {
+    queue: &'a str,
+    messages: Vec<BatchMessage>,
+}
+
+#[derive(Serialize)]
+struct BatchMessage {
+    id: String,
+    attempts: u32,
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct BatchResult {
+    retry: Vec<String>,
+}
+
+/// Returns once `is_stopped` and the batches being delivered are done.
+pub(crate) async fn consume(
+    backend: Arc<dyn QueueBackend>,
+    consumer: QueueConsumer,
+    deliver: Deliver,
+    is_stopped: impl Fn() -> bool,
+) {
+    let consumer = Arc::new(consumer);
+    let batches = Arc::new(Semaphore::new(consumer.concurrency));
+    let mut tasks = JoinSet::new();
+
+    while !is_stopped() {
+        let permit = batches.clone().acquire_owned().await.unwrap();
+        while tasks.try_join_next().is_some() {}
+
+        let messages = match backend
+            .receive(
+                &consumer.queue,
+                consumer.batch_size,
+                consumer.visibility_timeout,
+            )
+            .await
+        {
+            Ok(messages) => messages,
+            Err(error) => {
+                telemetry::queue_backend_error(&consumer.queue, "receive", &format!("{error:?}"));
+                tokio::time::sleep(consumer.poll_interval).await;
+                continue;
+            }
+        };
+        if messages.is_empty() {
+            tokio::time::sleep(consumer.poll_interval).await;
+            continue;
+        }
+        telemetry::queue_received(&consumer.queue, messages.len());
+
+        let backend = backend.clone();
+        let consumer = consumer.clone();
+        let deliver = deliver.clone();
+        tasks.spawn(async move {
+            deliver_batch(backend.as_ref(), &consumer, deliver, messages).await;
+            drop(permit);
+        });
+    }
+
+    tasks.join_all().await;
+}
+
+async fn deliver_batch(
+    backend: &dyn QueueBackend,
+    consumer: &QueueConsumer,
+    deliver: Deliver,
+    messages: Vec<QueueMessage>,
+) {
+    let request = batch_request(&consumer.queue, &messages);
+    // `None` retries all.
+    let retry_ids = match deliver(request).await {
+        Ok(response) if response.status().is_success() => retry_ids(response).await,
+        _ => None,
+    };
+
+    let queue = &consumer.queue;
+    for message in messages {
+        let is_ok = retry_ids
+            .as_ref()
+            .is_some_and(|retry_ids| !retry_ids.contains(&message.id));
+        let result = if is_ok {
+            telemetry::queue_acked(queue);
+            backend.ack(queue, message.receipt).await
+        } else if message.attempts < consumer.max_attempts {
+            telemetry::queue_retried(queue);
+            let delay = consumer.retry_delay(message.attempts);
+            backend.retry(queue, message.receipt, delay).await
+        } else {
+            telemetry::queue_dead_lettered(queue);
+            dead_letter(backend, consumer, message).await
+        };
+        if let Err(error) = result {
+            // The message comes back after the visibility timeout.
+            telemetry::queue_backend_error(queue, "settle", &format!("{error:?}"));
+        }
+    }
+}
+
+async fn dead_letter(
+    backend: &dyn QueueBackend,
+    consumer: &QueueConsumer,
+    message: QueueMessage,
+) -> Result<()> {
+    if let Some(dead_letter_queue) = &consumer.dead_letter_queue {
+        backend.send(dead_letter_queue, message.body).await?;
+    }
+    backend.ack(&consumer.queue, message.receipt).await
+}
+
+fn batch_request(queue: &str, messages: &[QueueMessage]) -> Request {
+    let batch = Batch {
+        queue,
+        messages: messages
+            .iter()
+            .map(|message| BatchMessage {
+                id: message.id.to_string(),
+                attempts: message.attempts,
+                body: BASE64.encode(&message.body),
+            })
+            .collect(),
+    };
+    let body = Full::new(Bytes::from(sonic_rs::to_vec(&batch).unwrap()))
+        .map_err(|never| match never {})
+        .boxed_unsync();
+    hyper::Request::builder()
+        .method(Method::POST)
+        .uri(QUEUE_PATH)
+        .header(HOST, "localhost")
+        .header(CONTENT_TYPE, "application/json")
+        .header(QUEUE_HEADER, queue)
+        .body(body)
+        .unwrap()
+}
+
+/// An empty body retries none, and one that isn't a `BatchResult` retries all.
+async fn retry_ids(response: Response) -> Option<HashSet<u64>> {
+    let body = Limited::new(response.into_body(), MAX_RESPONSE_BODY)
+        .collect()
+        .await
+        .ok()?
+        .to_bytes();
+    if body.is_empty() {
+        return Some(HashSet::new());
+    }
+    let result = sonic_rs::from_slice::<BatchResult>(&body).ok()?;
+    Some(
+        result
+            .retry
+            .iter()
+            .filter_map(|id| id.parse().ok())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execute::response;
+    use hyper::StatusCode;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Deserialize)]
+    struct TestBatch {
+        messages: Vec<TestMessage>,
+    }
+
+    #[derive(Deserialize)]
+    struct TestMessage {
+        id: String,
+        body: String,
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let backend = Arc::new(MemoryQueue::default());
+        for body in ["ok", "bad", "ok"] {
+            backend.send("q", Bytes::from(body)).await.unwrap();
+        }
+
+        let consumer = QueueConsumer {
+            batch_size: 2,
+            concurrency: 2,
+            max_attempts: 2,
+            dead_letter_queue: Some("dead".to_string()),
+            retry_delay: Duration::from_millis(1),
+            poll_interval: Duration::from_millis(1),
+            ..QueueConsumer::new("q", "code")
+        };
+        // Retries messages with body "bad".
+        let deliver: Deliver = Arc::new(|request: Request| {
+            Box::pin(async move {
+                let body = request.into_body().collect().await?.to_bytes();
+                let batch: TestBatch = sonic_rs::from_slice(&body)?;
+                let retry = batch
+                    .messages
+                    .into_iter()
+                    .filter(|message| BASE64.decode(&message.body).unwrap() == b"bad")
+                    .map(|message| format!("\"{}\"", message.id))
+                    .collect::<Vec<_>>();
+                let body = format!("{{\"retry\":[{}]}}", retry.join(","));
+                Ok(response(StatusCode::OK, Bytes::from(body)))
+            })
+        });
+
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(consume(backend.clone(), consumer, deliver, {
+            let is_stopped = is_stopped.clone();
+            move || is_stopped.load(Ordering::Relaxed)
+        }));
+
+        let dead = loop {
+            let dead = backend
+                .receive("dead", 10, Duration::from_secs(60))
+                .await
+                .unwrap();
+            if !dead.is_empty() {
+                break dead;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        };
+        is_stopped.store(true, Ordering::Relaxed);
+        task.await.unwrap();
+
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].body, Bytes::from("bad"));
+        let left = backend.receive("q", 10, Duration::ZERO).await.unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retry_ids() {
+        let retry_ids = |body: &'static str| retry_ids(response(StatusCode::OK, Bytes::from(body)));
+        assert_eq!(retry_ids("").await, Some(HashSet::new()));
+        assert_eq!(
+            retry_ids(r#"{"retry":["1","2"]}"#).await,
+            Some(HashSet::from([1, 2]))
+        );
+        assert_eq!(retry_ids("ok").await, None);
+    }
+}
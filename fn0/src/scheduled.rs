@@ -0,0 +1,88 @@
+//! Cron runs pushed by hq.
+//!
+//! A component exporting `fn0:scheduled/handler` of `wit/scheduled.wit` gets `scheduled` called.
+//! ski calls the code's global `scheduled(event, env)` if it defines one.
+//! Other codes get a `POST /__fn0/scheduled` request, with the run in `x-fn0-schedule` and
+//! `x-fn0-scheduled-at`. The run failed unless the code returns `Ok` or a 2xx response.
+//! Only the host agent makes these requests, see `internal_request`.
+
+use crate::Request;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Method, header::HOST};
+use wasmtime::{
+    AsContextMut,
+    component::{Instance, TypedFunc},
+};
+
+const SCHEDULED_PATH: &str = "/__fn0/scheduled";
+const SCHEDULE_HEADER: &str = "x-fn0-schedule";
+const SCHEDULED_AT_HEADER: &str = "x-fn0-scheduled-at";
+const SCHEDULED_INTERFACE: &str = "fn0:scheduled/handler";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledEvent {
+    /// `CronSchedule::name` in the deployment.
+    pub schedule: String,
+    /// Unix epoch milliseconds of the fire time.
+    pub scheduled_at: u64,
+}
+
+pub(crate) type ScheduledFunc = TypedFunc<(String, u64), (Result<(), String>,)>;
+
+impl ScheduledEvent {
+    /// Also carries `self` in the extensions, so executors can tell it from a real request.
+    pub(crate) fn into_request(self) -> Request {
+        let body = Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed_unsync();
+        let mut request = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(SCHEDULED_PATH)
+            .header(HOST, "localhost")
+            .header(SCHEDULE_HEADER, &self.schedule)
+            .header(SCHEDULED_AT_HEADER, self.scheduled_at)
+            .body(body)
+            .unwrap();
+        request.extensions_mut().insert(self);
+        request
+    }
+}
+
+/// `scheduled` of the component instance, if it exports one of the right type.
+pub(crate) fn scheduled_func(
+    mut store: impl AsContextMut,
+    instance: &Instance,
+) -> Option<ScheduledFunc> {
+    let interface = instance.get_export_index(&mut store, None, SCHEDULED_INTERFACE)?;
+    let func = instance.get_export_index(&mut store, Some(&interface), "scheduled")?;
+    instance.get_typed_func(&mut store, func).ok()
+}
+
+impl From<&ScheduledEvent> for ski::Scheduled {
+    fn from(event: &ScheduledEvent) -> Self {
+        Self {
+            schedule: event.schedule.clone(),
+            scheduled_at: event.scheduled_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_request() {
+        let event = ScheduledEvent {
+            schedule: "nightly".to_string(),
+            scheduled_at: 1_700_000_000_000,
+        };
+        let request = event.clone().into_request();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri().path(), SCHEDULED_PATH);
+        assert_eq!(request.headers()[SCHEDULE_HEADER], "nightly");
+        assert_eq!(request.headers()[SCHEDULED_AT_HEADER], "1700000000000");
+        assert_eq!(request.extensions().get::<ScheduledEvent>(), Some(&event));
+    }
+}
@@ -0,0 +1,203 @@
+//! In-process calls between codes through `internal://<code_id>/...` URLs.
+
+use crate::{Request, Response, trace_context};
+use futures::future::BoxFuture;
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tracing::Span;
+
+pub const INTERNAL_SCHEME: &str = "internal";
+
+/// Kept if the external request carries it, so logs can be matched with the caller's.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ServiceBindingLimits {
+    /// Nesting of internal calls, counted from the external request.
+    pub max_depth: usize,
+    /// Internal calls made by all codes handling one external request.
+    pub max_invocations: usize,
+}
+
+impl Default for ServiceBindingLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 8,
+            max_invocations: 32,
+        }
+    }
+}
+
+/// Shared by every code running on behalf of one external request.
+#[derive(Clone)]
+pub(crate) struct Invocation {
+    request_id: Arc<str>,
+    depth: usize,
+    internal_invocations: Arc<AtomicUsize>,
+    /// Of this code's run, see `trace_context`.
+    span: Span,
+}
+
+impl Default for Invocation {
+    fn default() -> Self {
+        Self {
+            request_id: Default::default(),
+            depth: 0,
+            internal_invocations: Default::default(),
+            span: Span::none(),
+        }
+    }
+}
+
+impl Invocation {
+    pub(crate) fn new(code_id: &str, request: &Request) -> Self {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .map(Arc::from)
+            .unwrap_or_else(|| Arc::from(format!("{:016x}", rand::random::<u64>())));
+        let span = trace_context::root_span(code_id, &request_id, request.headers());
+        Self {
+            request_id,
+            depth: 0,
+            internal_invocations: Default::default(),
+            span,
+        }
+    }
+
+    pub(crate) fn request_id(&self) -> &Arc<str> {
+        &self.request_id
+    }
+
+    pub(crate) fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// The invocation of the callee, if limits allow one more internal call.
+    pub(crate) fn enter(
+        &self,
+        callee_code_id: &str,
+        limits: ServiceBindingLimits,
+    ) -> Result<Self, ServiceBindingError> {
+        if self.depth + 1 > limits.max_depth {
+            return Err(ServiceBindingError::DepthExceeded);
+        }
+        if self.internal_invocations.fetch_add(1, Ordering::Relaxed) >= limits.max_invocations {
+            return Err(ServiceBindingError::InvocationsExceeded);
+        }
+        Ok(Self {
+            request_id: self.request_id.clone(),
+            depth: self.depth + 1,
+            internal_invocations: self.internal_invocations.clone(),
+            span: trace_context::child_span(&self.span, callee_code_id, &self.request_id),
+        })
+    }
+}
+
+/// Implemented by `Fn0`, so executors can call other codes without knowing its cache types.
+pub(crate) trait ServiceBinding: Send + Sync + 'static {
+    fn call(
+        self: Arc<Self>,
+        caller_code_id: String,
+        request: Request,
+        invocation: Invocation,
+    ) -> BoxFuture<'static, Result<Response, ServiceBindingError>>;
+}
+
+#[derive(Debug)]
+pub enum ServiceBindingError {
+    UnknownCode,
+    NotInSameDeployment,
+    DepthExceeded,
+    InvocationsExceeded,
+    Run(anyhow::Error),
+}
+
+impl ServiceBindingError {
+    pub(crate) fn outcome(&self) -> &'static str {
+        match self {
+            Self::UnknownCode => "unknown_code",
+            Self::NotInSameDeployment => "not_in_same_deployment",
+            Self::DepthExceeded => "depth_exceeded",
+            Self::InvocationsExceeded => "invocations_exceeded",
+            Self::Run(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for ServiceBindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownCode => write!(f, "unknown code"),
+            Self::NotInSameDeployment => write!(f, "code is not in the same deployment"),
+            Self::DepthExceeded => write!(f, "service binding depth limit exceeded"),
+            Self::InvocationsExceeded => write!(f, "service binding invocation limit exceeded"),
+            Self::Run(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ServiceBindingError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CodeKind, DeploymentMap, Fn0, JsCode,
+        test_util::{MemoryCache, request},
+    };
+
+    #[test]
+    fn test_invocation_limits() {
+        let limits = ServiceBindingLimits {
+            max_depth: 2,
+            max_invocations: 3,
+        };
+        let root = Invocation::default();
+        let child = root.enter("callee", limits).unwrap();
+        let grandchild = child.enter("callee", limits).unwrap();
+        assert!(matches!(
+            grandchild.enter("callee", limits),
+            Err(ServiceBindingError::DepthExceeded)
+        ));
+
+        root.enter("callee", limits).unwrap();
+        assert!(matches!(
+            root.enter("callee", limits),
+            Err(ServiceBindingError::InvocationsExceeded)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_calls_from_another_deployment() {
+        let mut deployment_map = DeploymentMap::new();
+        deployment_map.register_code("caller", CodeKind::Wasm);
+        deployment_map.register_code("callee", CodeKind::Wasm);
+        let fn0 = Fn0::<MemoryCache<JsCode>>::new(
+            MemoryCache::default(),
+            MemoryCache::default(),
+            deployment_map,
+            Default::default(),
+        );
+
+        for (callee, expected) in [
+            ("callee", "not_in_same_deployment"),
+            ("unknown", "unknown_code"),
+        ] {
+            let request = request(&format!("{INTERNAL_SCHEME}://{callee}/"));
+            let result = fn0
+                .clone()
+                .call("caller".to_string(), request, Invocation::default())
+                .await;
+            assert_eq!(result.unwrap_err().outcome(), expected);
+        }
+    }
+}
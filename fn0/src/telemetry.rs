@@ -1,14 +1,23 @@
+use crate::guest_log::GuestLogStream;
+use opentelemetry::logs::{LogRecord, Logger, LoggerProvider, Severity};
 use opentelemetry::{KeyValue, global, trace::TracerProvider};
 use opentelemetry_otlp::{Protocol, WithExportConfig};
 use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::logs::{SdkLogger, SdkLoggerProvider};
 use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
 use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::sync::OnceLock;
 use std::time::Duration;
 use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 
-pub type TelemetryProviders = (SdkTracerProvider, SdkMeterProvider);
+pub type TelemetryProviders = (SdkTracerProvider, SdkMeterProvider, SdkLoggerProvider);
+
+/// Guest logs go to stdout through `tracing` until this is set.
+static GUEST_LOGGER: OnceLock<SdkLogger> = OnceLock::new();
+/// Kept for `force_flush`, which runs where the providers returned by `setup_telemetry` aren't.
+static PROVIDERS: OnceLock<TelemetryProviders> = OnceLock::new();
 
 pub fn setup_telemetry(
     otlp_endpoint: Option<String>,
@@ -55,18 +64,46 @@ pub fn setup_telemetry(
 
     global::set_meter_provider(meter_provider.clone());
 
+    let log_exporter = opentelemetry_otlp::LogExporter::builder()
+        .with_tonic()
+        .with_endpoint(&endpoint)
+        .with_protocol(Protocol::Grpc)
+        .build()?;
+
+    let logger_provider = SdkLoggerProvider::builder()
+        .with_resource(Resource::builder().with_service_name("fn0").build())
+        .with_batch_exporter(log_exporter)
+        .build();
+
+    let _ = GUEST_LOGGER.set(logger_provider.logger("fn0-guest"));
+    let _ = PROVIDERS.set((
+        tracer_provider.clone(),
+        meter_provider.clone(),
+        logger_provider.clone(),
+    ));
+
     info!("telemetry setup completed with OTLP endpoint: {}", endpoint);
-    Ok(Some((tracer_provider, meter_provider)))
+    Ok(Some((tracer_provider, meter_provider, logger_provider)))
 }
 
 pub fn shutdown_telemetry(providers: Option<TelemetryProviders>) -> anyhow::Result<()> {
-    if let Some((tracer_provider, meter_provider)) = providers {
+    if let Some((tracer_provider, meter_provider, logger_provider)) = providers {
         tracer_provider.shutdown()?;
         meter_provider.shutdown()?;
+        logger_provider.shutdown()?;
     }
     Ok(())
 }
 
+/// Exports what is buffered without shutting the providers down.
+pub fn force_flush() {
+    if let Some((tracer_provider, meter_provider, logger_provider)) = PROVIDERS.get() {
+        let _ = tracer_provider.force_flush();
+        let _ = meter_provider.force_flush();
+        let _ = logger_provider.force_flush();
+    }
+}
+
 // Telemetry event functions
 
 pub fn wasmtime_error(func: &'static str, code_id: &str, error: &str) {
@@ -138,6 +175,42 @@ pub fn cpu_timeout(code_id: &str, cpu_time: Duration) {
     );
 }
 
+pub fn init_cpu_time(code_id: &str, cpu_time: Duration) {
+    let histogram = global::meter("fn0")
+        .f64_histogram("init_cpu_time_seconds")
+        .build();
+    histogram.record(
+        cpu_time.as_secs_f64(),
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn init_timeout(code_id: &str, cpu_time: Duration) {
+    let counter = global::meter("fn0").u64_counter("init_timeout").build();
+    counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+
+    let histogram = global::meter("fn0")
+        .f64_histogram("init_timeout_seconds")
+        .build();
+    histogram.record(
+        cpu_time.as_secs_f64(),
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn duration_timeout(code_id: &str, duration: Duration) {
+    let counter = global::meter("fn0").u64_counter("duration_timeout").build();
+    counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+
+    let histogram = global::meter("fn0")
+        .f64_histogram("duration_timeout_seconds")
+        .build();
+    histogram.record(
+        duration.as_secs_f64(),
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
 pub fn trapped(code_id: &str, trap: &str) {
     let counter = global::meter("fn0").u64_counter("trapped").build();
     counter.add(
@@ -162,9 +235,148 @@ pub fn canceled_unexpectedly(code_id: &str, error: &str) {
     );
 }
 
-pub fn create_instance(code_id: &str) {
+pub fn cgi_exit_code(code_id: &str, exit_code: i32) {
+    let counter = global::meter("fn0").u64_counter("cgi_exit_code").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("exit_code", exit_code as i64),
+        ],
+    );
+}
+
+pub fn cgi_invalid_output(code_id: &str, error: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("cgi_invalid_output")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn subrequest(code_id: &str, outcome: &'static str) {
+    let counter = global::meter("fn0").u64_counter("subrequest").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("outcome", outcome),
+        ],
+    );
+}
+
+pub fn subrequest_duration(code_id: &str, duration: Duration) {
+    let histogram = global::meter("fn0")
+        .f64_histogram("subrequest_duration_seconds")
+        .build();
+    histogram.record(
+        duration.as_secs_f64(),
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn service_binding(caller_code_id: &str, callee_code_id: &str, outcome: &'static str) {
+    let counter = global::meter("fn0").u64_counter("service_binding").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", caller_code_id.to_string()),
+            KeyValue::new("callee_code_id", callee_code_id.to_string()),
+            KeyValue::new("outcome", outcome),
+        ],
+    );
+}
+
+pub fn guest_log(code_id: &str, request_id: &str, stream: GuestLogStream, line: &str) {
+    let Some(logger) = GUEST_LOGGER.get() else {
+        info!(
+            target: "fn0::guest",
+            code_id,
+            request_id,
+            stream = stream.as_str(),
+            "{line}"
+        );
+        return;
+    };
+    let mut record = logger.create_log_record();
+    let (severity, severity_text) = match stream {
+        GuestLogStream::Stdout => (Severity::Info, "INFO"),
+        GuestLogStream::Stderr => (Severity::Error, "ERROR"),
+    };
+    record.set_severity_number(severity);
+    record.set_severity_text(severity_text);
+    record.set_body(line.to_string().into());
+    record.add_attribute("code_id", code_id.to_string());
+    record.add_attribute("request_id", request_id.to_string());
+    record.add_attribute("stream", stream.as_str());
+    logger.emit(record);
+}
+
+pub fn guest_log_truncated(code_id: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("guest_log_truncated")
+        .build();
+    counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+}
+
+pub fn request_too_large(code_id: &str, status: u16) {
+    let counter = global::meter("fn0")
+        .u64_counter("request_too_large")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("status", status as i64),
+        ],
+    );
+}
+
+pub fn response_header_too_large(code_id: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("response_header_too_large")
+        .build();
+    counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+}
+
+pub fn create_instance(code_id: &str, pre_initialized: bool) {
     let counter = global::meter("fn0").u64_counter("create_instance").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("pre_initialized", pre_initialized),
+        ],
+    );
+}
+
+pub fn tier_up(code_id: &str, compile_time: Duration) {
+    let counter = global::meter("fn0").u64_counter("tier_up").build();
     counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+
+    let histogram = global::meter("fn0")
+        .f64_histogram("tier_up_compile_seconds")
+        .build();
+    histogram.record(
+        compile_time.as_secs_f64(),
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn tier_up_failed(code_id: &str, error: &str) {
+    let counter = global::meter("fn0").u64_counter("tier_up_failed").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
 }
 
 pub fn proxy_cache_error(code_id: &str, error: &str) {
@@ -186,3 +398,240 @@ pub fn code_id_parse_error() {
         .build();
     counter.add(1, &[]);
 }
+
+pub fn host_agent_connection_status(success: bool) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_connection_status")
+        .build();
+    counter.add(1, &[KeyValue::new("success", success)]);
+}
+
+pub fn host_agent_status_sent(success: bool) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_status_sent")
+        .build();
+    counter.add(1, &[KeyValue::new("success", success)]);
+}
+
+pub fn host_agent_deployment_updates(applied: bool, count: usize) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_deployment_updates")
+        .build();
+    counter.add(count as u64, &[KeyValue::new("applied", applied)]);
+}
+
+pub fn host_agent_message_parse_error(error: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_message_parse_error")
+        .build();
+    counter.add(1, &[KeyValue::new("error", error.to_string())]);
+}
+
+pub fn host_agent_graceful_shutdown() {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_graceful_shutdown")
+        .build();
+    counter.add(1, &[]);
+}
+
+pub fn shutdown(finished: u64, dropped: u64) {
+    let counter = global::meter("fn0")
+        .u64_counter("shutdown_requests")
+        .build();
+    counter.add(finished, &[KeyValue::new("dropped", false)]);
+    counter.add(dropped, &[KeyValue::new("dropped", true)]);
+}
+
+pub fn admission_queue_len(delta: i64) {
+    let counter = global::meter("fn0")
+        .i64_up_down_counter("admission_queue_len")
+        .build();
+    counter.add(delta, &[]);
+}
+
+pub fn admission_rejected(code_id: &str, reason: &'static str) {
+    let counter = global::meter("fn0")
+        .u64_counter("admission_rejected")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("reason", reason),
+        ],
+    );
+}
+
+pub fn keyvalue_quota_exceeded(code_id: &str, quota: &'static str) {
+    let counter = global::meter("fn0")
+        .u64_counter("keyvalue_quota_exceeded")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("quota", quota),
+        ],
+    );
+}
+
+pub fn keyvalue_error(code_id: &str, error: &str) {
+    let counter = global::meter("fn0").u64_counter("keyvalue_error").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn host_agent_scheduled_run(code_id: &str, ok: bool) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_scheduled_run")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("ok", ok),
+        ],
+    );
+}
+
+pub fn host_agent_scheduled_run_result_sent(success: bool) {
+    let counter = global::meter("fn0")
+        .u64_counter("host_agent_scheduled_run_result_sent")
+        .build();
+    counter.add(1, &[KeyValue::new("success", success)]);
+}
+
+pub fn queue_backend_error(queue: &str, op: &'static str, error: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("queue_backend_error")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("queue", queue.to_string()),
+            KeyValue::new("op", op),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn queue_received(queue: &str, count: usize) {
+    let counter = global::meter("fn0").u64_counter("queue_received").build();
+    counter.add(count as u64, &[KeyValue::new("queue", queue.to_string())]);
+}
+
+pub fn queue_acked(queue: &str) {
+    let counter = global::meter("fn0").u64_counter("queue_acked").build();
+    counter.add(1, &[KeyValue::new("queue", queue.to_string())]);
+}
+
+pub fn queue_retried(queue: &str) {
+    let counter = global::meter("fn0").u64_counter("queue_retried").build();
+    counter.add(1, &[KeyValue::new("queue", queue.to_string())]);
+}
+
+pub fn queue_dead_lettered(queue: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("queue_dead_lettered")
+        .build();
+    counter.add(1, &[KeyValue::new("queue", queue.to_string())]);
+}
+
+pub fn error_response(code_id: &str, kind: &'static str) {
+    let counter = global::meter("fn0").u64_counter("error_response").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("kind", kind),
+        ],
+    );
+}
+
+pub fn peak_memory(code_id: &str, bytes: usize) {
+    let histogram = global::meter("fn0")
+        .u64_histogram("peak_memory_bytes")
+        .build();
+    histogram.record(
+        bytes as u64,
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn peak_table_elements(code_id: &str, elements: usize) {
+    let histogram = global::meter("fn0")
+        .u64_histogram("peak_table_elements")
+        .build();
+    histogram.record(
+        elements as u64,
+        &[KeyValue::new("code_id", code_id.to_string())],
+    );
+}
+
+pub fn memory_growth_denied(code_id: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("memory_growth_denied")
+        .build();
+    counter.add(1, &[KeyValue::new("code_id", code_id.to_string())]);
+}
+
+pub fn cluster_forward(code_id: &str, outcome: &'static str) {
+    let counter = global::meter("fn0").u64_counter("cluster_forward").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("outcome", outcome),
+        ],
+    );
+}
+
+pub fn cluster_rejected(code_id: &str, error: &str) {
+    let counter = global::meter("fn0").u64_counter("cluster_rejected").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id.to_string()),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn cluster_error(op: &'static str, error: &str) {
+    let counter = global::meter("fn0").u64_counter("cluster_error").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("op", op),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn list_neighbors_error(source: &'static str, error: &str) {
+    let counter = global::meter("fn0")
+        .u64_counter("list_neighbors_error")
+        .build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("source", source),
+            KeyValue::new("error", error.to_string()),
+        ],
+    );
+}
+
+pub fn cluster_role(role: &'static str) {
+    let counter = global::meter("fn0").u64_counter("cluster_role").build();
+    counter.add(1, &[KeyValue::new("role", role)]);
+}
+
+pub fn cluster_handover(outcome: &'static str) {
+    let counter = global::meter("fn0").u64_counter("cluster_handover").build();
+    counter.add(1, &[KeyValue::new("outcome", outcome)]);
+}
@@ -0,0 +1,118 @@
+//! Helpers shared by the tests of several modules.
+
+use crate::{Body, Request};
+use adapt_cache::{AdaptCache, Error};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Serves sources put with `insert`, and caches what `convert` makes of them until replaced.
+#[derive(Clone)]
+pub(crate) struct MemoryCache<T> {
+    inner: Arc<Mutex<MemoryCacheInner<T>>>,
+}
+
+struct MemoryCacheInner<T> {
+    sources: HashMap<String, Bytes>,
+    values: HashMap<String, T>,
+}
+
+impl<T> Default for MemoryCache<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MemoryCacheInner {
+                sources: Default::default(),
+                values: Default::default(),
+            })),
+        }
+    }
+}
+
+impl<T> MemoryCache<T> {
+    pub(crate) fn insert(&self, id: &str, source: impl Into<Bytes>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sources.insert(id.to_string(), source.into());
+        inner.values.remove(id);
+    }
+}
+
+impl<T, E> AdaptCache<T, E> for MemoryCache<T>
+where
+    T: Clone + Send + Sync + 'static,
+    E: Send + 'static,
+{
+    fn get(
+        &self,
+        id: &str,
+        convert: impl FnOnce(Bytes) -> std::result::Result<(T, usize), E> + Send,
+    ) -> impl Future<Output = Result<T, Error<E>>> + Send {
+        let result = (|| {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(value) = inner.values.get(id) {
+                return Ok(value.clone());
+            }
+            let source = inner.sources.get(id).cloned().ok_or(Error::NotFound)?;
+            let (value, _weight) = convert(source).map_err(Error::ConvertError)?;
+            inner.values.insert(id.to_string(), value.clone());
+            Ok(value)
+        })();
+        std::future::ready(result)
+    }
+}
+
+pub(crate) fn request(uri: &str) -> Request {
+    hyper::Request::builder()
+        .uri(uri)
+        .body(Body::new(Empty::new().map_err(|never| match never {})))
+        .unwrap()
+}
+
+/// A component whose handler runs `handle`, and whose core module may have more, like `start`.
+pub(crate) fn component(handle: &str, module_fields: &str) -> Vec<u8> {
+    let wat = format!(
+        r#"(component
+            (import "wasi:http/types@0.2.6" (instance $types
+                (export "incoming-request" (type (sub resource)))
+                (export "response-outparam" (type (sub resource)))
+            ))
+            (alias export $types "incoming-request" (type $request))
+            (alias export $types "response-outparam" (type $response-out))
+            (core module $m
+                (func (export "handle") (param i32 i32) {handle})
+                {module_fields}
+            )
+            (core instance $i (instantiate $m))
+            (func $handle (param "request" (own $request)) (param "response-out" (own $response-out))
+                (canon lift (core func $i "handle")))
+            (instance $handler (export "handle" (func $handle)))
+            (export "wasi:http/incoming-handler@0.2.6" (instance $handler))
+        )"#
+    );
+    wat::parse_str(wat).unwrap()
+}
+
+pub(crate) async fn eventually<F, Fut>(mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    for _ in 0..100 {
+        if check().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("timed out");
+}
+
+pub(crate) fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
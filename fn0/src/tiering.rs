@@ -0,0 +1,118 @@
+//! Tiered compilation.
+//!
+//! `compile` builds code with Winch, which is fast to compile, so a cold code is served right away.
+//! Once a code gets hot, the executor compiles the embedded source with optimizing Cranelift in the
+//! background and swaps the result into the cached `TieredWasmPre`.
+//! Code that Winch rejects, like code using tail calls, is built optimized from the start.
+
+use crate::execute::WasmPre;
+use bytes::Bytes;
+use measure_cpu_time::Clock;
+use std::{
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompilerTier {
+    /// Winch. Artifacts from `compile` are built with this.
+    Baseline,
+    /// Cranelift with `OptLevel::Speed`.
+    Optimized,
+}
+
+/// A code tiers up when either of these is crossed, counted since it was loaded into the cache.
+#[derive(Clone, Copy, Debug)]
+pub struct TierUpThreshold {
+    pub invocations: u64,
+    pub cpu_time: Duration,
+}
+
+impl Default for TierUpThreshold {
+    fn default() -> Self {
+        Self {
+            invocations: 100,
+            cpu_time: Duration::from_millis(100),
+        }
+    }
+}
+
+/// What the wasm proxy cache holds. Clones share the current pre and the counters.
+pub struct TieredWasmPre<C: Clock> {
+    inner: Arc<Inner<C>>,
+}
+
+impl<C: Clock> Clone for TieredWasmPre<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct Inner<C: Clock> {
+    current: RwLock<WasmPre<C>>,
+    /// Only baseline artifacts have it, until tier-up takes it.
+    source: Mutex<Option<Bytes>>,
+    hotness: Arc<Hotness>,
+}
+
+/// Invocations and cpu time of a code, recorded by the handlers.
+#[derive(Default)]
+pub(crate) struct Hotness {
+    invocations: AtomicU64,
+    cpu_time_nanos: AtomicU64,
+}
+
+impl Hotness {
+    pub(crate) fn record_invocation(&self) {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_cpu_time(&self, cpu_time: Duration) {
+        self.cpu_time_nanos
+            .fetch_add(cpu_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn is_over(&self, threshold: &TierUpThreshold) -> bool {
+        self.invocations.load(Ordering::Relaxed) >= threshold.invocations
+            || self.cpu_time_nanos.load(Ordering::Relaxed) >= threshold.cpu_time.as_nanos() as u64
+    }
+}
+
+impl<C: Clock> TieredWasmPre<C> {
+    pub(crate) fn new(pre: WasmPre<C>, source: Option<Bytes>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                current: RwLock::new(pre),
+                source: Mutex::new(source),
+                hotness: Default::default(),
+            }),
+        }
+    }
+
+    pub(crate) fn current(&self) -> WasmPre<C> {
+        self.inner.current.read().unwrap().clone()
+    }
+
+    pub(crate) fn hotness(&self) -> Arc<Hotness> {
+        self.inner.hotness.clone()
+    }
+
+    /// The source to compile, for only the first caller after the threshold is crossed.
+    /// It's taken, so its memory is freed once the optimized tier is built.
+    pub(crate) fn start_tier_up(&self, threshold: &TierUpThreshold) -> Option<Bytes> {
+        if !self.inner.hotness.is_over(threshold) {
+            return None;
+        }
+        self.inner.source.lock().unwrap().take()
+    }
+
+    /// Invocations already holding the baseline pre finish with it.
+    pub(crate) fn finish_tier_up(&self, pre: WasmPre<C>) {
+        *self.inner.current.write().unwrap() = pre;
+    }
+}
@@ -0,0 +1,131 @@
+//! W3C trace context of invocations.
+//!
+//! Each invocation has a span with its code_id, request id, CPU time and peak memory.
+//! The span of an external request continues the trace of its `traceparent` and `tracestate`,
+//! or starts a new one. Internal calls get a child span of the caller's. Outgoing subrequests of
+//! components carry the context of the invocation span, unless the guest set `traceparent` itself.
+//! Spans are only exported in OTLP mode, see `telemetry::setup_telemetry`.
+
+use hyper::{
+    HeaderMap,
+    header::{HeaderName, HeaderValue},
+};
+use opentelemetry::{
+    Context,
+    propagation::{Extractor, Injector, TextMapPropagator},
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing::{Span, field::Empty};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// The span of an invocation of `code_id` for an external request or a trigger.
+pub(crate) fn root_span(code_id: &str, request_id: &str, headers: &HeaderMap) -> Span {
+    let span = tracing::info_span!(
+        parent: None,
+        "invocation",
+        code_id,
+        request_id,
+        cpu_time_ms = Empty,
+        peak_memory_bytes = Empty
+    );
+    if headers.contains_key(TRACEPARENT_HEADER) {
+        let _ = span.set_parent(extract(headers));
+    }
+    span
+}
+
+/// The span of an internal call to `code_id` made under `parent`.
+pub(crate) fn child_span(parent: &Span, code_id: &str, request_id: &str) -> Span {
+    tracing::info_span!(
+        parent: parent,
+        "invocation",
+        code_id,
+        request_id,
+        cpu_time_ms = Empty,
+        peak_memory_bytes = Empty
+    )
+}
+
+pub(crate) fn record_cpu_time(span: &Span, cpu_time: std::time::Duration) {
+    span.record("cpu_time_ms", cpu_time.as_millis() as u64);
+}
+
+pub(crate) fn record_peak_memory(span: &Span, bytes: usize) {
+    span.record("peak_memory_bytes", bytes as u64);
+}
+
+/// Adds the context of `span` to a subrequest that has none.
+pub(crate) fn inject_span(span: &Span, headers: &mut HeaderMap) {
+    if !headers.contains_key(TRACEPARENT_HEADER) {
+        inject(&span.context(), headers);
+    }
+}
+
+fn extract(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+fn inject(context: &Context, headers: &mut HeaderMap) {
+    TraceContextPropagator::new().inject_context(context, &mut HeaderInjector(headers));
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt;
+
+    #[test]
+    fn test_extract_and_inject() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT_HEADER, HeaderValue::from_static(traceparent));
+        headers.insert("tracestate", HeaderValue::from_static("congo=t61rcWkgMzE"));
+
+        let context = extract(&headers);
+        assert_eq!(
+            context.span().span_context().trace_id().to_string(),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+
+        let mut injected = HeaderMap::new();
+        inject(&context, &mut injected);
+        assert_eq!(injected[TRACEPARENT_HEADER], traceparent);
+        assert_eq!(injected["tracestate"], "congo=t61rcWkgMzE");
+    }
+
+    #[test]
+    fn test_inject_span_keeps_guest_traceparent() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT_HEADER, HeaderValue::from_static(traceparent));
+        inject_span(&Span::none(), &mut headers);
+        assert_eq!(headers[TRACEPARENT_HEADER], traceparent);
+    }
+}
@@ -11,20 +11,21 @@ use std::{
 };
 use tokio::sync::RwLock;
 
-type CodeId = u128;
-type NodeId = u128;
+pub(crate) type CodeId = u128;
+pub(crate) type NodeId = u128;
 
-struct WarmUpMapNode {
+#[derive(Default)]
+pub(crate) struct WarmUpMapNode {
     map: RwLock<BTreeMap<CodeId, BTreeSet<NodePresence>>>,
     event_queue: RwLock<BTreeMap<CodeId, BTreeSet<NodePresence>>>,
 }
 
 #[derive(Clone, Debug)]
-struct NodePresence {
-    id: NodeId,
-    addr: Ipv4Addr,
-    updated_at: u64,
-    is_alive: bool,
+pub(crate) struct NodePresence {
+    pub(crate) id: NodeId,
+    pub(crate) addr: Ipv4Addr,
+    pub(crate) updated_at: u64,
+    pub(crate) is_alive: bool,
 }
 impl PartialEq for NodePresence {
     fn eq(&self, other: &Self) -> bool {
@@ -43,6 +44,30 @@ impl Ord for NodePresence {
     }
 }
 
+impl WarmUpMapNode {
+    /// Sets a presence of this node, and queues it for the next broadcast.
+    pub(crate) async fn set_local_presence(&self, code_id: CodeId, presence: NodePresence) {
+        let mut map = self.map.write().await;
+        let mut event_map = self.event_queue.write().await;
+        event_map
+            .entry(code_id)
+            .or_default()
+            .replace(presence.clone());
+        map.entry(code_id).or_default().replace(presence);
+    }
+
+    /// Nodes that have `code_id` warm.
+    pub(crate) async fn warm_nodes(&self, code_id: CodeId) -> Vec<NodePresence> {
+        let map = self.map.read().await;
+        map.get(&code_id)
+            .into_iter()
+            .flatten()
+            .filter(|node| node.is_alive)
+            .cloned()
+            .collect()
+    }
+}
+
 impl EventDelegate for WarmUpMapNode {
     type Id = NodeId;
     type Address = Ipv4Addr;
@@ -1,7 +1,7 @@
 use adapt_cache::AdaptCache;
 use anyhow::Result;
 use bytes::Bytes;
-use fn0::{CodeKind, DeploymentMap, Fn0};
+use fn0::{CodeKind, CodeManifest, Deployment, DeploymentMap, Fn0};
 use http_body_util::{BodyExt, combinators::UnsyncBoxBody};
 use hyper::Request;
 use hyper::server::conn::http1;
@@ -16,12 +16,27 @@ use tokio::sync::Mutex;
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut deployment_map = DeploymentMap::new();
-    deployment_map.register_code("backend", CodeKind::Wasm);
-    deployment_map.register_code("frontend", CodeKind::Js);
+    // One app, so the codes may also call each other through internal:// URLs.
+    deployment_map.register_deployment(Deployment {
+        id: "forte".to_string(),
+        codes: [("backend", CodeKind::Wasm), ("frontend", CodeKind::Js)]
+            .into_iter()
+            .map(|(code_id, kind)| {
+                let manifest = CodeManifest {
+                    kind,
+                    code_id: code_id.to_string(),
+                    code_version: 0,
+                    limits: Default::default(),
+                    config: Default::default(),
+                };
+                (code_id.to_string(), manifest)
+            })
+            .collect(),
+    });
 
     let cache = SimpleCache::new();
 
-    let fn0 = Arc::new(Fn0::new(cache.clone(), cache, deployment_map));
+    let fn0 = Fn0::new(cache.clone(), cache, deployment_map, Default::default());
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     let listener = TcpListener::bind(addr).await?;
@@ -13,8 +13,18 @@ pub enum HqToHostReliable {
         code_id_and_versions: Vec<(u64, u64)>,
     },
     GracefulShutdown,
+    /// A cron run. Answered with `ScheduledRunResult` once it's done, and hq retries it elsewhere
+    /// if that doesn't come in time.
+    RunScheduled {
+        run_id: u64,
+        code_id: u64,
+        schedule: String,
+        /// Unix epoch milliseconds.
+        scheduled_at: u64,
+    },
 }
 
+/// Sent as a datagram, except `ScheduledRunResult`.
 #[derive(Clone, Serialize, Deserialize)]
 pub enum HostToHq {
     NotifyHostStatus {
@@ -22,6 +32,8 @@ pub enum HostToHq {
         deployment_id: u64,
         instances: u64,
     },
+    /// Sent over a uni stream, as a lost one makes hq run it again.
+    ScheduledRunResult { run_id: u64, ok: bool },
 }
 
 impl HqToHostDatagram {
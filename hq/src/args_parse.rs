@@ -1,17 +1,20 @@
 use color_eyre::eyre::{Result, eyre};
 use doc_db::DocDb;
+use tokio::sync::mpsc;
 
 use crate::{
     args::*,
+    cron::CronScheduler,
     deployment_cache::DeploymentCache,
     dns::{DnsProvider, cloudflare::CloudflareDnsProvider},
     host_provider::{HostProvider, oci_container::OciContainerInstanceHostProvider},
-    site::Site,
+    site::{Site, SiteContext},
 };
 
 pub struct HqArgsParsed {
     pub sites: Vec<Site>,
     pub deployment_cache: DeploymentCache,
+    pub cron_scheduler: CronScheduler,
 }
 
 impl HqArgs {
@@ -26,8 +29,15 @@ impl HqArgs {
 
         let doc_db = DocDb::new(args.doc_db.url, args.doc_db.token).await?;
         let deployment_cache = DeploymentCache::new(doc_db.clone()).await?;
+        let (cron_results_tx, cron_results_rx) = mpsc::unbounded_channel();
+        let context = SiteContext {
+            cert: args.cert,
+            deployment_cache: deployment_cache.clone(),
+            doc_db: doc_db.clone(),
+            cron_results_tx: cron_results_tx.clone(),
+        };
 
-        let sites = args
+        let sites: Vec<Site> = args
             .sites
             .into_iter()
             .map(|site_args| {
@@ -50,18 +60,25 @@ impl HqArgs {
                 Site::new(
                     host_provider,
                     dns_provider,
-                    args.cert.clone(),
-                    deployment_cache.clone(),
                     host_cpu_cores,
                     host_memory_in_gb,
-                    doc_db.clone(),
+                    context.clone(),
                 )
             })
             .collect();
 
+        let cron_scheduler = CronScheduler::new(
+            doc_db,
+            deployment_cache.clone(),
+            sites.iter().map(Site::cron_hosts).collect(),
+            cron_results_tx,
+            cron_results_rx,
+        );
+
         Ok(HqArgsParsed {
             sites,
             deployment_cache,
+            cron_scheduler,
         })
     }
 }
@@ -0,0 +1,463 @@
+//! Cron runs of deployed codes.
+//!
+//! Every fire time of the schedules in the latest deployment of each code becomes a run,
+//! persisted in doc-db before it is sent. A run goes to one healthy host, picked by rendezvous
+//! hashing of the run and the attempt, so runs are spread over hosts and a retry usually lands
+//! on another one. Dead hosts and hosts scaling in get none.
+//! A run that fails or isn't answered in time is retried with backoff, so it may run more than once.
+
+use crate::{deployment_cache::DeploymentCache, host_connection::HostConnection, telemetry, *};
+use chrono::{TimeZone, Utc};
+use dashmap::DashMap;
+use doc_db::{CronRun, DocDb};
+use host_hq_protocol::HqToHostReliable;
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+
+/// Runs made for one schedule in a tick, so catching up after a downtime doesn't flood hosts.
+const MAX_RUNS_PER_TICK: usize = 16;
+const MAX_ATTEMPTS: u32 = 8;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// A run without a result after this is sent again. Longer than the duration limit of any code.
+const RESULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct CronRunResult {
+    pub run_id: u64,
+    pub ok: bool,
+}
+
+/// Hosts of a site as the site sees them.
+#[derive(Clone)]
+pub struct CronHosts {
+    pub host_connections: Arc<DashMap<Host, HostConnection>>,
+    pub dead_hosts: Arc<DashMap<Host, Instant>>,
+    pub graceful_shutdown_hosts: Arc<DashMap<Host, Instant>>,
+}
+
+impl CronHosts {
+    fn healthy(&self) -> Vec<(Host, HostConnection)> {
+        self.host_connections
+            .iter()
+            .filter(|entry| {
+                !self.dead_hosts.contains_key(entry.key())
+                    && !self.graceful_shutdown_hosts.contains_key(entry.key())
+            })
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+}
+
+struct PendingRun {
+    run: CronRun,
+    attempts: u32,
+    next_attempt_at: Instant,
+}
+
+pub struct CronScheduler {
+    doc_db: DocDb,
+    deployment_cache: DeploymentCache,
+    sites: Vec<CronHosts>,
+    results_tx: mpsc::UnboundedSender<CronRunResult>,
+    results_rx: mpsc::UnboundedReceiver<CronRunResult>,
+    /// Runs left by the last hq are loaded before any new run is made.
+    is_loaded: bool,
+    /// `scheduled_at` of the last run made, by code and schedule name.
+    cursors: HashMap<(u64, String), u64>,
+    /// Parsed cron expressions. `None` if invalid, so it's reported once.
+    parsed: HashMap<String, Option<::cron::Schedule>>,
+    pending: HashMap<u64, PendingRun>,
+}
+
+impl CronScheduler {
+    pub fn new(
+        doc_db: DocDb,
+        deployment_cache: DeploymentCache,
+        sites: Vec<CronHosts>,
+        results_tx: mpsc::UnboundedSender<CronRunResult>,
+        results_rx: mpsc::UnboundedReceiver<CronRunResult>,
+    ) -> Self {
+        Self {
+            doc_db,
+            deployment_cache,
+            sites,
+            results_tx,
+            results_rx,
+            is_loaded: false,
+            cursors: Default::default(),
+            parsed: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn run(&mut self) {
+        let mut interval = tokio::time::interval(cron_interval_ms());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            interval.tick().await;
+
+            if !self.is_loaded {
+                match self.doc_db.all_cron_runs().await {
+                    Ok(runs) => {
+                        telemetry::cron_db_status(true);
+                        for run in runs {
+                            self.add_pending(run);
+                        }
+                        self.is_loaded = true;
+                    }
+                    Err(err) => {
+                        warn!(%err, "Failed to load cron runs");
+                        telemetry::cron_db_status(false);
+                        continue;
+                    }
+                }
+            }
+
+            while let Ok(result) = self.results_rx.try_recv() {
+                self.on_result(result).await;
+            }
+            self.make_runs().await;
+            self.send_runs().await;
+
+            telemetry::cron_pending_runs(self.pending.len());
+        }
+    }
+
+    fn add_pending(&mut self, run: CronRun) {
+        self.pending.insert(
+            run.run_id,
+            PendingRun {
+                run,
+                attempts: 0,
+                next_attempt_at: Instant::now(),
+            },
+        );
+    }
+
+    async fn on_result(&mut self, result: CronRunResult) {
+        // Finished or given up already.
+        let Some(pending) = self.pending.get_mut(&result.run_id) else {
+            return;
+        };
+        telemetry::cron_run_result(pending.run.code_id, result.ok);
+        if result.ok {
+            self.remove_run(result.run_id).await;
+            return;
+        }
+        pending.next_attempt_at = Instant::now() + backoff(pending.attempts);
+    }
+
+    async fn make_runs(&mut self) {
+        let now = Utc::now();
+
+        for (code_id, schedules) in self.deployment_cache.schedules() {
+            for schedule in schedules {
+                let parsed = self
+                    .parsed
+                    .entry(schedule.cron.clone())
+                    .or_insert_with(|| match ::cron::Schedule::from_str(&schedule.cron) {
+                        Ok(parsed) => Some(parsed),
+                        Err(err) => {
+                            warn!(%err, code_id, schedule = schedule.name, "Invalid cron expression");
+                            telemetry::cron_invalid_schedule(code_id);
+                            None
+                        }
+                    });
+                let Some(parsed) = parsed.clone() else {
+                    continue;
+                };
+
+                let key = (code_id, schedule.name.clone());
+                let cursor = match self.cursors.get(&key) {
+                    Some(cursor) => *cursor,
+                    None => match self.doc_db.get_cron_cursor(code_id, &schedule.name).await {
+                        Ok(cursor) => {
+                            telemetry::cron_db_status(true);
+                            // A new schedule starts from now, not from the epoch.
+                            let cursor = cursor.unwrap_or(now.timestamp_millis() as u64);
+                            self.cursors.insert(key.clone(), cursor);
+                            cursor
+                        }
+                        Err(err) => {
+                            warn!(%err, "Failed to get cron cursor");
+                            telemetry::cron_db_status(false);
+                            continue;
+                        }
+                    },
+                };
+                let Some(after) = Utc.timestamp_millis_opt(cursor as i64).single() else {
+                    continue;
+                };
+
+                for fire_at in parsed
+                    .after(&after)
+                    .take_while(|fire_at| *fire_at <= now)
+                    .take(MAX_RUNS_PER_TICK)
+                {
+                    let run = CronRun {
+                        run_id: new_run_id(),
+                        code_id,
+                        schedule: schedule.name.clone(),
+                        scheduled_at: fire_at.timestamp_millis() as u64,
+                    };
+                    if let Err(err) = self.doc_db.put_cron_run(&run).await {
+                        warn!(%err, "Failed to put cron run");
+                        telemetry::cron_db_status(false);
+                        break;
+                    }
+                    telemetry::cron_db_status(true);
+                    telemetry::cron_run_made(code_id);
+                    self.cursors.insert(key.clone(), run.scheduled_at);
+                    self.add_pending(run);
+                }
+            }
+        }
+    }
+
+    async fn send_runs(&mut self) {
+        let hosts = self
+            .sites
+            .iter()
+            .flat_map(CronHosts::healthy)
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+        let mut given_up = vec![];
+
+        for pending in self.pending.values_mut() {
+            if now < pending.next_attempt_at {
+                continue;
+            }
+            if pending.attempts >= MAX_ATTEMPTS {
+                given_up.push(pending.run.run_id);
+                continue;
+            }
+            // Not an attempt. The run waits for a host to come up, and others may still be given up.
+            let Some((host, connection)) = pick_host(&hosts, pending.run.run_id, pending.attempts)
+            else {
+                continue;
+            };
+
+            pending.attempts += 1;
+            pending.next_attempt_at = now + RESULT_TIMEOUT;
+
+            let message = HqToHostReliable::RunScheduled {
+                run_id: pending.run.run_id,
+                code_id: pending.run.code_id,
+                schedule: pending.run.schedule.clone(),
+                scheduled_at: pending.run.scheduled_at,
+            };
+            let run_id = pending.run.run_id;
+            let host_id = host.id.clone();
+            let connection = connection.clone();
+            let results_tx = self.results_tx.clone();
+            tokio::spawn(async move {
+                let result = connection.send_reliable(message).await;
+                telemetry::cron_run_sent(&host_id, result.is_ok());
+                if let Err(err) = result {
+                    warn!(%err, "Failed to send cron run");
+                    let _ = results_tx.send(CronRunResult { run_id, ok: false });
+                }
+            });
+        }
+
+        for run_id in given_up {
+            if let Some(pending) = self.pending.get(&run_id) {
+                telemetry::cron_run_given_up(pending.run.code_id);
+            }
+            self.remove_run(run_id).await;
+        }
+    }
+
+    /// If doc-db fails, the run stays there and the next hq runs it again.
+    async fn remove_run(&mut self, run_id: u64) {
+        self.pending.remove(&run_id);
+        let result = self.doc_db.delete_cron_run(run_id).await;
+        telemetry::cron_db_status(result.is_ok());
+        if let Err(err) = result {
+            warn!(%err, "Failed to delete cron run");
+        }
+    }
+}
+
+/// doc-db keys runs by it, and SQLite integers are signed.
+fn new_run_id() -> u64 {
+    rand::random_range(0..=i64::MAX as u64)
+}
+
+/// Rendezvous hashing, so a host leaving only moves its own runs.
+fn pick_host(
+    hosts: &[(Host, HostConnection)],
+    run_id: u64,
+    attempt: u32,
+) -> Option<&(Host, HostConnection)> {
+    hosts.iter().max_by_key(|(host, _)| {
+        let mut hasher = DefaultHasher::new();
+        (run_id, attempt, &host.id).hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+fn backoff(attempts: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+fn cron_interval_ms() -> Duration {
+    match std::env::var("CRON_INTERVAL_MS") {
+        Ok(s) => match s.parse() {
+            Ok(v) => return Duration::from_millis(v),
+            Err(err) => warn!(%err, "CRON_INTERVAL_MS is not a valid number"),
+        },
+        Err(err) => warn!(%err, "Fail to get CRON_INTERVAL_MS from env"),
+    }
+    Duration::from_secs(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use doc_db::{CronSchedule, Deployment};
+
+    const CODE_ID: u64 = 1;
+    const SCHEDULE: &str = "every-minute";
+
+    async fn doc_db(name: &str) -> DocDb {
+        let path = std::env::temp_dir().join(format!("hq-cron-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        DocDb::new_local(&path).await.unwrap()
+    }
+
+    fn scheduler(doc_db: &DocDb) -> CronScheduler {
+        let deployment_cache = DeploymentCache::from_deployments(
+            doc_db.clone(),
+            vec![Deployment {
+                code_id: CODE_ID,
+                code_version: 1,
+                schedules: vec![CronSchedule {
+                    name: SCHEDULE.to_string(),
+                    cron: "0 * * * * *".to_string(),
+                }],
+            }],
+        );
+        let (results_tx, results_rx) = mpsc::unbounded_channel();
+        CronScheduler::new(
+            doc_db.clone(),
+            deployment_cache,
+            vec![],
+            results_tx,
+            results_rx,
+        )
+    }
+
+    async fn scheduler_with_loaded_runs(doc_db: &DocDb) -> CronScheduler {
+        let mut scheduler = scheduler(doc_db);
+        for run in doc_db.all_cron_runs().await.unwrap() {
+            scheduler.add_pending(run);
+        }
+        scheduler.is_loaded = true;
+        scheduler
+    }
+
+    fn scheduled_ats(runs: &[CronRun]) -> Vec<u64> {
+        let mut scheduled_ats = runs.iter().map(|run| run.scheduled_at).collect::<Vec<_>>();
+        scheduled_ats.sort();
+        scheduled_ats
+    }
+
+    #[tokio::test]
+    async fn test_runs_follow_the_cursor() {
+        let doc_db = doc_db("cursor").await;
+        let mut scheduler = scheduler(&doc_db);
+
+        // Three minutes of downtime make three runs, one per fire time.
+        let cursor = Utc::now().timestamp_millis() as u64 - 3 * 60 * 1000;
+        scheduler
+            .cursors
+            .insert((CODE_ID, SCHEDULE.to_string()), cursor);
+        scheduler.make_runs().await;
+
+        let runs = doc_db.all_cron_runs().await.unwrap();
+        assert!(runs.len() >= 3);
+        assert_eq!(scheduler.pending.len(), runs.len());
+        assert!(runs.iter().all(|run| run.run_id <= i64::MAX as u64));
+        let last = *scheduled_ats(&runs).last().unwrap();
+        assert_eq!(
+            doc_db.get_cron_cursor(CODE_ID, SCHEDULE).await.unwrap(),
+            Some(last)
+        );
+
+        // A new hq starts from the stored cursor, so it doesn't make the same runs again.
+        let mut next = scheduler_with_loaded_runs(&doc_db).await;
+        next.make_runs().await;
+        let runs = doc_db.all_cron_runs().await.unwrap();
+        let scheduled_ats = scheduled_ats(&runs);
+        let mut deduped = scheduled_ats.clone();
+        deduped.dedup();
+        assert_eq!(scheduled_ats, deduped);
+        assert_eq!(next.pending.len(), runs.len());
+    }
+
+    #[tokio::test]
+    async fn test_retry_and_give_up() {
+        let doc_db = doc_db("retry").await;
+        let mut scheduler = scheduler(&doc_db);
+        let run = |run_id| CronRun {
+            run_id,
+            code_id: CODE_ID,
+            schedule: SCHEDULE.to_string(),
+            scheduled_at: run_id,
+        };
+        for run_id in [1, 2] {
+            doc_db.put_cron_run(&run(run_id)).await.unwrap();
+            scheduler.add_pending(run(run_id));
+        }
+
+        // A failed run waits for the backoff, then is retried.
+        scheduler.pending.get_mut(&1).unwrap().attempts = 3;
+        scheduler
+            .on_result(CronRunResult {
+                run_id: 1,
+                ok: false,
+            })
+            .await;
+        assert!(scheduler.pending[&1].next_attempt_at > Instant::now() + BACKOFF_BASE);
+
+        // A run that failed every attempt is given up.
+        let pending = scheduler.pending.get_mut(&1).unwrap();
+        pending.attempts = MAX_ATTEMPTS;
+        pending.next_attempt_at = Instant::now();
+        scheduler.send_runs().await;
+        assert!(!scheduler.pending.contains_key(&1));
+
+        // A finished run is gone, and so is a result that comes after.
+        scheduler
+            .on_result(CronRunResult {
+                run_id: 2,
+                ok: true,
+            })
+            .await;
+        scheduler
+            .on_result(CronRunResult {
+                run_id: 2,
+                ok: false,
+            })
+            .await;
+        assert!(scheduler.pending.is_empty());
+        assert!(doc_db.all_cron_runs().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), BACKOFF_BASE);
+        assert_eq!(backoff(3), BACKOFF_BASE * 4);
+        assert_eq!(backoff(MAX_ATTEMPTS * 4), BACKOFF_MAX);
+    }
+}
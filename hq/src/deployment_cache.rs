@@ -1,7 +1,7 @@
 use crate::telemetry;
 use color_eyre::eyre::Result;
-use doc_db::{Deployment, DocDb};
-use std::{sync::Arc, time::Duration};
+use doc_db::{CronSchedule, Deployment, DocDb};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use tokio::time::MissedTickBehavior;
 use tracing::warn;
 
@@ -18,6 +18,14 @@ impl DeploymentCache {
         Ok(Self { cache, doc_db })
     }
 
+    #[cfg(test)]
+    pub fn from_deployments(doc_db: DocDb, deployments: Vec<Deployment>) -> Self {
+        Self {
+            cache: Arc::new(boxcar::Vec::from_iter(deployments)),
+            doc_db,
+        }
+    }
+
     pub async fn run_sync(&self) {
         let mut interval = tokio::time::interval(deployment_id_sync_interval_ms());
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
@@ -61,13 +69,23 @@ impl DeploymentCache {
         self.cache
             .iter()
             .skip(deployment_id_start_excluded.0 as usize)
-            .map(|(_, deployment)| *deployment)
+            .map(|(_, deployment)| deployment.clone())
             .collect()
     }
 
     pub fn last_deployment_id(&self) -> u64 {
         self.cache.count() as _
     }
+
+    /// Schedules in the latest deployment of each code that has any.
+    pub fn schedules(&self) -> BTreeMap<u64, Vec<CronSchedule>> {
+        let mut schedules = BTreeMap::new();
+        for (_, deployment) in self.cache.iter() {
+            schedules.insert(deployment.code_id, deployment.schedules.clone());
+        }
+        schedules.retain(|_, schedules| !schedules.is_empty());
+        schedules
+    }
 }
 
 #[derive(Eq, Hash, PartialEq, Clone, Debug, Ord, PartialOrd)]
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use color_eyre::eyre::{Result, eyre};
 use host_hq_protocol::{HqToHostDatagram, HqToHostReliable};
 use quinn::{ClientConfig, Connection, Endpoint, ReadDatagram};
@@ -45,11 +46,19 @@ impl HostConnection {
     pub fn read_unreliable_small_message(&self) -> ReadDatagram<'_> {
         self.connection.read_datagram()
     }
+    pub async fn read_reliable(&self) -> Result<Bytes> {
+        let mut recv = self.connection.accept_uni().await?;
+        let bytes = recv.read_to_end(MAX_RELIABLE_MESSAGE_SIZE).await?;
+        Ok(bytes.into())
+    }
     pub fn close(&self) {
         self.connection.close(0_u8.into(), &[]);
     }
 }
 
+/// Hosts only send small results this way.
+const MAX_RELIABLE_MESSAGE_SIZE: usize = 64 * 1024;
+
 const LOCAL_IPV4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 const LOCAL_IPV6: SocketAddr =
     SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0);
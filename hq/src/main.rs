@@ -1,5 +1,6 @@
 mod args;
 mod args_parse;
+mod cron;
 mod deployment_cache;
 mod dns;
 mod host_connection;
@@ -30,6 +31,7 @@ fn main() -> Result<()> {
         let HqArgsParsed {
             sites,
             deployment_cache,
+            mut cron_scheduler,
         } = HqArgs::parse().await?;
 
         let mut set = JoinSet::new();
@@ -38,6 +40,10 @@ fn main() -> Result<()> {
             deployment_cache.run_sync().await;
             Ok(())
         });
+        set.spawn(async move {
+            cron_scheduler.run().await;
+            Ok(())
+        });
         for mut site in sites {
             set.spawn(async move {
                 site.run().await;
@@ -6,7 +6,10 @@ mod scaler;
 mod send_ping;
 
 use crate::{
-    deployment_cache::DeploymentCache, dns::DnsProvider, host_connection::HostConnection,
+    cron::{CronHosts, CronRunResult},
+    deployment_cache::DeploymentCache,
+    dns::DnsProvider,
+    host_connection::HostConnection,
     telemetry, *,
 };
 use dashmap::{DashMap, DashSet};
@@ -31,18 +34,32 @@ pub struct Site {
     host_cpu_cores: NonZeroUsize,
     host_memory_in_gb: NonZeroUsize,
     doc_db: DocDb,
+    cron_results_tx: mpsc::UnboundedSender<CronRunResult>,
+}
+
+/// What every site shares with the others.
+#[derive(Clone)]
+pub struct SiteContext {
+    pub cert: String,
+    pub deployment_cache: DeploymentCache,
+    pub doc_db: DocDb,
+    pub cron_results_tx: mpsc::UnboundedSender<CronRunResult>,
 }
 
 impl Site {
     pub fn new(
         host_provider: HostProvider,
         dns_provider: DnsProvider,
-        cert: String,
-        deployment_cache: DeploymentCache,
         host_cpu_cores: NonZeroUsize,
         host_memory_in_gb: NonZeroUsize,
-        doc_db: DocDb,
+        context: SiteContext,
     ) -> Self {
+        let SiteContext {
+            cert,
+            deployment_cache,
+            doc_db,
+            cron_results_tx,
+        } = context;
         Site {
             host_provider,
             dns_provider,
@@ -56,6 +73,15 @@ impl Site {
             host_cpu_cores,
             host_memory_in_gb,
             doc_db,
+            cron_results_tx,
+        }
+    }
+
+    pub fn cron_hosts(&self) -> CronHosts {
+        CronHosts {
+            host_connections: self.host_connections.clone(),
+            dead_hosts: self.dead_hosts.clone(),
+            graceful_shutdown_hosts: self.graceful_shutdown_hosts.clone(),
         }
     }
     #[tracing::instrument(skip_all)]
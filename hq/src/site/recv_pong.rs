@@ -1,5 +1,5 @@
 use super::*;
-use crate::{deployment_cache::DeploymentId, telemetry, *};
+use crate::{cron::CronRunResult, deployment_cache::DeploymentId, telemetry, *};
 use doc_db::Deployment;
 use host_hq_protocol::{HostToHq, HqToHostReliable};
 
@@ -15,7 +15,12 @@ impl Site {
             let connection = connection.clone();
             let hosts_status = self.hosts_status.clone();
             let deployment_cache = self.deployment_cache.clone();
+            let cron_results_tx = self.cron_results_tx.clone();
 
+            tokio::spawn(recv_reliable_loop(
+                connection.clone(),
+                cron_results_tx.clone(),
+            ));
             tokio::spawn(async move {
                 while let Ok(bytes) = connection.read_unreliable_small_message().await {
                     let host_to_hq = match HostToHq::from_bytes(bytes) {
@@ -52,6 +57,9 @@ impl Site {
                                 deployment_cache.slice_updates(DeploymentId(deployment_id));
                             send_updates(&connection, &new_host.id, updates, deployment_id);
                         }
+                        HostToHq::ScheduledRunResult { run_id, ok } => {
+                            let _ = cron_results_tx.send(CronRunResult { run_id, ok });
+                        }
                     }
                 }
             });
@@ -59,6 +67,26 @@ impl Site {
     }
 }
 
+/// Scheduled run results, which hosts send over uni streams.
+async fn recv_reliable_loop(
+    connection: HostConnection,
+    cron_results_tx: mpsc::UnboundedSender<CronRunResult>,
+) {
+    while let Ok(bytes) = connection.read_reliable().await {
+        match HostToHq::from_bytes(bytes) {
+            Ok(HostToHq::ScheduledRunResult { run_id, ok }) => {
+                let _ = cron_results_tx.send(CronRunResult { run_id, ok });
+            }
+            Ok(HostToHq::NotifyHostStatus { .. }) => {
+                warn!("Host status came over a stream");
+            }
+            Err(err) => {
+                warn!(%err, "Failed to parse host message");
+            }
+        }
+    }
+}
+
 #[tracing::instrument(skip(connection, updates), fields(update_count = updates.len()))]
 fn send_updates(
     connection: &HostConnection,
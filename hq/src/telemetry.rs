@@ -292,3 +292,58 @@ pub fn scaler_config_fetch_status(success: bool) {
         )],
     );
 }
+
+pub fn cron_db_status(success: bool) {
+    let counter = global::meter("hq").u64_counter("cron_db_status").build();
+    counter.add(
+        1,
+        &[KeyValue::new(
+            "result",
+            if success { "success" } else { "failure" },
+        )],
+    );
+}
+
+pub fn cron_invalid_schedule(code_id: u64) {
+    let counter = global::meter("hq")
+        .u64_counter("cron_invalid_schedule")
+        .build();
+    counter.add(1, &[KeyValue::new("code_id", code_id as i64)]);
+}
+
+pub fn cron_run_made(code_id: u64) {
+    let counter = global::meter("hq").u64_counter("cron_run_made").build();
+    counter.add(1, &[KeyValue::new("code_id", code_id as i64)]);
+}
+
+pub fn cron_run_sent(host_id: impl ToString, success: bool) {
+    let counter = global::meter("hq").u64_counter("cron_run_sent").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("host_id", host_id.to_string()),
+            KeyValue::new("result", if success { "success" } else { "failure" }),
+        ],
+    );
+}
+
+pub fn cron_run_result(code_id: u64, ok: bool) {
+    let counter = global::meter("hq").u64_counter("cron_run_result").build();
+    counter.add(
+        1,
+        &[
+            KeyValue::new("code_id", code_id as i64),
+            KeyValue::new("result", if ok { "success" } else { "failure" }),
+        ],
+    );
+}
+
+pub fn cron_run_given_up(code_id: u64) {
+    let counter = global::meter("hq").u64_counter("cron_run_given_up").build();
+    counter.add(1, &[KeyValue::new("code_id", code_id as i64)]);
+}
+
+pub fn cron_pending_runs(count: usize) {
+    let gauge = global::meter("hq").f64_gauge("cron_pending_runs").build();
+    gauge.record(count as f64, &[]);
+}
@@ -38,8 +38,37 @@ import * as request from "ext:deno_fetch/23_request.js";
 import * as response from "ext:deno_fetch/23_response.js";
 import * as fetch from "ext:deno_fetch/26_fetch.js";
 
+const NULL_BODY_STATUS = [101, 204, 205, 304];
+
+// internal://<code_id>/... goes to another code of the same deployment, without network.
+async function fetchWithInternal(input, init) {
+  const req = new request.Request(input, init);
+  if (!req.url.startsWith("internal://")) {
+    return fetch.fetch(req);
+  }
+
+  const body = req.body !== null
+    ? new Uint8Array(await req.arrayBuffer())
+    : new Uint8Array(0);
+  const {
+    0: status,
+    1: headers,
+    2: rid,
+  } = await core.ops.op_internal_fetch(
+    req.url,
+    req.method,
+    Array.from(req.headers.entries()),
+    body,
+  );
+
+  const responseBody = rid !== null && !NULL_BODY_STATUS.includes(status)
+    ? streams.readableStreamForRid(rid)
+    : null;
+  return new response.Response(responseBody, { status, headers });
+}
+
 Object.defineProperty(globalThis, "fetch", {
-  value: fetch.fetch,
+  value: fetchWithInternal,
   enumerable: true,
   configurable: true,
   writable: true,
@@ -74,7 +103,7 @@ Object.defineProperty(globalThis, "FormData", {
 });
 
 Object.defineProperty(globalThis, "console", {
-  value: new console.Console((msg, level) => core.print(msg, level > 1)),
+  value: new console.Console((msg, level) => core.ops.op_log(msg, level > 1)),
   enumerable: false,
   configurable: true,
   writable: true,
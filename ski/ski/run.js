@@ -14,12 +14,21 @@ export async function runHandler() {
     const body = rid !== null ? readableStreamForRid(rid) : null;
 
     const request = new Request(url, { method, headers, body });
+    const env = Object.freeze(core.ops.op_get_env());
+    const scheduledEvent = core.ops.op_get_scheduled_event();
 
-    if (typeof handler !== "function") {
-      throw new Error("User code must define a global 'handler' function.");
+    let response;
+    if (scheduledEvent !== null && typeof scheduled === "function") {
+      console.log("[ski/run.js] Calling user scheduled...");
+      await scheduled(Object.freeze(scheduledEvent), env);
+      response = new Response(null, { status: 204 });
+    } else {
+      if (typeof handler !== "function") {
+        throw new Error("User code must define a global 'handler' function.");
+      }
+      console.log("[ski/run.js] Calling user handler...");
+      response = await handler(request, env);
     }
-    console.log("[ski/run.js] Calling user handler...");
-    const response = await handler(request);
     console.log("[ski/run.js] Handler returned, status:", response.status);
 
     const responseBody = response.body;
@@ -1,96 +1,125 @@
 mod http_body_resource;
+mod pool;
 mod runtime_options;
 
 use bytes::Bytes;
 use deno_core::anyhow::{Result, anyhow};
 use deno_core::*;
+use deno_error::JsErrorBox;
 use http::*;
 use http_body_resource::*;
 use http_body_util::combinators::UnsyncBoxBody;
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+pub use pool::{Code, IsolatePoolLimits};
+pub use runtime_options::Scheduled;
 use runtime_options::*;
+use std::{collections::HashMap, future::Future, pin::Pin, rc::Rc, sync::Arc};
 
-type Body = UnsyncBoxBody<Bytes, anyhow::Error>;
-type Request = hyper::Request<Body>;
-type Response = hyper::Response<Body>;
+pub type Body = UnsyncBoxBody<Bytes, anyhow::Error>;
+pub type Request = hyper::Request<Body>;
+pub type Response = hyper::Response<Body>;
 
-static RUNTIME_SNAPSHOT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/RUNJS_SNAPSHOT.bin"));
-
-pub async fn run(code: &str, request: Request) -> Result<Response> {
-    let code = code.to_string();
-
-    tokio::task::spawn_blocking(move || {
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap();
-        rt.block_on(async move {
-            let mut runtime_options = runtime_options();
-            runtime_options.startup_snapshot = Some(RUNTIME_SNAPSHOT);
-
-            let mut runtime = JsRuntime::new(runtime_options);
-            runtime.execute_script("[user code]", code.to_string())?;
-
-            register_hyper_request(&mut runtime, request);
-
-            eprintln!("[ski/lib.rs] Executing __ski_runHandler()...");
-            let script_result = runtime.execute_script(
-                "[run]",
-                ascii_str!("globalThis.__ski_runHandler();"),
-            )?;
-            eprintln!("[ski/lib.rs] Script executed, resolving future...");
-            let run_future = runtime.resolve(script_result);
-            eprintln!("[ski/lib.rs] Awaiting run_future with event loop...");
-            runtime.with_event_loop_future(run_future, Default::default()).await?;
-            eprintln!("[ski/lib.rs] Handler completed");
-
-            eprintln!("[ski/lib.rs] Getting op_state...");
-            let op_state = runtime.op_state();
-
-            eprintln!("[ski/lib.rs] Extracting ResponseParts...");
-            let response_parts = op_state
-                .borrow_mut()
-                .try_take::<ResponseParts>()
-                .ok_or_else(|| anyhow!("Did not get a response from JavaScript"))?;
+/// Runs `fetch("internal://<code_id>/...")` of the user code in the embedder.
+pub type InternalFetch =
+    Arc<dyn Fn(Request) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> + Send + Sync>;
 
-            eprintln!("[ski/lib.rs] Got ResponseParts: status={}, rid={:?}", response_parts.status, response_parts.rid);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
 
-            let mut builder =
-                hyper::Response::builder().status(StatusCode::from_u16(response_parts.status)?);
+/// Receives `console.*` output of the user code. Each call is one message ending with a newline.
+pub type Log = Arc<dyn Fn(LogStream, &str) + Send + Sync>;
+
+#[derive(Default)]
+pub struct RunOptions {
+    pub internal_fetch: Option<InternalFetch>,
+    /// Without this, `console.*` prints to the process's stdout and stderr.
+    pub log: Option<Log>,
+    /// Given to the handler as `handler(request, env)`, frozen.
+    pub env: HashMap<String, String>,
+    /// Calls `scheduled(event, env)` instead of the handler, if the code defines it.
+    /// The response is then 204, or 500 if it throws.
+    pub scheduled: Option<Scheduled>,
+}
 
-            for (key, value) in response_parts.headers {
-                if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
-                    builder = builder.header(name, value);
-                }
-            }
+static RUNTIME_SNAPSHOT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/RUNJS_SNAPSHOT.bin"));
 
-            let Some(rid) = response_parts.rid else {
-                eprintln!("[ski/lib.rs] No RID, returning empty body");
-                let body =
-                    BodyExt::boxed_unsync(Empty::<Bytes>::new().map_err(|never| match never {}));
-                return Ok(builder.body(body)?);
-            };
+/// Runs `code` in a new isolate, for one request.
+/// Use `Code` to keep isolates of the code between requests.
+pub async fn run(code: &str, request: Request, options: RunOptions) -> Result<Response> {
+    let limits = IsolatePoolLimits {
+        max_isolates: 1,
+        max_requests: 1,
+        ..Default::default()
+    };
+    Code::new(code.to_string(), limits)
+        .run(request, options)
+        .await
+}
 
-            eprintln!("[ski/lib.rs] Getting resource from table, RID: {}", rid);
+fn set_run_options(runtime: &mut JsRuntime, options: RunOptions) {
+    let op_state = runtime.op_state();
+    let mut state = op_state.borrow_mut();
+    match options.internal_fetch {
+        Some(internal_fetch) => state.put(internal_fetch_fn(internal_fetch)),
+        None => drop(state.try_take::<InternalFetchFn>()),
+    }
+    match options.log {
+        Some(log) => state.put(log_fn(log)),
+        None => drop(state.try_take::<LogFn>()),
+    }
+    state.put(Env(options.env));
+    state.put(ScheduledEvent(options.scheduled));
+}
 
-            // Get the resource that was created by resourceForReadableStream() or is Deno-backed
-            let resource = op_state
-                .borrow_mut()
-                .resource_table
-                .get_any(rid)
-                .map_err(|_| anyhow!("Resource not found"))?;
+/// Returns the response, with its body apart as it's read through the event loop.
+async fn run_handler(
+    runtime: &mut JsRuntime,
+    request: Request,
+) -> Result<(Response, Option<Body>)> {
+    register_hyper_request(runtime, request);
 
-            eprintln!("[ski/lib.rs] Creating body from resource using ResourceToBodyAdapter...");
+    let script_result =
+        runtime.execute_script("[run]", ascii_str!("globalThis.__ski_runHandler();"))?;
+    let run_future = runtime.resolve(script_result);
+    runtime
+        .with_event_loop_future(run_future, Default::default())
+        .await?;
 
-            // Use Deno's ResourceToBodyAdapter to convert Resource to Hyper Body
-            let body_adapter = deno_fetch::ResourceToBodyAdapter::new(resource);
-            let body = BodyExt::boxed_unsync(body_adapter.map_err(|e| anyhow::anyhow!(e)));
+    let op_state = runtime.op_state();
+    let response_parts = op_state
+        .borrow_mut()
+        .try_take::<ResponseParts>()
+        .ok_or_else(|| anyhow!("Did not get a response from JavaScript"))?;
+
+    let mut builder =
+        hyper::Response::builder().status(StatusCode::from_u16(response_parts.status)?);
+    for (key, value) in response_parts.headers {
+        if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
+            builder = builder.header(name, value);
+        }
+    }
+    let response = builder.body(BodyExt::boxed_unsync(
+        Empty::<Bytes>::new().map_err(|never| match never {}),
+    ))?;
+
+    let Some(rid) = response_parts.rid else {
+        return Ok((response, None));
+    };
 
-            eprintln!("[ski/lib.rs] Body created, building response...");
-            Ok(builder.body(body)?)
-        })
-    })
-    .await?
+    // Get the resource that was created by resourceForReadableStream() or is Deno-backed
+    let resource = op_state
+        .borrow_mut()
+        .resource_table
+        .get_any(rid)
+        .map_err(|_| anyhow!("Resource not found"))?;
+
+    // Use Deno's ResourceToBodyAdapter to convert Resource to Hyper Body
+    let body_adapter = deno_fetch::ResourceToBodyAdapter::new(resource);
+    let body = BodyExt::boxed_unsync(body_adapter.map_err(|e| anyhow::anyhow!(e)));
+    Ok((response, Some(body)))
 }
 
 fn register_hyper_request(runtime: &mut JsRuntime, req: Request) {
@@ -126,6 +155,58 @@ fn register_hyper_request(runtime: &mut JsRuntime, req: Request) {
     });
 }
 
+fn internal_fetch_fn(internal_fetch: InternalFetch) -> InternalFetchFn {
+    Rc::new(move |state, request| {
+        let internal_fetch = internal_fetch.clone();
+        Box::pin(async move {
+            let method = Method::from_bytes(request.method.as_bytes())
+                .map_err(|err| JsErrorBox::type_error(err.to_string()))?;
+            let mut builder = hyper::Request::builder().method(method).uri(&request.url);
+            for (key, value) in request.headers {
+                builder = builder.header(key, value);
+            }
+            let body = Full::new(Bytes::from(request.body))
+                .map_err(|never| match never {})
+                .boxed_unsync();
+            let request = builder
+                .body(body)
+                .map_err(|err| JsErrorBox::type_error(err.to_string()))?;
+
+            let response = internal_fetch(request)
+                .await
+                .map_err(|err| JsErrorBox::generic(err.to_string()))?;
+
+            let (parts, body) = response.into_parts();
+            let headers = parts
+                .headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
+                .collect();
+            let rid = state
+                .borrow_mut()
+                .resource_table
+                .add(HttpBodyResource::new(body));
+
+            Ok(InternalFetchResponse {
+                status: parts.status.as_u16(),
+                headers,
+                rid: Some(rid),
+            })
+        })
+    })
+}
+
+fn log_fn(log: Log) -> LogFn {
+    Rc::new(move |msg, is_err| {
+        let stream = if is_err {
+            LogStream::Stderr
+        } else {
+            LogStream::Stdout
+        };
+        log(stream, msg);
+    })
+}
+
 #[tokio::test]
 async fn test() {
     run(
@@ -133,6 +214,7 @@ async fn test() {
         Request::new(UnsyncBoxBody::new(
             http_body_util::Empty::new().map_err(|never| match never {}),
         )),
+        Default::default(),
     )
     .await
     .unwrap();
@@ -0,0 +1,398 @@
+//! Isolates of a user code, kept between requests.
+//!
+//! `JsRuntime` isn't `Send`, so each isolate lives on its own thread with a current-thread runtime.
+//! The user code is evaluated once per isolate, compiled with the V8 code cache of the pool.
+//! An isolate serves one request at a time, until its response body is sent,
+//! and stops after `max_requests`, when idle for `idle_timeout` or when the `Code` is dropped.
+
+use crate::*;
+use deno_core::v8::{
+    self,
+    script_compiler::{CompileOptions, NoCacheReason, Source},
+};
+use std::{
+    collections::HashSet,
+    sync::{
+        Mutex, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot};
+
+#[derive(Clone, Copy, Debug)]
+pub struct IsolatePoolLimits {
+    /// Isolates of a code at once. More requests wait for one to be free.
+    pub max_isolates: usize,
+    /// Requests an isolate serves before it's replaced, as globals of the code live on between them.
+    pub max_requests: u64,
+    pub idle_timeout: Duration,
+}
+
+impl Default for IsolatePoolLimits {
+    fn default() -> Self {
+        Self {
+            max_isolates: 4,
+            max_requests: 1000,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// User code with its isolates. Clones share the isolates.
+#[derive(Clone)]
+pub struct Code {
+    pool: Arc<Pool>,
+}
+
+struct Pool {
+    source: Arc<str>,
+    limits: IsolatePoolLimits,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<IdleIsolate>>,
+    /// Made by the first isolate that compiles the code, or whose cache was rejected.
+    code_cache: Mutex<Option<Arc<[u8]>>>,
+    next_isolate_id: AtomicU64,
+}
+
+struct IdleIsolate {
+    id: u64,
+    job_tx: oneshot::Sender<Job>,
+}
+
+struct Job {
+    request: Request,
+    options: RunOptions,
+    response_tx: oneshot::Sender<Result<Response>>,
+    /// Held until the response body is sent.
+    permit: OwnedSemaphorePermit,
+}
+
+impl Code {
+    pub fn new(source: String, limits: IsolatePoolLimits) -> Self {
+        Self {
+            pool: Arc::new(Pool {
+                source: source.into(),
+                limits,
+                permits: Arc::new(Semaphore::new(limits.max_isolates)),
+                idle: Default::default(),
+                code_cache: Default::default(),
+                next_isolate_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub async fn run(&self, request: Request, options: RunOptions) -> Result<Response> {
+        let permit = self.pool.permits.clone().acquire_owned().await?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let mut job = Job {
+            request,
+            options,
+            response_tx,
+            permit,
+        };
+
+        loop {
+            let idle = self.pool.idle.lock().unwrap().pop();
+            let Some(isolate) = idle else {
+                spawn_isolate(&self.pool, job)?;
+                break;
+            };
+            match isolate.job_tx.send(job) {
+                Ok(()) => break,
+                // Its thread is gone.
+                Err(returned) => job = returned,
+            }
+        }
+
+        response_rx
+            .await
+            .map_err(|_| anyhow!("The isolate stopped before responding"))?
+    }
+}
+
+fn spawn_isolate(pool: &Arc<Pool>, job: Job) -> Result<()> {
+    let id = pool.next_isolate_id.fetch_add(1, Ordering::Relaxed);
+    let pool = Arc::downgrade(pool);
+    std::thread::Builder::new()
+        .name("ski-isolate".to_string())
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(isolate_loop(pool, id, job));
+        })?;
+    Ok(())
+}
+
+async fn isolate_loop(pool: Weak<Pool>, id: u64, mut job: Job) {
+    let mut runtime_options = runtime_options();
+    runtime_options.startup_snapshot = Some(RUNTIME_SNAPSHOT);
+    let mut runtime = JsRuntime::new(runtime_options);
+    let mut served = 0;
+
+    loop {
+        let Job {
+            request,
+            options,
+            response_tx,
+            permit,
+        } = job;
+        let Some(limits) = pool.upgrade().map(|pool| pool.limits) else {
+            return;
+        };
+
+        set_run_options(&mut runtime, options);
+        // After the options of the first job, so its log gets output of the top-level code.
+        if served == 0
+            && let Err(err) = evaluate_user_code(&mut runtime, &pool)
+        {
+            let _ = response_tx.send(Err(err));
+            return;
+        }
+        if !serve(&mut runtime, request, response_tx).await {
+            return;
+        }
+        served += 1;
+        if served >= limits.max_requests {
+            return;
+        }
+
+        let (job_tx, mut job_rx) = oneshot::channel();
+        {
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            pool.idle.lock().unwrap().push(IdleIsolate { id, job_tx });
+        }
+        drop(permit);
+
+        job = match tokio::time::timeout(limits.idle_timeout, &mut job_rx).await {
+            Ok(Ok(next)) => next,
+            // The pool is dropped.
+            Ok(Err(_)) => return,
+            Err(_elapsed) => {
+                let stop = pool.upgrade().is_none_or(|pool| {
+                    let mut idle = pool.idle.lock().unwrap();
+                    let index = idle.iter().position(|isolate| isolate.id == id);
+                    index.map(|index| idle.swap_remove(index)).is_some()
+                });
+                if stop {
+                    return;
+                }
+                // A request took it from `idle` while timing out, and is sending its job.
+                match job_rx.await {
+                    Ok(next) => next,
+                    Err(_) => return,
+                }
+            }
+        };
+    }
+}
+
+/// Like `JsRuntime::execute_script`, but compiles with the code cache of the pool.
+fn evaluate_user_code(runtime: &mut JsRuntime, pool: &Weak<Pool>) -> Result<()> {
+    let pool = pool
+        .upgrade()
+        .ok_or_else(|| anyhow!("The code is dropped"))?;
+    let code_cache = pool.code_cache.lock().unwrap().clone();
+
+    scope!(scope, runtime);
+    let source = v8::String::new(scope, &pool.source)
+        .ok_or_else(|| anyhow!("The user code is too large"))?;
+    let name = v8::String::new(scope, "[user code]").unwrap();
+    let origin = v8::ScriptOrigin::new(
+        scope,
+        name.into(),
+        0,
+        0,
+        false,
+        0,
+        None,
+        false,
+        false,
+        false,
+        None,
+    );
+    v8::tc_scope!(let tc_scope, scope);
+
+    let (script, make_cache) = match &code_cache {
+        Some(code_cache) => {
+            let mut source = Source::new_with_cached_data(
+                source,
+                Some(&origin),
+                v8::CachedData::new(code_cache),
+            );
+            let script = v8::script_compiler::compile(
+                tc_scope,
+                &mut source,
+                CompileOptions::ConsumeCodeCache,
+                NoCacheReason::NoReason,
+            );
+            let rejected = source
+                .get_cached_data()
+                .is_none_or(|cached_data| cached_data.rejected());
+            (script, rejected)
+        }
+        None => (v8::Script::compile(tc_scope, source, Some(&origin)), true),
+    };
+    let result = script.and_then(|script| {
+        if make_cache
+            && let Some(code_cache) = script.get_unbound_script(tc_scope).create_code_cache()
+        {
+            *pool.code_cache.lock().unwrap() = Some(Arc::from(&code_cache[..]));
+        }
+        script.run(tc_scope)
+    });
+    if result.is_some() {
+        return Ok(());
+    }
+    match tc_scope.exception() {
+        Some(exception) => Err(error::JsError::from_v8_exception(tc_scope, exception).into()),
+        None => Err(anyhow!("The user code was terminated")),
+    }
+}
+
+/// Runs the handler and sends its response, then streams the body from the event loop.
+/// Returns whether the isolate can serve more requests.
+async fn serve(
+    runtime: &mut JsRuntime,
+    request: Request,
+    response_tx: oneshot::Sender<Result<Response>>,
+) -> bool {
+    let op_state = runtime.op_state();
+    let resources: HashSet<ResourceId> = op_state
+        .borrow()
+        .resource_table
+        .names()
+        .map(|(rid, _)| rid)
+        .collect();
+
+    let is_reusable = respond(runtime, request, response_tx).await;
+
+    // Closes what the request opened, like bodies the code didn't read, so they don't pile up.
+    let opened: Vec<_> = {
+        let mut state = op_state.borrow_mut();
+        let rids: Vec<ResourceId> = state
+            .resource_table
+            .names()
+            .map(|(rid, _)| rid)
+            .filter(|rid| !resources.contains(rid))
+            .collect();
+        rids.into_iter()
+            .filter_map(|rid| state.resource_table.take_any(rid).ok())
+            .collect()
+    };
+    for resource in opened {
+        resource.close();
+    }
+    is_reusable
+}
+
+async fn respond(
+    runtime: &mut JsRuntime,
+    request: Request,
+    response_tx: oneshot::Sender<Result<Response>>,
+) -> bool {
+    let (response, body) = match run_handler(runtime, request).await {
+        Ok(response) => response,
+        Err(err) => {
+            let _ = response_tx.send(Err(err));
+            return false;
+        }
+    };
+
+    let Some(mut body) = body else {
+        let _ = response_tx.send(Ok(response));
+        return true;
+    };
+    let (mut frame_tx, frame_rx) = futures::channel::mpsc::channel(1);
+    let response = response.map(|_| BodyExt::boxed_unsync(StreamBody::new(frame_rx)));
+    if response_tx.send(Ok(response)).is_err() {
+        return true;
+    }
+
+    let send_body = Box::pin(async move {
+        use futures::SinkExt;
+        while let Some(frame) = body.frame().await {
+            // The client is gone.
+            if frame_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+    let _ = runtime
+        .with_event_loop_future(send_body, Default::default())
+        .await;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str =
+        "let count = 0; function handler() { count += 1; return new Response(String(count)); }";
+
+    async fn body(code: &Code) -> String {
+        let response = code
+            .run(
+                Request::new(UnsyncBoxBody::new(
+                    http_body_util::Empty::new().map_err(|never| match never {}),
+                )),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_code_reuses_isolates() {
+        let code = Code::new(SOURCE.to_string(), IsolatePoolLimits::default());
+        assert_eq!(body(&code).await, "1");
+        assert_eq!(body(&code).await, "2");
+
+        let limits = IsolatePoolLimits {
+            max_requests: 1,
+            ..Default::default()
+        };
+        let code = Code::new(SOURCE.to_string(), limits);
+        assert_eq!(body(&code).await, "1");
+        assert_eq!(body(&code).await, "1");
+    }
+
+    #[tokio::test]
+    async fn test_idle_isolate_stops() {
+        let limits = IsolatePoolLimits {
+            idle_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let code = Code::new(SOURCE.to_string(), limits);
+        assert_eq!(body(&code).await, "1");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(code.pool.idle.lock().unwrap().is_empty());
+        // Globals are gone with the isolate.
+        assert_eq!(body(&code).await, "1");
+    }
+
+    #[tokio::test]
+    async fn test_isolates_use_code_cache() {
+        let limits = IsolatePoolLimits {
+            max_requests: 1,
+            ..Default::default()
+        };
+        let code = Code::new(SOURCE.to_string(), limits);
+        assert_eq!(body(&code).await, "1");
+        let made = code.pool.code_cache.lock().unwrap().clone().unwrap();
+
+        // A new isolate, which would make the cache again if V8 rejected it.
+        assert_eq!(body(&code).await, "1");
+        let used = code.pool.code_cache.lock().unwrap().clone().unwrap();
+        assert!(Arc::ptr_eq(&made, &used));
+    }
+}
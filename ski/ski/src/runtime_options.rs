@@ -3,6 +3,8 @@ use deno_core::{RuntimeOptions, extension, v8::CreateParams};
 use deno_error::JsErrorBox;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
 
 pub fn runtime_options() -> RuntimeOptions {
@@ -39,6 +41,47 @@ pub struct ResponseParts {
     pub rid: Option<ResourceId>,
 }
 
+pub struct InternalFetchRequest {
+    pub url: String,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+pub struct InternalFetchResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub rid: Option<ResourceId>,
+}
+
+/// Handles `fetch("internal://<code_id>/...")`. Not put if the embedder doesn't support it.
+pub type InternalFetchFn = Rc<
+    dyn Fn(
+        Rc<RefCell<OpState>>,
+        InternalFetchRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<InternalFetchResponse, JsErrorBox>>>>,
+>;
+
+/// Entries of the handler's `env` object.
+#[derive(Default)]
+pub struct Env(pub HashMap<String, String>);
+
+/// A cron run, given to `scheduled` as `{ schedule, scheduledAt }`.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scheduled {
+    pub schedule: String,
+    /// Unix epoch milliseconds.
+    pub scheduled_at: u64,
+}
+
+/// The cron run the code is invoked for, if any.
+#[derive(Default)]
+pub struct ScheduledEvent(pub Option<Scheduled>);
+
+/// Receives `console.*` output with `is_err`. Not put if the embedder doesn't capture logs.
+pub type LogFn = Rc<dyn Fn(&str, bool)>;
+
 type OpGetRequestParts = (String, String, Vec<(String, String)>, Option<ResourceId>);
 
 #[op2]
@@ -50,6 +93,18 @@ fn op_get_request_parts(state: &mut OpState) -> Result<OpGetRequestParts, JsErro
     Ok((parts.url, parts.method, parts.headers, parts.rid))
 }
 
+#[op2]
+#[serde]
+fn op_get_env(state: &mut OpState) -> HashMap<String, String> {
+    state.try_take::<Env>().unwrap_or_default().0
+}
+
+#[op2]
+#[serde]
+fn op_get_scheduled_event(state: &mut OpState) -> Option<Scheduled> {
+    state.try_take::<ScheduledEvent>().unwrap_or_default().0
+}
+
 #[op2(async)]
 async fn op_respond(
     state: Rc<RefCell<OpState>>,
@@ -68,10 +123,57 @@ async fn op_respond(
     Ok(())
 }
 
+type OpInternalFetch = (u16, Vec<(String, String)>, Option<ResourceId>);
+
+#[op2(async)]
+#[serde]
+async fn op_internal_fetch(
+    state: Rc<RefCell<OpState>>,
+    #[string] url: String,
+    #[string] method: String,
+    #[serde] headers: Vec<(String, String)>,
+    #[buffer(copy)] body: Vec<u8>,
+) -> Result<OpInternalFetch, JsErrorBox> {
+    let internal_fetch = state
+        .borrow()
+        .try_borrow::<InternalFetchFn>()
+        .cloned()
+        .ok_or_else(|| JsErrorBox::type_error("internal:// is not supported"))?;
+    let response = internal_fetch(
+        state,
+        InternalFetchRequest {
+            url,
+            method,
+            headers,
+            body,
+        },
+    )
+    .await?;
+    Ok((response.status, response.headers, response.rid))
+}
+
+#[op2(fast)]
+fn op_log(state: &mut OpState, #[string] msg: &str, is_err: bool) {
+    match state.try_borrow::<LogFn>() {
+        Some(log) => log(msg, is_err),
+        None if is_err => eprint!("{msg}"),
+        None => print!("{msg}"),
+    }
+}
+
 deno_core::extension!(
     request_response_extension,
-    ops = [op_get_request_parts, op_respond],
+    ops = [
+        op_get_request_parts,
+        op_get_env,
+        op_get_scheduled_event,
+        op_respond,
+        op_internal_fetch,
+        op_log,
+    ],
     state = |s| {
         s.put(RequestParts::default());
+        s.put(Env::default());
+        s.put(ScheduledEvent::default());
     },
 );
@@ -61,8 +61,14 @@ where
             Scheme::Http => (false, http::uri::Scheme::HTTP),
             Scheme::Https => (true, http::uri::Scheme::HTTPS),
 
-            // We can only support http/https
-            Scheme::Other(_) => return Err(types::ErrorCode::HttpProtocolError.into()),
+            // Other schemes are left to `WasiHttpView::send_request`.
+            // The default implementation only supports http/https.
+            Scheme::Other(scheme) => (
+                false,
+                scheme
+                    .parse()
+                    .map_err(|_| types::ErrorCode::HttpProtocolError)?,
+            ),
         };
 
         let authority = req.authority.unwrap_or_else(String::new);